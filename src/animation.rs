@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::f64;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use nalgebra::Rotation3;
+use png::EncodingError;
+
+use crate::img::{self, OutputColorSpace};
+use crate::math::{Unit3, Vec3};
+use crate::render::{self, Camera, CameraError, CameraOptions, RenderOptions};
+use crate::scene::Scene;
+
+/// A description of how a shot changes over time, parametrized by `t` in `[0, 1)`.
+pub trait Animation {
+    fn camera_at(&self, t: f64) -> CameraOptions;
+
+    /// Returns the scene to render at time `t`, or `None` to use the scene passed to
+    /// [`render_animation`].
+    fn scene_at(&self, _t: f64) -> Option<Scene> {
+        None
+    }
+}
+
+/// Orbits the camera about a vertical axis through a fixed center, completing one full turn over
+/// the course of the animation.
+pub struct Turntable {
+    base: CameraOptions,
+    center: Vec3,
+    axis: Unit3,
+}
+
+impl Turntable {
+    /// Creates a turntable starting from the camera described by `base` and orbiting `center`
+    /// about `base.vup`. The camera looks at `center` throughout.
+    pub fn new(base: CameraOptions, center: Vec3) -> Self {
+        Self {
+            base,
+            center,
+            axis: Unit3::new_normalize(base.vup),
+        }
+    }
+
+    /// Creates a turntable orbiting the center of `scene`'s bounds, or `None` if the scene is
+    /// empty.
+    pub fn around_scene(base: CameraOptions, scene: &Scene) -> Option<Self> {
+        Some(Self::new(base, scene.bounds()?.centroid()))
+    }
+}
+
+impl Animation for Turntable {
+    fn camera_at(&self, t: f64) -> CameraOptions {
+        let rotation = Rotation3::from_axis_angle(&self.axis, t * f64::consts::TAU);
+
+        CameraOptions {
+            origin: self.center + rotation * (self.base.origin - self.center),
+            look_at: self.center,
+            ..self.base
+        }
+    }
+}
+
+pub struct AnimationOptions {
+    pub frame_count: u32,
+    /// Tone map every frame with the exposure chosen for the first one, so that brightness does
+    /// not flicker as the brightest pixel changes from frame to frame.
+    pub lock_exposure: bool,
+    /// Luminance mapped to white in every frame, overriding `lock_exposure`. A white point chosen
+    /// up front keeps the exposure of separately rendered shots consistent as well.
+    pub white_point: Option<f64>,
+    pub color_space: OutputColorSpace,
+}
+
+#[derive(Debug)]
+pub enum AnimationError {
+    Camera(u32, CameraError),
+    Write(u32, EncodingError),
+}
+
+impl fmt::Display for AnimationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnimationError::Camera(frame, err) => write!(f, "frame {}: {}", frame, err),
+            AnimationError::Write(frame, err) => {
+                write!(f, "failed to write frame {}: {}", frame, err)
+            }
+        }
+    }
+}
+
+impl Error for AnimationError {}
+
+/// Returns the name of the file to which frame `index` is written.
+pub fn frame_filename(index: u32) -> String {
+    format!("frame_{:04}.png", index)
+}
+
+/// Returns the animation time at which frame `index` of `frame_count` is rendered.
+///
+/// Frames are spread evenly over `[0, 1)`, so the last frame leads back into the first one and
+/// cyclic animations such as [`Turntable`] loop seamlessly.
+pub fn frame_time(index: u32, frame_count: u32) -> f64 {
+    index as f64 / frame_count as f64
+}
+
+/// Renders every frame of `animation` into `output_dir`, naming them with [`frame_filename`].
+///
+/// Frames are rendered with the same `opts` (including the seed), so that noise stays put from one
+/// frame to the next instead of crawling across the image.
+pub fn render_animation(
+    animation: &dyn Animation,
+    scene: &Scene,
+    opts: &RenderOptions,
+    anim_opts: &AnimationOptions,
+    output_dir: &Path,
+) -> Result<(), AnimationError> {
+    let mut locked_max_y = None;
+
+    for index in 0..anim_opts.frame_count {
+        let t = frame_time(index, anim_opts.frame_count);
+
+        let camera = Camera::new(&animation.camera_at(t))
+            .map_err(|err| AnimationError::Camera(index, err))?;
+        let frame_scene = animation.scene_at(t);
+
+        let mut pixels =
+            vec![Vec3::default(); (camera.pixel_width() * camera.pixel_height()) as usize];
+        render::render_to(
+            &mut pixels,
+            frame_scene.as_ref().unwrap_or(scene),
+            &camera,
+            opts,
+        );
+
+        let max_y = match anim_opts.white_point.or(locked_max_y) {
+            Some(max_y) => max_y,
+            None => {
+                let max_y = img::max_luminance(&pixels);
+                if anim_opts.lock_exposure {
+                    locked_max_y = Some(max_y);
+                }
+                max_y
+            }
+        };
+
+        write_frame(
+            &output_dir.join(frame_filename(index)),
+            &img::pixels_to_raw(&pixels, max_y, anim_opts.color_space, false),
+            &camera,
+        )
+        .map_err(|err| AnimationError::Write(index, err))?;
+    }
+
+    Ok(())
+}
+
+fn write_frame(filename: &Path, raw_pixels: &[u8], camera: &Camera) -> Result<(), EncodingError> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    img::write_png(
+        &mut writer,
+        raw_pixels,
+        camera.pixel_width(),
+        camera.pixel_height(),
+    )
+}
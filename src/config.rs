@@ -0,0 +1,186 @@
+//! Render settings stored in a TOML file.
+//!
+//! Settings are grouped into `[image]`, `[camera]` and `[render]` tables. For example:
+//!
+//! ```toml
+//! [image]
+//! width = 640
+//! height = 360
+//!
+//! [camera]
+//! vfov = 50
+//! origin = [0, 0, 0.5]
+//!
+//! [render]
+//! spp = 256
+//! ```
+//!
+//! Every setting is optional, so that a file only needs to mention what it wants to change.
+//! Unknown tables and keys are rejected, so that typos do not go unnoticed.
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::img::OutputColorSpace;
+use crate::math::Vec3;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub output: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub color_space: Option<OutputColorSpace>,
+    pub white_point: Option<f64>,
+    pub exposure_key: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub vfov: Option<f64>,
+    pub aperture: Option<f64>,
+    pub far: Option<f64>,
+    #[serde(deserialize_with = "vec3")]
+    pub origin: Option<Vec3>,
+    #[serde(deserialize_with = "vec3")]
+    pub look_at: Option<Vec3>,
+    #[serde(deserialize_with = "vec3")]
+    pub vup: Option<Vec3>,
+    #[serde(deserialize_with = "vec3")]
+    pub focus_target: Option<Vec3>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    #[serde(rename = "spp")]
+    pub samples_per_pixel: Option<u32>,
+    pub max_depth: Option<u32>,
+    pub seed: Option<u64>,
+    pub clamp_indirect: Option<f64>,
+    pub background_bounces: Option<u32>,
+    pub regularize: Option<f64>,
+    pub min_light_distance: Option<f64>,
+    pub refracted_connections: Option<bool>,
+    pub max_leaf_size: Option<usize>,
+    #[serde(deserialize_with = "parsed")]
+    pub sky: Option<Sky>,
+    #[serde(deserialize_with = "vec3")]
+    pub sky_nadir: Option<Vec3>,
+    #[serde(deserialize_with = "vec3")]
+    pub sky_horizon: Option<Vec3>,
+    #[serde(deserialize_with = "vec3")]
+    pub sky_zenith: Option<Vec3>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub image: ImageConfig,
+    pub camera: CameraConfig,
+    pub render: RenderConfig,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError)
+    }
+}
+
+/// An error encountered while parsing a config file, describing where in the file it occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError(toml::de::Error);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The sky lighting a scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sky {
+    None,
+    Hemisphere,
+    Gradient,
+    Sunset,
+}
+
+impl FromStr for Sky {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Sky::None),
+            "hemisphere" => Ok(Sky::Hemisphere),
+            "gradient" => Ok(Sky::Gradient),
+            "sunset" => Ok(Sky::Sunset),
+            _ => Err(format!("unknown sky '{}'", s)),
+        }
+    }
+}
+
+/// Reads a string setting and parses it with its type's `FromStr` implementation, so that the
+/// settings share their syntax with the corresponding command-line flags.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Reads an array of 3 numbers as a vector.
+fn vec3<'de, D>(deserializer: D) -> Result<Option<Vec3>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let [x, y, z] = <[f64; 3]>::deserialize(deserializer)?;
+    Ok(Some(Vec3::new(x, y, z)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_example_config() {
+        let config = Config::parse(include_str!("../render.example.toml")).unwrap();
+
+        assert_eq!(config.image.width, Some(640));
+        assert_eq!(config.image.output, Some(PathBuf::from("render.png")));
+        assert_eq!(config.image.color_space, Some(OutputColorSpace::Srgb));
+        assert_eq!(config.camera.vfov, Some(50.));
+        assert_eq!(config.camera.origin, Some(Vec3::new(0., 0., 0.5)));
+        assert_eq!(config.render.samples_per_pixel, Some(100));
+        assert_eq!(config.render.sky, Some(Sky::Hemisphere));
+        assert_eq!(config.render.clamp_indirect, None);
+    }
+
+    #[test]
+    fn reports_where_errors_occur() {
+        let err = Config::parse("[render]\nspp = 4\nsky = \"cloudy\"\n").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("line 3"), "{}", msg);
+        assert!(msg.contains("unknown sky 'cloudy'"), "{}", msg);
+
+        let err = Config::parse("[camera]\nfov = 50\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `fov`"), "{}", err);
+
+        let err = Config::parse("[image]\nwidth = \"wide\"\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+}
@@ -4,14 +4,45 @@ use rand::Rng;
 use rand_distr::Distribution;
 
 use crate::math::{Unit3, Vec3};
+use crate::shading;
 
-pub struct CosWeightedHemisphere;
+/// Distribution of directions in the upper (`+z`) hemisphere with density proportional to
+/// `cos(theta)^exponent`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerCosineHemisphere {
+    exponent: f64,
+}
 
-impl Distribution<Unit3> for CosWeightedHemisphere {
+impl PowerCosineHemisphere {
+    pub fn new(exponent: f64) -> Self {
+        assert!(exponent >= 0., "negative exponent");
+        Self { exponent }
+    }
+
+    pub fn exponent(&self) -> f64 {
+        self.exponent
+    }
+
+    /// Returns the density of `dir` with respect to solid angle, which is 0 below the horizon.
+    pub fn pdf(&self, dir: Unit3) -> f64 {
+        let cos_theta = shading::cos_theta(dir);
+        if cos_theta <= 0. {
+            return 0.;
+        }
+
+        (self.exponent + 1.) / f64::consts::TAU * cos_theta.powf(self.exponent)
+    }
+}
+
+impl Distribution<Unit3> for PowerCosineHemisphere {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Unit3 {
-        let radius_squared: f64 = rng.gen();
+        let u: f64 = rng.gen();
         let phi = rng.gen_range(0.0..f64::consts::TAU);
 
+        // `1 - u` is uniform as well; using it makes the squared radius exactly `u` when the
+        // exponent is 1.
+        let radius_squared = 1. - (1. - u).powf(2. / (self.exponent + 1.));
+
         let radius = radius_squared.sqrt();
         Unit3::new_unchecked(Vec3::new(
             radius * phi.cos(),
@@ -20,3 +51,42 @@ impl Distribution<Unit3> for CosWeightedHemisphere {
         ))
     }
 }
+
+/// The cosine-weighted hemisphere, [`PowerCosineHemisphere`] with an exponent of 1.
+pub struct CosWeightedHemisphere;
+
+impl CosWeightedHemisphere {
+    const DISTR: PowerCosineHemisphere = PowerCosineHemisphere { exponent: 1. };
+
+    /// Returns `cos(theta) / pi` for directions in the upper hemisphere, and 0 otherwise.
+    pub fn pdf(&self, dir: Unit3) -> f64 {
+        Self::DISTR.pdf(dir)
+    }
+}
+
+impl Distribution<Unit3> for CosWeightedHemisphere {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Unit3 {
+        Self::DISTR.sample(rng)
+    }
+}
+
+/// Maps a point in the unit square to the unit disc, preserving relative areas and keeping nearby
+/// points nearby, so that stratified points in the square stay stratified on the disc.
+///
+/// This is Shirley and Chiu's concentric mapping.
+pub fn square_to_disc(u: [f64; 2]) -> [f64; 2] {
+    let x = 2. * u[0] - 1.;
+    let y = 2. * u[1] - 1.;
+
+    if x == 0. && y == 0. {
+        return [0., 0.];
+    }
+
+    let (radius, theta) = if x.abs() > y.abs() {
+        (x, f64::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f64::consts::FRAC_PI_2 - f64::consts::FRAC_PI_4 * (x / y))
+    };
+
+    [radius * theta.cos(), radius * theta.sin()]
+}
@@ -1,4 +1,12 @@
-use crate::math::{Aabb, OrthoNormalBasis, Ray, Unit3, Vec3, EPSILON};
+use std::f64;
+
+use nalgebra::{Matrix3, Matrix4, Point3, Rotation3, UnitQuaternion};
+
+use crate::math::{Aabb, OrthoNormalBasis, Ray, Transform3, Unit3, Vec3};
+
+pub use self::bezier::BezierPatch;
+
+mod bezier;
 
 #[derive(Debug, Clone, Copy)]
 pub struct RawHitInfo {
@@ -13,9 +21,17 @@ pub enum HitSide {
 }
 
 pub struct HitInfo {
+    /// Distance along the ray at which the hit occurred.
+    pub t: f64,
     pub point: Vec3,
+    /// The true normal of the surface, facing the side from which it was hit.
+    pub geometric_normal: Unit3,
+    /// The shading frame, whose `w` axis is the shading normal. This matches
+    /// `geometric_normal` unless a shading normal has been attached.
     pub basis: OrthoNormalBasis,
     pub side: HitSide,
+    /// The time of the ray that made the hit, inherited by rays spawned from it.
+    pub time: f64,
 }
 
 impl HitInfo {
@@ -32,7 +48,45 @@ impl HitInfo {
 
         let basis = OrthoNormalBasis::from_w(normal);
 
-        Self { point, basis, side }
+        Self {
+            t,
+            point,
+            geometric_normal: normal,
+            basis,
+            side,
+            time: ray.time,
+        }
+    }
+
+    /// Shades the hit with `shading_normal` (such as an interpolated vertex normal) in place of
+    /// the geometric normal. The normal is flipped if necessary to face the same side as the
+    /// geometric normal.
+    pub fn with_shading_normal(mut self, shading_normal: Unit3) -> Self {
+        let normal = if shading_normal.dot(&self.geometric_normal) < 0. {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+
+        self.basis = OrthoNormalBasis::from_w(normal);
+        self
+    }
+
+    /// Returns the shading normal in world space. Like `geometric_normal`, it faces the side from
+    /// which the surface was hit, pointing inward for [`HitSide::Inside`] hits.
+    pub fn world_normal(&self) -> Unit3 {
+        self.basis.w()
+    }
+
+    /// Checks whether `local_dir` lies on the same side of the geometric surface as it does of
+    /// the shading surface.
+    ///
+    /// When the two normals differ, a direction can appear to reflect off the shading surface
+    /// while actually pointing into the geometry (or vice versa). Following such directions leaks
+    /// light through surfaces, so they should be discarded.
+    pub fn is_consistent(&self, local_dir: Unit3) -> bool {
+        let geometric_cos = self.local_to_world(local_dir).dot(&self.geometric_normal);
+        (geometric_cos > 0.) == (local_dir[2] > 0.)
     }
 
     pub fn world_to_local(&self, world: Unit3) -> Unit3 {
@@ -44,7 +98,7 @@ impl HitInfo {
     }
 
     pub fn spawn_world_ray(&self, dir: Unit3) -> Ray {
-        Ray::new(self.point, dir)
+        Ray::new(self.point, dir).with_time(self.time)
     }
 
     pub fn spawn_local_ray(&self, local_dir: Unit3) -> Ray {
@@ -53,47 +107,116 @@ impl HitInfo {
 }
 
 pub trait Geom {
-    fn bounds(&self) -> Aabb;
-    fn hit(&self, ray: &Ray, t_max: f64) -> Option<RawHitInfo>;
+    /// Returns the bounds of the geometry, or `None` if it extends infinitely.
+    ///
+    /// Geometry that moves over the shutter interval must return bounds enclosing it at all times
+    /// in `[0, 1]`, as rays at any of them are tested against these bounds before reaching it.
+    fn bounds(&self) -> Option<Aabb>;
+    /// Returns the closest hit along `ray` between `t_min` and `t_max`, if any. Callers usually
+    /// pass [`EPSILON`](crate::math::EPSILON) as `t_min` to keep rays from hitting the surface they
+    /// leave.
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo>;
 }
 
+/// A sphere, optionally clipped to a partial surface.
+///
+/// Clipping is expressed in coordinates relative to the center: only points whose `z` lies in
+/// `[z_min, z_max]` and whose azimuth (measured counterclockwise from the `x` axis) is at most
+/// `phi_max` (in radians) are part of the surface. Cut faces are left open.
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f64,
+    pub z_min: f64,
+    pub z_max: f64,
+    pub phi_max: f64,
 }
 
 impl Sphere {
     pub fn new(center: Vec3, radius: f64) -> Self {
-        Self { center, radius }
+        Self {
+            center,
+            radius,
+            z_min: -radius,
+            z_max: radius,
+            phi_max: f64::consts::TAU,
+        }
+    }
+
+    /// Clips the sphere to the slab between `z_min` and `z_max`, relative to the center.
+    pub fn with_z_range(mut self, z_min: f64, z_max: f64) -> Self {
+        self.z_min = z_min.clamp(-self.radius, self.radius);
+        self.z_max = z_max.clamp(-self.radius, self.radius);
+        self
+    }
+
+    /// Clips the sphere to azimuths of at most `phi_max` radians.
+    pub fn with_phi_max(mut self, phi_max: f64) -> Self {
+        self.phi_max = phi_max.clamp(0., f64::consts::TAU);
+        self
+    }
+
+    fn is_clipped(&self) -> bool {
+        self.z_min > -self.radius || self.z_max < self.radius || self.phi_max < f64::consts::TAU
+    }
+
+    /// Checks whether `offset`, a point on the full sphere relative to its center, survives the
+    /// clipping.
+    fn contains(&self, offset: Vec3) -> bool {
+        if offset[2] < self.z_min || offset[2] > self.z_max {
+            return false;
+        }
+
+        let mut phi = offset[1].atan2(offset[0]);
+        if phi < 0. {
+            phi += f64::consts::TAU;
+        }
+
+        phi <= self.phi_max
     }
 }
 
 impl Geom for Sphere {
-    fn bounds(&self) -> Aabb {
+    fn bounds(&self) -> Option<Aabb> {
         let radius_vec = Vec3::from_element(self.radius);
-        Aabb::new(self.center - radius_vec, self.center + radius_vec)
+        let mut min_point = self.center - radius_vec;
+        let mut max_point = self.center + radius_vec;
+
+        min_point[2] = self.center[2] + self.z_min;
+        max_point[2] = self.center[2] + self.z_max;
+
+        Some(Aabb::new(min_point, max_point))
     }
 
-    fn hit(&self, ray: &Ray, t_max: f64) -> Option<RawHitInfo> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
         let oc = ray.origin - self.center;
         let b = oc.dot(&ray.dir);
         let c = oc.norm_squared() - self.radius.powi(2);
 
-        let discriminant = b.powi(2) - c;
+        // `b² - c` cancels catastrophically when the origin is far from the sphere relative to its
+        // radius; measuring the distance from the center to the ray directly does not.
+        let discriminant = self.radius.powi(2) - (oc - b * *ray.dir).norm_squared();
 
         if discriminant < 0. {
             return None;
         }
 
-        let radical = discriminant.sqrt();
+        // Compute the root farther from `-b` directly and derive the other from the product of
+        // the roots, avoiding the cancellation in `-b ± √discriminant`.
+        let q = -b - discriminant.sqrt().copysign(b);
+        let (t1, t2) = {
+            let (r1, r2) = (c / q, q);
+            if r1 <= r2 {
+                (r1, r2)
+            } else {
+                (r2, r1)
+            }
+        };
 
-        let t1 = -b - radical;
-        let t2 = -b + radical;
+        let clipped = self.is_clipped();
 
-        let t = [t1, t2]
-            .iter()
-            .copied()
-            .find(|t| (EPSILON..t_max).contains(t))?;
+        let t = [t1, t2].iter().copied().find(|&t| {
+            (t_min..t_max).contains(&t) && (!clipped || self.contains(ray.at(t) - self.center))
+        })?;
 
         let normal = Unit3::new_unchecked((ray.at(t) - self.center) / self.radius);
 
@@ -103,3 +226,294 @@ impl Geom for Sphere {
         })
     }
 }
+
+/// An infinite plane through `point`, whose outward side is the one `normal` points toward.
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Unit3,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Unit3) -> Self {
+        Self { point, normal }
+    }
+}
+
+impl Geom for Plane {
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
+        let denom = self.normal.dot(&ray.dir);
+        if denom == 0. {
+            return None;
+        }
+
+        let t = self.normal.dot(&(self.point - ray.origin)) / denom;
+        if !(t_min..t_max).contains(&t) {
+            return None;
+        }
+
+        Some(RawHitInfo {
+            t,
+            outward_normal: self.normal,
+        })
+    }
+}
+
+/// Places a geometry in the world through an arbitrary (invertible) affine transform.
+///
+/// Normals are transformed by the inverse transpose, so non-uniform scales (such as those turning
+/// a sphere into an ellipsoid) shade correctly.
+///
+/// The transform may also change over the camera's shutter interval (see
+/// [`Transformed::new_moving`]), blurring the geometry along its motion.
+pub struct Transformed<G> {
+    geom: G,
+    to_world: Transform3,
+    to_local: Transform3,
+    /// The transforms at times 0 and 1, if they differ.
+    motion: Option<(Decomposed, Decomposed)>,
+}
+
+impl<G: Geom> Transformed<G> {
+    /// Wraps `geom` with `to_world`, or returns `None` if the transform is singular.
+    pub fn new(geom: G, to_world: Transform3) -> Option<Self> {
+        let to_local = to_world.try_inverse()?;
+
+        Some(Self {
+            geom,
+            to_world,
+            to_local,
+            motion: None,
+        })
+    }
+
+    /// Wraps `geom` with a transform moving from `start` at time 0 to `end` at time 1, or returns
+    /// `None` if either transform is singular. Equal transforms make a static geometry.
+    ///
+    /// Each transform is split into a translation, a rotation and a remaining scale (which may
+    /// include shear). In between, the translations and scales are interpolated linearly and the
+    /// rotations spherically, along the shorter arc; rotations of half a turn or more between the
+    /// two keys therefore need to be split into several objects or keys.
+    pub fn new_moving(geom: G, start: Transform3, end: Transform3) -> Option<Self> {
+        if start == end {
+            return Self::new(geom, start);
+        }
+
+        let motion = (Decomposed::new(&start)?, Decomposed::new(&end)?);
+
+        Some(Self {
+            motion: Some(motion),
+            ..Self::new(geom, start)?
+        })
+    }
+
+    fn transforms_at(&self, time: f64) -> Option<(Transform3, Transform3)> {
+        match &self.motion {
+            Some((start, end)) => {
+                let to_world = start.interpolate(end, time.clamp(0., 1.));
+                Some((to_world, to_world.try_inverse()?))
+            }
+            None => Some((self.to_world, self.to_local)),
+        }
+    }
+}
+
+impl<G: Geom> Geom for Transformed<G> {
+    fn bounds(&self) -> Option<Aabb> {
+        const MOTION_STEPS: u32 = 64;
+
+        let local_corners = self.geom.bounds()?.corners();
+        let transform_corners = |to_world: &Transform3| {
+            local_corners.map(|corner| to_world.transform_point(&Point3::from(corner)).coords)
+        };
+
+        let corners = transform_corners(&self.to_world);
+        let bounds = corners[1..]
+            .iter()
+            .fold(Aabb::at_point(corners[0]), |aabb, &corner| {
+                aabb.extend(corner)
+            });
+
+        let (start, end) = match &self.motion {
+            Some(motion) => motion,
+            None => return Some(bounds),
+        };
+
+        // Enclose the corners at closely spaced times, padded by the furthest any of them moves
+        // between two consecutive times to cover the (curved) paths in between.
+        let mut bounds = bounds;
+        let mut prev_corners = corners;
+        let mut max_step = 0.;
+
+        for step in 1..=MOTION_STEPS {
+            let to_world = start.interpolate(end, step as f64 / MOTION_STEPS as f64);
+            let corners = transform_corners(&to_world);
+
+            for (corner, prev) in corners.iter().zip(&prev_corners) {
+                bounds = bounds.extend(*corner);
+                max_step = (corner - prev).norm().max(max_step);
+            }
+
+            prev_corners = corners;
+        }
+
+        let padding = Vec3::from_element(max_step);
+        Some(Aabb::new(
+            bounds.min_point - padding,
+            bounds.max_point + padding,
+        ))
+    }
+
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
+        let (_, to_local) = self.transforms_at(ray.time)?;
+
+        let origin = to_local.transform_point(&Point3::from(ray.origin)).coords;
+        let (dir, scale) = Unit3::new_and_get(to_local.transform_vector(&ray.dir));
+
+        // Distances along the local ray are stretched by `scale` relative to the world ray.
+        let local_ray = Ray::new(origin, dir).with_time(ray.time);
+        let raw = self.geom.hit(&local_ray, t_min * scale, t_max * scale)?;
+
+        let normal_transform = to_local.matrix().fixed_slice::<3, 3>(0, 0).transpose();
+
+        Some(RawHitInfo {
+            t: raw.t / scale,
+            outward_normal: Unit3::new_normalize(normal_transform * *raw.outward_normal),
+        })
+    }
+}
+
+/// An affine transform split into a translation, a rotation and a scale, for interpolation.
+struct Decomposed {
+    translation: Vec3,
+    rotation: UnitQuaternion<f64>,
+    scale: Matrix3<f64>,
+}
+
+impl Decomposed {
+    /// Decomposes `transform`, or returns `None` if it is singular.
+    fn new(transform: &Transform3) -> Option<Self> {
+        const MAX_ITERATIONS: u32 = 100;
+
+        let matrix = transform.matrix();
+        let translation = matrix.fixed_slice::<3, 1>(0, 3).into_owned();
+        let linear = matrix.fixed_slice::<3, 3>(0, 0).into_owned();
+
+        // Polar decomposition: averaging a matrix with its inverse transpose converges to the
+        // nearest orthogonal matrix.
+        let mut rotation = linear;
+        for _ in 0..MAX_ITERATIONS {
+            let next = 0.5 * (rotation + rotation.try_inverse()?.transpose());
+            let change = (next - rotation).abs().max();
+            rotation = next;
+
+            if change < 1e-12 {
+                break;
+            }
+        }
+
+        // Leave any reflection to the scale, so that the rotation is a proper one.
+        if rotation.determinant() < 0. {
+            rotation = -rotation;
+        }
+
+        Some(Self {
+            translation,
+            rotation: UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(
+                rotation,
+            )),
+            scale: rotation.transpose() * linear,
+        })
+    }
+
+    fn interpolate(&self, other: &Self, t: f64) -> Transform3 {
+        let translation = self.translation.lerp(&other.translation, t);
+        let rotation = self
+            .rotation
+            .try_slerp(&other.rotation, t, 1e-9)
+            .unwrap_or_else(|| self.rotation.nlerp(&other.rotation, t));
+        let scale = self.scale * (1. - t) + other.scale * t;
+
+        let mut matrix = Matrix4::identity();
+        matrix
+            .fixed_slice_mut::<3, 3>(0, 0)
+            .copy_from(&(rotation.to_rotation_matrix().into_inner() * scale));
+        matrix.fixed_slice_mut::<3, 1>(0, 3).copy_from(&translation);
+
+        Transform3::from_matrix_unchecked(matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transformed_t_min_is_in_world_units() {
+        // A sphere of radius 1 in world space, which a ray from `z = 5` enters at 4 and leaves
+        // at 6.
+        let sphere = Transformed::new(
+            Sphere::new(Vec3::default(), 0.5),
+            Transform3::from_matrix_unchecked(Matrix4::new_scaling(2.)),
+        )
+        .unwrap();
+        let ray = Ray::new(
+            Vec3::new(0., 0., 5.),
+            Unit3::new_normalize(Vec3::new(0., 0., -1.)),
+        );
+
+        let near = sphere.hit(&ray, 0., f64::INFINITY).unwrap();
+        assert!((near.t - 4.).abs() < 1e-12);
+
+        let far = sphere.hit(&ray, 5., f64::INFINITY).unwrap();
+        assert!((far.t - 6.).abs() < 1e-12);
+
+        assert!(sphere.hit(&ray, 6.5, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn nonuniformly_scaled_sphere_has_ellipsoid_normals() {
+        // The ellipsoid `(x / 2)² + y² + z² = 1`.
+        let ellipsoid = Transformed::new(
+            Sphere::new(Vec3::default(), 1.),
+            Transform3::from_matrix_unchecked(Matrix4::new_nonuniform_scaling(&Vec3::new(
+                2., 1., 1.,
+            ))),
+        )
+        .unwrap();
+        let down = Unit3::new_normalize(Vec3::new(0., 0., -1.));
+
+        let ray = Ray::new(Vec3::new(1.2, 0.4, 5.), down);
+        let hit = ellipsoid.hit(&ray, 0., f64::INFINITY).unwrap();
+        let point = ray.at(hit.t);
+        let z = (1. - 0.6f64.powi(2) - 0.4f64.powi(2)).sqrt();
+        assert!((point - Vec3::new(1.2, 0.4, z)).norm() < 1e-9);
+
+        // Normals follow the gradient of the implicit function, not the scaled sphere normal.
+        let gradient = Unit3::new_normalize(Vec3::new(point[0] / 4., point[1], point[2]));
+        assert!((*hit.outward_normal - *gradient).norm() < 1e-9);
+
+        assert!(ellipsoid
+            .hit(&Ray::new(Vec3::new(1.9, 0., 5.), down), 0., f64::INFINITY)
+            .is_some());
+        assert!(ellipsoid
+            .hit(&Ray::new(Vec3::new(2.1, 0., 5.), down), 0., f64::INFINITY)
+            .is_none());
+    }
+
+    #[test]
+    fn phi_max_is_in_radians() {
+        let half = Sphere::new(Vec3::default(), 1.).with_phi_max(f64::consts::PI);
+        let down = Unit3::new_normalize(Vec3::new(0., 0., -1.));
+
+        // Points at azimuth π/2 are kept, and points at 3π/2 are clipped away.
+        let kept = Ray::new(Vec3::new(0., 0.5, 5.), down);
+        assert!(half.hit(&kept, 0., f64::INFINITY).is_some());
+
+        let clipped = Ray::new(Vec3::new(0., -0.5, 5.), down);
+        assert!(half.hit(&clipped, 0., f64::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::math::{Aabb, OrthoNormalBasis, Ray, Unit3, Vec3, EPSILON};
+
+use super::{Geom, RawHitInfo};
+
+/// Number of subpatches along each parametric direction used to seed intersection searches.
+const SUBDIVISIONS: usize = 8;
+
+const MAX_NEWTON_ITERATIONS: u32 = 16;
+
+/// Slack allowed on the parametric domain when accepting a solution, so that hits exactly on the
+/// border between adjacent patches are not lost to rounding.
+const PARAM_TOLERANCE: f64 = 1e-7;
+
+struct Subpatch {
+    bounds: Aabb,
+    u_center: f64,
+    v_center: f64,
+}
+
+/// A bicubic Bézier patch.
+///
+/// Rays are intersected by Newton iteration, seeded from the center of every subpatch (in a fixed
+/// grid over the parametric domain) whose bounds the ray passes through. The nearest converged
+/// solution is reported.
+pub struct BezierPatch {
+    control_points: [[Vec3; 4]; 4],
+    bounds: Aabb,
+    subpatches: Vec<Subpatch>,
+    tolerance: f64,
+}
+
+impl BezierPatch {
+    /// Creates a patch from its control points, indexed as `control_points[v][u]`.
+    pub fn new(control_points: [[Vec3; 4]; 4]) -> Self {
+        let bounds = control_point_bounds(&control_points);
+        let extent = bounds.diagonal().norm();
+
+        let step = 1. / SUBDIVISIONS as f64;
+        let mut subpatches = Vec::with_capacity(SUBDIVISIONS * SUBDIVISIONS);
+
+        for i in 0..SUBDIVISIONS {
+            let (v0, v1) = (i as f64 * step, (i + 1) as f64 * step);
+
+            for j in 0..SUBDIVISIONS {
+                let (u0, u1) = (j as f64 * step, (j + 1) as f64 * step);
+
+                subpatches.push(Subpatch {
+                    bounds: control_point_bounds(&sub_patch(&control_points, u0, u1, v0, v1)),
+                    u_center: (u0 + u1) / 2.,
+                    v_center: (v0 + v1) / 2.,
+                });
+            }
+        }
+
+        Self {
+            control_points,
+            bounds,
+            subpatches,
+            tolerance: 1e-9 * (1. + extent),
+        }
+    }
+
+    /// Evaluates the patch and its partial derivatives at `(u, v)`.
+    fn eval(&self, u: f64, v: f64) -> (Vec3, Vec3, Vec3) {
+        let (bu, dbu) = (bernstein(u), bernstein_derivative(u));
+        let (bv, dbv) = (bernstein(v), bernstein_derivative(v));
+
+        let mut point = Vec3::default();
+        let mut du = Vec3::default();
+        let mut dv = Vec3::default();
+
+        for (i, row) in self.control_points.iter().enumerate() {
+            for (j, cp) in row.iter().enumerate() {
+                point += bv[i] * bu[j] * cp;
+                du += bv[i] * dbu[j] * cp;
+                dv += dbv[i] * bu[j] * cp;
+            }
+        }
+
+        (point, du, dv)
+    }
+
+    /// Searches for a point on the patch lying on the line through `ray`, starting at `(u, v)`.
+    ///
+    /// The line is represented as the intersection of two planes containing it, and Newton's
+    /// method is used to drive the signed distances of the surface point from both planes to zero.
+    fn newton(&self, ray: &Ray, planes: &OrthoNormalBasis, mut u: f64, mut v: f64) -> Option<Hit> {
+        let n1 = planes.u();
+        let n2 = planes.v();
+
+        let d1 = n1.dot(&ray.origin);
+        let d2 = n2.dot(&ray.origin);
+
+        for _ in 0..MAX_NEWTON_ITERATIONS {
+            let (point, du, dv) = self.eval(u, v);
+
+            let f1 = n1.dot(&point) - d1;
+            let f2 = n2.dot(&point) - d2;
+
+            if f1.abs() < self.tolerance && f2.abs() < self.tolerance {
+                return Some(Hit {
+                    t: (point - ray.origin).dot(&ray.dir),
+                    u,
+                    v,
+                    du,
+                    dv,
+                });
+            }
+
+            let (j11, j12) = (n1.dot(&du), n1.dot(&dv));
+            let (j21, j22) = (n2.dot(&du), n2.dot(&dv));
+
+            let det = j11 * j22 - j12 * j21;
+            if det.abs() < EPSILON * self.tolerance {
+                return None;
+            }
+
+            u -= (j22 * f1 - j12 * f2) / det;
+            v -= (j11 * f2 - j21 * f1) / det;
+
+            // Give up on iterates that have wandered far from the patch; they are unlikely to
+            // come back.
+            if !(-0.5..=1.5).contains(&u) || !(-0.5..=1.5).contains(&v) {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+struct Hit {
+    t: f64,
+    u: f64,
+    v: f64,
+    du: Vec3,
+    dv: Vec3,
+}
+
+impl Geom for BezierPatch {
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
+        if !self.bounds.hit(ray, t_min, t_max) {
+            return None;
+        }
+
+        let planes = OrthoNormalBasis::from_w(ray.dir);
+        let domain = -PARAM_TOLERANCE..=1. + PARAM_TOLERANCE;
+
+        let mut closest: Option<Hit> = None;
+        let mut t_max = t_max;
+
+        for subpatch in &self.subpatches {
+            if !subpatch.bounds.hit(ray, t_min, t_max) {
+                continue;
+            }
+
+            let hit = match self.newton(ray, &planes, subpatch.u_center, subpatch.v_center) {
+                Some(hit) => hit,
+                None => continue,
+            };
+
+            if (t_min..t_max).contains(&hit.t) && domain.contains(&hit.u) && domain.contains(&hit.v)
+            {
+                t_max = hit.t;
+                closest = Some(hit);
+            }
+        }
+
+        let hit = closest?;
+
+        let normal = match Unit3::try_new(hit.du.cross(&hit.dv), 0.) {
+            Some(normal) => normal,
+            None => {
+                // The patch is degenerate here (as happens where a row of control points
+                // collapses to a single point). Nudge toward the center to recover a normal.
+                let (_, du, dv) = self.eval(
+                    hit.u + 1e-6 * (0.5 - hit.u).signum(),
+                    hit.v + 1e-6 * (0.5 - hit.v).signum(),
+                );
+                Unit3::try_new(du.cross(&dv), 0.)?
+            }
+        };
+
+        Some(RawHitInfo {
+            t: hit.t,
+            outward_normal: normal,
+        })
+    }
+}
+
+fn bernstein(t: f64) -> [f64; 4] {
+    let s = 1. - t;
+    [s.powi(3), 3. * t * s.powi(2), 3. * t.powi(2) * s, t.powi(3)]
+}
+
+fn bernstein_derivative(t: f64) -> [f64; 4] {
+    let s = 1. - t;
+    [
+        -3. * s.powi(2),
+        3. * s.powi(2) - 6. * t * s,
+        6. * t * s - 3. * t.powi(2),
+        3. * t.powi(2),
+    ]
+}
+
+fn control_point_bounds(control_points: &[[Vec3; 4]; 4]) -> Aabb {
+    control_points
+        .iter()
+        .flatten()
+        .fold(Aabb::at_point(control_points[0][0]), |aabb, &cp| {
+            aabb.extend(cp)
+        })
+}
+
+/// Splits a cubic Bézier curve at `t` using de Casteljau's algorithm, returning both halves.
+fn split_curve(c: [Vec3; 4], t: f64) -> ([Vec3; 4], [Vec3; 4]) {
+    let p01 = c[0].lerp(&c[1], t);
+    let p12 = c[1].lerp(&c[2], t);
+    let p23 = c[2].lerp(&c[3], t);
+
+    let p012 = p01.lerp(&p12, t);
+    let p123 = p12.lerp(&p23, t);
+
+    let mid = p012.lerp(&p123, t);
+
+    ([c[0], p01, p012, mid], [mid, p123, p23, c[3]])
+}
+
+/// Returns the control points of the portion of a cubic Bézier curve between `t0` and `t1`.
+fn sub_curve(c: [Vec3; 4], t0: f64, t1: f64) -> [Vec3; 4] {
+    let (head, _) = split_curve(c, t1);
+    if t1 == 0. {
+        return head;
+    }
+
+    let (_, segment) = split_curve(head, t0 / t1);
+    segment
+}
+
+fn sub_patch(
+    control_points: &[[Vec3; 4]; 4],
+    u0: f64,
+    u1: f64,
+    v0: f64,
+    v1: f64,
+) -> [[Vec3; 4]; 4] {
+    let mut rows = [[Vec3::default(); 4]; 4];
+    for (row, cps) in rows.iter_mut().zip(control_points) {
+        *row = sub_curve(*cps, u0, u1);
+    }
+
+    let mut result = [[Vec3::default(); 4]; 4];
+    for j in 0..4 {
+        let column = sub_curve([rows[0][j], rows[1][j], rows[2][j], rows[3][j]], v0, v1);
+        for i in 0..4 {
+            result[i][j] = column[i];
+        }
+    }
+
+    result
+}
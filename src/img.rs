@@ -1,17 +1,23 @@
-use std::array::IntoIter;
 use std::io::Write;
+use std::str::FromStr;
 
 use png::{BitDepth, ColorType, Encoder, EncodingError};
 
 use crate::math::Vec3;
 
-fn luminance(color: &Vec3) -> f64 {
+pub fn luminance(color: &Vec3) -> f64 {
     0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
 }
 
-fn tone_map(color: &Vec3, max_y: f64) -> Vec3 {
+/// Applies Reinhard's extended tone mapping operator, `L (1 + L / L_white²) / (1 + L)`, to the
+/// luminance `L` of `color`, mapping the luminance `white` to exactly 1.
+///
+/// All channels are scaled by the same factor, preserving hue; channels of saturated colors near
+/// white may still exceed 1 and clip when encoded. Lower luminances are compressed less, and
+/// approach the identity as `white` grows.
+pub fn reinhard_extended(color: &Vec3, white: f64) -> Vec3 {
     let y = luminance(color);
-    let scale = (1. + y / max_y.powi(2)) / (1. + y);
+    let scale = (1. + y / white.powi(2)) / (1. + y);
 
     scale * color
 }
@@ -24,39 +30,447 @@ fn gamma_correct(v: f64) -> f64 {
     }
 }
 
-fn channel_to_raw(v: f64) -> u8 {
-    (gamma_correct(v) * 255. + 0.5).clamp(0., 255.) as u8
+/// Decodes a color stored with the sRGB transfer function (as colors picked in most tools, and the
+/// pixels of most 8-bit images, are) to the linear values used for rendering. This is the inverse
+/// of the encoding applied to rendered images.
+///
+/// Only colors should be decoded; other data stored in images, such as normals or roughness, is
+/// already linear.
+pub fn srgb_to_linear(color: Vec3) -> Vec3 {
+    color.map(|v| {
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    })
 }
 
-pub fn pixels_to_srgb(pixels: &[Vec3]) -> Vec<u8> {
-    let max_y = pixels
+/// The transfer function applied to tone-mapped linear values before quantizing them for output.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputColorSpace {
+    /// The piecewise sRGB curve.
+    #[default]
+    Srgb,
+    /// A pure power curve, raising values to `1 / gamma`.
+    Gamma(f64),
+    /// No transfer function; values are written linearly.
+    Linear,
+}
+
+impl OutputColorSpace {
+    pub fn encode(&self, v: f64) -> f64 {
+        match *self {
+            OutputColorSpace::Srgb => gamma_correct(v),
+            OutputColorSpace::Gamma(gamma) => v.max(0.).powf(1. / gamma),
+            OutputColorSpace::Linear => v,
+        }
+    }
+}
+
+impl FromStr for OutputColorSpace {
+    type Err = String;
+
+    /// Parses `"srgb"`, `"linear"` or `"gamma:<value>"`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" => Ok(OutputColorSpace::Srgb),
+            "linear" => Ok(OutputColorSpace::Linear),
+            _ => {
+                let gamma = s
+                    .strip_prefix("gamma:")
+                    .ok_or_else(|| format!("unknown color space '{}'", s))?;
+
+                match gamma.parse() {
+                    Ok(gamma) if gamma > 0. => Ok(OutputColorSpace::Gamma(gamma)),
+                    _ => Err(format!("invalid gamma '{}'", gamma)),
+                }
+            }
+        }
+    }
+}
+
+fn channel_to_raw(v: f64, color_space: OutputColorSpace) -> u8 {
+    (color_space.encode(v) * 255. + 0.5).clamp(0., 255.) as u8
+}
+
+/// Color used to flag clipped pixels in [`pixels_to_srgb_with_clip_overlay`].
+pub const CLIP_OVERLAY_COLOR: Vec3 = Vec3::new(1., 0., 1.);
+
+/// Returns the luminance of the brightest pixel, which is mapped to white by [`pixels_to_srgb`].
+pub fn max_luminance(pixels: &[Vec3]) -> f64 {
+    pixels
         .iter()
         .map(luminance)
         .max_by(|y1, y2| y1.partial_cmp(y2).unwrap())
-        .unwrap_or(1.);
+        .unwrap_or(1.)
+}
 
-    pixels
+/// Small luminance added to every pixel when taking the log-average luminance of an image, so
+/// that nearly black pixels do not send it to zero.
+const LOG_AVERAGE_DELTA: f64 = 1e-4;
+
+/// Returns the log-average (geometric mean) luminance of `pixels`, `exp(mean(ln(δ + L)))` for a
+/// small `δ`, which estimates the overall brightness of an image without being swayed by a few
+/// very bright pixels.
+///
+/// Pixels that are exactly black (such as an empty background) say nothing about how the scene
+/// is lit, and would drag the average down and overexpose everything else, so they are left out
+/// along with pixels of NaN or infinite luminance. An image without any others has an average
+/// of 1.
+pub fn log_average_luminance(pixels: &[Vec3]) -> f64 {
+    let (sum, count) = pixels
+        .iter()
+        .map(luminance)
+        .filter(|&y| y.is_finite() && y > 0.)
+        .fold((0., 0), |(sum, count), y| {
+            (sum + (LOG_AVERAGE_DELTA + y.max(0.)).ln(), count + 1)
+        });
+
+    if count == 0 {
+        1.
+    } else {
+        (sum / count as f64).exp()
+    }
+}
+
+/// The exposure with which an image is tone mapped: its pixels are scaled by `scale`, after which
+/// the luminance `white` maps to white (see [`reinhard_extended`]).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exposure {
+    pub scale: f64,
+    pub white: f64,
+}
+
+impl Exposure {
+    /// Returns an exposure leaving pixels unscaled and mapping the luminance `white` to white.
+    pub fn with_white_point(white: f64) -> Self {
+        Self { scale: 1., white }
+    }
+}
+
+/// How the exposure of an image is chosen from its pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExposureMode {
+    /// Leaves the pixels as they are and maps the luminance of the brightest one to white. A
+    /// single bright pixel (such as a visible light) decides the exposure, and dim images stay
+    /// dim.
+    #[default]
+    MaxLuminance,
+    /// Reinhard's key-value method: scales the pixels so that their
+    /// [log-average luminance](log_average_luminance) becomes `key` (0.18 for a middle-gray
+    /// scene), then maps the brightest scaled luminance to white. The white point is clamped to
+    /// at least 1, so that images without highlights are not brightened further.
+    KeyValue { key: f64 },
+}
+
+impl ExposureMode {
+    /// Chooses the exposure of an image made up of `pixels`.
+    pub fn exposure(&self, pixels: &[Vec3]) -> Exposure {
+        match *self {
+            ExposureMode::MaxLuminance => Exposure::with_white_point(max_luminance(pixels)),
+            ExposureMode::KeyValue { key } => {
+                let scale = key / log_average_luminance(pixels);
+                Exposure {
+                    scale,
+                    white: (scale * max_luminance(pixels)).max(1.),
+                }
+            }
+        }
+    }
+}
+
+/// Statistics of the luminance of an image's pixels, before any tone mapping. Pixels with NaN or
+/// infinite luminance are counted in `non_finite` and left out of everything else.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadianceStats {
+    /// Number of pixels with finite luminance, which the other statistics describe. These are all
+    /// zero if there are none.
+    pub finite: usize,
+    pub non_finite: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Luminance not exceeded by 99% of the pixels. Fireflies show as a maximum far above it.
+    pub percentile_99: f64,
+    /// Fraction of the pixels with finite luminance above the threshold passed to
+    /// [`radiance_stats`].
+    pub fraction_above: f64,
+}
+
+/// Computes [`RadianceStats`] of `pixels`, counting pixels with luminance above `threshold` (such
+/// as a candidate white point or firefly clamp) in [`RadianceStats::fraction_above`].
+pub fn radiance_stats(pixels: &[Vec3], threshold: f64) -> RadianceStats {
+    let mut luminances: Vec<_> = pixels
         .iter()
-        .map(|color| tone_map(color, max_y))
+        .map(luminance)
+        .filter(|y| y.is_finite())
+        .collect();
+    let non_finite = pixels.len() - luminances.len();
+
+    if luminances.is_empty() {
+        return RadianceStats {
+            finite: 0,
+            non_finite,
+            min: 0.,
+            max: 0.,
+            mean: 0.,
+            percentile_99: 0.,
+            fraction_above: 0.,
+        };
+    }
+
+    luminances.sort_unstable_by(f64::total_cmp);
+
+    let count = luminances.len();
+    // Nearest-rank percentile: the smallest value at least 99% of the pixels do not exceed.
+    let rank = (count as f64 * 0.99).ceil() as usize;
+    let above = luminances.len() - luminances.partition_point(|&y| y <= threshold);
+
+    RadianceStats {
+        finite: count,
+        non_finite,
+        min: luminances[0],
+        max: luminances[count - 1],
+        mean: luminances.iter().sum::<f64>() / count as f64,
+        percentile_99: luminances[rank.clamp(1, count) - 1],
+        fraction_above: above as f64 / count as f64,
+    }
+}
+
+fn colors_to_raw(colors: impl Iterator<Item = Vec3>, color_space: OutputColorSpace) -> Vec<u8> {
+    colors
         .flat_map(|color| {
             let vals: [_; 3] = color.into();
-            IntoIter::new(vals)
+            IntoIterator::into_iter(vals)
+        })
+        .map(|v| channel_to_raw(v, color_space))
+        .collect()
+}
+
+/// Tone maps `pixels` with [`reinhard_extended`] so that the luminance `max_y` maps to white, and
+/// encodes them in `color_space`. If `clip_overlay` is set, pixels with any channel that would be
+/// clipped are replaced by [`CLIP_OVERLAY_COLOR`].
+pub fn pixels_to_raw(
+    pixels: &[Vec3],
+    max_y: f64,
+    color_space: OutputColorSpace,
+    clip_overlay: bool,
+) -> Vec<u8> {
+    exposed_pixels_to_raw(
+        pixels,
+        Exposure::with_white_point(max_y),
+        color_space,
+        clip_overlay,
+    )
+}
+
+/// Like [`pixels_to_raw`], but scales the pixels according to `exposure` before tone mapping
+/// them.
+pub fn exposed_pixels_to_raw(
+    pixels: &[Vec3],
+    exposure: Exposure,
+    color_space: OutputColorSpace,
+    clip_overlay: bool,
+) -> Vec<u8> {
+    colors_to_raw(
+        pixels.iter().map(|color| {
+            let mapped = reinhard_extended(&(exposure.scale * color), exposure.white);
+            if clip_overlay && mapped.max() > 1. {
+                CLIP_OVERLAY_COLOR
+            } else {
+                mapped
+            }
+        }),
+        color_space,
+    )
+}
+
+/// Encodes `pixels` in `color_space` as they are, without tone mapping, for images whose values
+/// already lie in `[0, 1]` (such as albedos). Values outside that range are clamped.
+pub fn unmapped_pixels_to_raw(pixels: &[Vec3], color_space: OutputColorSpace) -> Vec<u8> {
+    colors_to_raw(pixels.iter().copied(), color_space)
+}
+
+pub fn pixels_to_srgb(pixels: &[Vec3]) -> Vec<u8> {
+    pixels_to_srgb_with_max_luminance(pixels, max_luminance(pixels))
+}
+
+/// Like [`pixels_to_srgb`], but maps the luminance `max_y` to white instead of that of the
+/// brightest pixel, so that several images can share a single exposure.
+pub fn pixels_to_srgb_with_max_luminance(pixels: &[Vec3], max_y: f64) -> Vec<u8> {
+    pixels_to_raw(pixels, max_y, OutputColorSpace::Srgb, false)
+}
+
+/// Like [`pixels_to_srgb`], but replaces pixels with any channel that would be clipped after tone
+/// mapping by [`CLIP_OVERLAY_COLOR`].
+pub fn pixels_to_srgb_with_clip_overlay(pixels: &[Vec3]) -> Vec<u8> {
+    pixels_to_raw(pixels, max_luminance(pixels), OutputColorSpace::Srgb, true)
+}
+
+/// Options for [`to_rgba8`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RgbaOptions<'a> {
+    /// Luminance mapped to white, or `None` for that of the brightest pixel.
+    pub max_luminance: Option<f64>,
+    pub color_space: OutputColorSpace,
+    /// Per-pixel coverage in `[0, 1]` used as alpha, or `None` for fully opaque pixels.
+    pub coverage: Option<&'a [f64]>,
+}
+
+/// Tone maps and encodes `pixels` into tightly-packed RGBA bytes, suitable for uploading as a
+/// texture. With the default options, the RGB values are exactly those of [`pixels_to_srgb`].
+pub fn to_rgba8(pixels: &[Vec3], opts: &RgbaOptions<'_>) -> Vec<u8> {
+    if let Some(coverage) = opts.coverage {
+        assert_eq!(coverage.len(), pixels.len());
+    }
+
+    let max_y = opts.max_luminance.unwrap_or_else(|| max_luminance(pixels));
+    let rgb = pixels_to_raw(pixels, max_y, opts.color_space, false);
+
+    rgb.chunks_exact(3)
+        .enumerate()
+        .flat_map(|(idx, color)| {
+            let alpha = opts.coverage.map_or(255, |coverage| {
+                channel_to_raw(coverage[idx], OutputColorSpace::Linear)
+            });
+            [color[0], color[1], color[2], alpha]
+        })
+        .collect()
+}
+
+/// Color ramp used by [`heatmap`], running from the lowest values to the highest.
+const HEATMAP_RAMP: [[f64; 3]; 5] = [
+    [0., 0., 0.5],
+    [0., 0.6, 1.],
+    [0.2, 0.8, 0.2],
+    [1., 0.85, 0.],
+    [0.9, 0., 0.],
+];
+
+/// Visualizes per-pixel scalar values as raw RGB pixels, mapping `min` to the cold end of a color
+/// ramp and `max` to the hot end. Values outside the range are clamped.
+pub fn heatmap(values: &[f64], min: f64, max: f64, width: u32, height: u32) -> Vec<u8> {
+    assert_eq!(values.len(), (width * height) as usize);
+
+    let range = if max > min { max - min } else { 1. };
+
+    values
+        .iter()
+        .flat_map(|&value| {
+            let frac = ((value - min) / range).clamp(0., 1.);
+            let pos = frac * (HEATMAP_RAMP.len() - 1) as f64;
+            let lo = (pos.floor() as usize).min(HEATMAP_RAMP.len() - 2);
+            let t = pos - lo as f64;
+
+            let color = Vec3::from(HEATMAP_RAMP[lo]).lerp(&Vec3::from(HEATMAP_RAMP[lo + 1]), t);
+            let vals: [_; 3] = color.into();
+            IntoIterator::into_iter(vals).map(|v| (v * 255. + 0.5) as u8)
         })
-        .map(channel_to_raw)
         .collect()
 }
 
+/// Visualizes per-pixel sample counts with [`heatmap`], normalized to the smallest and largest
+/// counts present.
+pub fn samples_to_heatmap(counts: &[u32], width: u32, height: u32) -> Vec<u8> {
+    let min = counts.iter().copied().min().unwrap_or(0);
+    let max = counts.iter().copied().max().unwrap_or(0);
+
+    let values: Vec<_> = counts.iter().map(|&count| count as f64).collect();
+    heatmap(&values, min as f64, max as f64, width, height)
+}
+
 pub fn write_png<W: Write>(
     writer: &mut W,
     raw_pixels: &[u8],
     width: u32,
     height: u32,
 ) -> Result<(), EncodingError> {
-    assert_eq!(raw_pixels.len(), (width * height * 3) as usize);
+    write_png_with_color(writer, raw_pixels, width, height, ColorType::RGB, 3)
+}
+
+/// Like [`write_png`], but takes RGBA pixels such as those produced by [`to_rgba8`].
+pub fn write_png_rgba<W: Write>(
+    writer: &mut W,
+    raw_pixels: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), EncodingError> {
+    write_png_with_color(writer, raw_pixels, width, height, ColorType::RGBA, 4)
+}
+
+fn write_png_with_color<W: Write>(
+    writer: &mut W,
+    raw_pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    channels: u32,
+) -> Result<(), EncodingError> {
+    assert_eq!(raw_pixels.len(), (width * height * channels) as usize);
 
     let mut enc = Encoder::new(writer, width, height);
-    enc.set_color(ColorType::RGB);
+    enc.set_color(color);
     enc.set_depth(BitDepth::Eight);
 
     enc.write_header()?.write_image_data(raw_pixels)
 }
+
+/// A rendered image, holding linear radiance values in row-major order.
+#[derive(Debug, Clone)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn into_pixels(self) -> Vec<Vec3> {
+        self.pixels
+    }
+
+    /// Copies `src` into this image with its top-left corner at `(x, y)`. Panics if `src` does not
+    /// fit.
+    pub fn paste(&mut self, src: &Image, x: u32, y: u32) {
+        assert!(x + src.width <= self.width && y + src.height <= self.height);
+
+        for (row, src_row) in src.pixels.chunks(src.width as usize).enumerate() {
+            let start = ((y + row as u32) * self.width + x) as usize;
+            self.pixels[start..start + src_row.len()].copy_from_slice(src_row);
+        }
+    }
+
+    pub fn to_srgb(&self) -> Vec<u8> {
+        pixels_to_srgb(&self.pixels)
+    }
+
+    pub fn to_rgba8(&self, opts: &RgbaOptions<'_>) -> Vec<u8> {
+        to_rgba8(&self.pixels, opts)
+    }
+
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> Result<(), EncodingError> {
+        write_png(writer, &self.to_srgb(), self.width, self.height)
+    }
+}
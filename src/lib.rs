@@ -0,0 +1,16 @@
+pub mod animation;
+pub mod config;
+pub mod distr;
+pub mod geom;
+pub mod img;
+pub mod light;
+pub mod material;
+pub mod math;
+pub mod microfacet;
+pub mod montage;
+pub mod pointcloud;
+pub mod render;
+pub mod scene;
+pub mod shading;
+pub mod spectral;
+pub mod texture;
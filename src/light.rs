@@ -1,29 +1,74 @@
-use rand::RngCore;
+use std::f64;
+use std::sync::Arc;
 
-use crate::geom::HitInfo;
-use crate::math::{Ray, Unit3, Vec3};
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, UnitSphere};
+
+use crate::distr::CosWeightedHemisphere;
+use crate::geom::{Geom, HitInfo, Sphere};
+use crate::img::luminance;
+use crate::math::{Aabb, OrthoNormalBasis, Ray, Unit3, Vec2, Vec3, EPSILON};
 use crate::shading::SampledRadiance;
+use crate::texture::Texture;
+
+pub use self::sky::PreethamSky;
+
+mod sky;
 
 #[derive(Debug, Clone, Copy)]
 pub struct SampledLightRadiance {
     pub radiance: SampledRadiance,
-    pub t: f64,
+    /// Distance to the sampled point on the light, or `None` if the light lies at infinity.
+    pub dist: Option<f64>,
 }
 
 impl SampledLightRadiance {
-    pub fn new(radiance: SampledRadiance, t: f64) -> Self {
-        Self { radiance, t }
+    pub fn new(radiance: SampledRadiance, dist: f64) -> Self {
+        Self {
+            radiance,
+            dist: Some(dist),
+        }
+    }
+
+    /// Creates a sample of a light at infinity, which only geometry can occlude.
+    pub fn new_infinite(radiance: SampledRadiance) -> Self {
+        Self {
+            radiance,
+            dist: None,
+        }
     }
 }
 
 pub struct EmittedRadiance {
     pub color: Vec3,
-    pub t: f64,
+    /// Distance along the ray to the light, or `None` if the light lies at infinity.
+    pub dist: Option<f64>,
 }
 
 impl EmittedRadiance {
-    pub fn new(color: Vec3, t: f64) -> Self {
-        Self { color, t }
+    pub fn new(color: Vec3, dist: f64) -> Self {
+        Self {
+            color,
+            dist: Some(dist),
+        }
+    }
+
+    pub fn new_infinite(color: Vec3) -> Self {
+        Self { color, dist: None }
+    }
+}
+
+/// A photon leaving a light, carrying `power` (the light's total power divided by the density
+/// with which the photon was sampled) along `ray`.
+#[derive(Debug, Clone, Copy)]
+pub struct EmittedPhoton {
+    pub ray: Ray,
+    pub power: Vec3,
+}
+
+impl EmittedPhoton {
+    pub fn new(ray: Ray, power: Vec3) -> Self {
+        Self { ray, power }
     }
 }
 
@@ -36,16 +81,82 @@ pub trait Light {
     fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64;
 
     fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance>;
+
+    /// Samples a photon leaving the light, for use by photon mapping. Lights that cannot emit
+    /// photons (such as those at infinity, which have no finite power) return `None`.
+    fn sample_photon(&self, _rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        None
+    }
+
+    /// Returns a box outside of which the light illuminates nothing, or `None` if it may reach
+    /// any point.
+    ///
+    /// Only lights whose bounds contain the point being shaded are sampled there, so no light
+    /// may reach points outside its bounds.
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    /// Returns `true` if the light lies at infinity, surrounding the scene as its background (as
+    /// skies do).
+    fn is_infinite(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if the light is known to emit nothing at all. Used only to warn about
+    /// likely mistakes in scene setup, so lights may conservatively return `false`.
+    fn is_black(&self) -> bool {
+        false
+    }
+}
+
+/// A crude stand-in for indirect lighting: constant radiance reflected off every non-specular
+/// surface, in proportion to its albedo and regardless of occlusion.
+///
+/// This is not a [`Light`], since it neither casts shadows nor can be sampled; it is attached to a
+/// scene with [`SceneBuilder::set_ambient`](crate::scene::SceneBuilder::set_ambient).
+#[derive(Debug, Clone, Copy)]
+pub struct AmbientLight {
+    pub color: Vec3,
 }
 
+impl AmbientLight {
+    pub fn new(color: Vec3) -> Self {
+        Self { color }
+    }
+}
+
+/// A light radiating equally in all directions from a single point.
+///
+/// The light's color is its radiant intensity, the power emitted per unit solid angle, so that a
+/// surface at distance `d` facing the light receives `color / d²`.
 pub struct PointLight {
     point: Vec3,
     color: Vec3,
+    range: Option<f64>,
 }
 
 impl PointLight {
     pub fn new(point: Vec3, color: Vec3) -> Self {
-        Self { point, color }
+        Self {
+            point,
+            color,
+            range: None,
+        }
+    }
+
+    /// Cuts the light off entirely at points further than `range` away from it. This is not
+    /// physical, but lets scenes with many small lights skip the ones too far away to matter.
+    /// Caustics gathered from photons are not cut off.
+    pub fn with_range(mut self, range: f64) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Scales the light's color by `intensity`, so that the color can describe only its tint.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.color *= intensity;
+        self
     }
 }
 
@@ -56,6 +167,10 @@ impl Light for PointLight {
         _rng: &mut dyn RngCore,
     ) -> Option<SampledLightRadiance> {
         let (dir, t) = Unit3::new_and_get(self.point - hit.point);
+        if !in_range(t, self.range) {
+            return None;
+        }
+
         Some(SampledLightRadiance::new(
             SampledRadiance::new_delta(hit.world_to_local(dir), self.color / t.powi(2)),
             t,
@@ -69,4 +184,1008 @@ impl Light for PointLight {
     fn emitted(&self, _ray: &Ray) -> Option<EmittedRadiance> {
         None
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.range.map(|range| range_bounds(self.point, range))
+    }
+
+    fn sample_photon(&self, rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        let [x, y, z]: [f64; 3] = UnitSphere.sample(rng);
+        let dir = Unit3::new_normalize(Vec3::new(x, y, z));
+
+        Some(EmittedPhoton::new(
+            Ray::new(self.point, dir),
+            2. * f64::consts::TAU * self.color,
+        ))
+    }
+
+    fn is_black(&self) -> bool {
+        self.color == Vec3::default()
+    }
+}
+
+/// A point light restricted to a cone. As with [`PointLight`], the color is the radiant
+/// intensity along the cone's axis.
+pub struct SpotLight {
+    point: Vec3,
+    basis: OrthoNormalBasis,
+    cos_cutoff: f64,
+    tan_cutoff: f64,
+    color: Vec3,
+    gobo: Option<Arc<dyn Texture + Send + Sync>>,
+    range: Option<f64>,
+}
+
+impl SpotLight {
+    /// Creates a spot light at `point` shining toward `target`, with a cone of half-angle
+    /// `cone_angle` (in degrees).
+    pub fn new(point: Vec3, target: Vec3, cone_angle: f64, color: Vec3) -> Self {
+        let cutoff = cone_angle.to_radians();
+
+        Self {
+            point,
+            basis: OrthoNormalBasis::from_w(Unit3::new_normalize(target - point)),
+            cos_cutoff: cutoff.cos(),
+            tan_cutoff: cutoff.tan(),
+            color,
+            gobo: None,
+            range: None,
+        }
+    }
+
+    /// Modulates the emitted color by `gobo`, which is projected across the cone so that the
+    /// texture's unit square circumscribes the cone's cross-section.
+    pub fn with_gobo(mut self, gobo: Arc<dyn Texture + Send + Sync>) -> Self {
+        self.gobo = Some(gobo);
+        self
+    }
+
+    /// See [`PointLight::with_range`].
+    pub fn with_range(mut self, range: f64) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// See [`PointLight::with_intensity`].
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.color *= intensity;
+        self
+    }
+
+    fn intensity(&self, dir: Unit3) -> Option<Vec3> {
+        let light_dir = self.basis.trans_from_canonical(*dir);
+        if light_dir[2] < self.cos_cutoff {
+            return None;
+        }
+
+        let gobo = match &self.gobo {
+            Some(gobo) => gobo,
+            None => return Some(self.color),
+        };
+
+        // Project onto the plane at unit distance along the axis, so the pattern depends only on
+        // the angle and stays sharp regardless of how far away it lands.
+        let scale = 0.5 / (light_dir[2] * self.tan_cutoff);
+        let uv = Vec2::new(0.5 + light_dir[0] * scale, 0.5 + light_dir[1] * scale);
+
+        Some(self.color.component_mul(&gobo.value(uv)))
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        _rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        let (dir, t) = Unit3::new_and_get(self.point - hit.point);
+        if !in_range(t, self.range) {
+            return None;
+        }
+
+        let intensity = self.intensity(-dir)?;
+
+        Some(SampledLightRadiance::new(
+            SampledRadiance::new_delta(hit.world_to_local(dir), intensity / t.powi(2)),
+            t,
+        ))
+    }
+
+    fn pdf(&self, _hit: &HitInfo, _local_dir: Unit3) -> f64 {
+        0.
+    }
+
+    fn emitted(&self, _ray: &Ray) -> Option<EmittedRadiance> {
+        None
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.range.map(|range| range_bounds(self.point, range))
+    }
+
+    fn sample_photon(&self, rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        let dir = Unit3::new_normalize(
+            self.basis
+                .trans_to_canonical(*sample_uniform_cone(self.cos_cutoff, rng)),
+        );
+        let intensity = self.intensity(dir)?;
+
+        Some(EmittedPhoton::new(
+            Ray::new(self.point, dir),
+            intensity / uniform_cone_pdf(self.cos_cutoff),
+        ))
+    }
+
+    fn is_black(&self) -> bool {
+        self.color == Vec3::default()
+    }
+}
+
+fn in_range(dist: f64, range: Option<f64>) -> bool {
+    range.is_none_or(|range| dist <= range)
+}
+
+/// Returns the bounds of the points within `range` of `point`.
+fn range_bounds(point: Vec3, range: f64) -> Aabb {
+    let extent = Vec3::from_element(range);
+    Aabb::new(point - extent, point + extent)
+}
+
+/// A spherical area light emitting uniform radiance from its surface. The light's color is that
+/// radiance, so its brightness as seen by the camera does not depend on its size.
+///
+/// Lights are not part of the scene geometry, so the sphere neither blocks other light nor casts
+/// shadows.
+pub struct SphereLight {
+    sphere: Sphere,
+    color: Vec3,
+}
+
+impl SphereLight {
+    pub fn new(center: Vec3, radius: f64, color: Vec3) -> Self {
+        Self {
+            sphere: Sphere::new(center, radius),
+            color,
+        }
+    }
+
+    /// See [`PointLight::with_intensity`].
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.color *= intensity;
+        self
+    }
+
+    /// Returns the cosine of the half-angle of the cone subtended by the sphere at `point`, or
+    /// `None` if `point` lies inside the sphere.
+    fn cos_cone_angle(&self, point: Vec3) -> Option<f64> {
+        let dist2 = (self.sphere.center - point).norm_squared();
+        let sin2_max = self.sphere.radius.powi(2) / dist2;
+
+        if sin2_max >= 1. {
+            None
+        } else {
+            Some((1. - sin2_max).sqrt())
+        }
+    }
+
+    /// Returns the solid-angle density of sampling `dir` from `hit` (which must actually reach the
+    /// sphere, at distance `t` and cosine `cos_light` with the surface normal there).
+    fn pdf_for(&self, hit: &HitInfo, t: f64, cos_light: f64) -> f64 {
+        match self.cos_cone_angle(hit.point) {
+            Some(cos_max) => uniform_cone_pdf(cos_max),
+            None => {
+                let area = 4. * f64::consts::PI * self.sphere.radius.powi(2);
+                t.powi(2) / (cos_light.abs() * area)
+            }
+        }
+    }
+}
+
+impl Light for SphereLight {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        let dir = match self.cos_cone_angle(hit.point) {
+            // Only the cap facing the shading point is visible, so sample directions toward it
+            // instead of wasting samples on the far side of the sphere.
+            Some(cos_max) => {
+                let basis =
+                    OrthoNormalBasis::from_w(Unit3::new_normalize(self.sphere.center - hit.point));
+
+                Unit3::new_normalize(basis.trans_to_canonical(*sample_uniform_cone(cos_max, rng)))
+            }
+            None => {
+                let [x, y, z]: [f64; 3] = UnitSphere.sample(rng);
+                let point = self.sphere.center + self.sphere.radius * Vec3::new(x, y, z);
+                Unit3::try_new(point - hit.point, 0.)?
+            }
+        };
+
+        let ray = hit.spawn_world_ray(dir);
+        let surface = self.sphere.hit(&ray, EPSILON, f64::INFINITY)?;
+
+        let pdf = self.pdf_for(hit, surface.t, surface.outward_normal.dot(&dir));
+        Some(SampledLightRadiance::new(
+            SampledRadiance::new_real(hit.world_to_local(dir), self.color, pdf),
+            surface.t,
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        let dir = hit.local_to_world(local_dir);
+
+        match self
+            .sphere
+            .hit(&hit.spawn_world_ray(dir), EPSILON, f64::INFINITY)
+        {
+            Some(surface) => self.pdf_for(hit, surface.t, surface.outward_normal.dot(&dir)),
+            None => 0.,
+        }
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        let surface = self.sphere.hit(ray, EPSILON, f64::INFINITY)?;
+        Some(EmittedRadiance::new(self.color, surface.t))
+    }
+
+    fn sample_photon(&self, rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        let [x, y, z]: [f64; 3] = UnitSphere.sample(rng);
+        let normal = Unit3::new_normalize(Vec3::new(x, y, z));
+        let point = self.sphere.center + self.sphere.radius * *normal;
+
+        let area = 2. * f64::consts::TAU * self.sphere.radius.powi(2);
+        Some(EmittedPhoton::new(
+            Ray::new(point, sample_cos_weighted_around(normal, rng)),
+            f64::consts::PI * area * self.color,
+        ))
+    }
+
+    fn is_black(&self) -> bool {
+        self.color == Vec3::default()
+    }
+}
+
+/// Samples a direction uniformly within a cone about the `z` axis whose half-angle has cosine
+/// `cos_max`.
+fn sample_uniform_cone(cos_max: f64, rng: &mut dyn RngCore) -> Unit3 {
+    let cos_theta = 1. - rng.gen::<f64>() * (1. - cos_max);
+    let sin_theta = (1. - cos_theta.powi(2)).max(0.).sqrt();
+    let phi = rng.gen_range(0.0..f64::consts::TAU);
+
+    Unit3::new_normalize(Vec3::new(
+        sin_theta * phi.cos(),
+        sin_theta * phi.sin(),
+        cos_theta,
+    ))
+}
+
+/// Returns the density of directions sampled uniformly within a cone whose half-angle has cosine
+/// `cos_max`.
+fn uniform_cone_pdf(cos_max: f64) -> f64 {
+    1. / (f64::consts::TAU * (1. - cos_max))
+}
+
+/// Samples a cosine-weighted direction about `normal`, as emitted by a diffuse surface.
+fn sample_cos_weighted_around(normal: Unit3, rng: &mut dyn RngCore) -> Unit3 {
+    let local = CosWeightedHemisphere.sample(rng);
+    Unit3::new_normalize(OrthoNormalBasis::from_w(normal).trans_to_canonical(*local))
+}
+
+/// A one-sided rectangular (or parallelogram-shaped) area light whose emission varies across its
+/// surface according to a texture, like a screen displaying an image. The texture gives the
+/// emitted radiance.
+///
+/// The light emits from the side toward which `edge_u × edge_v` points. Like all lights, it is not
+/// part of the scene geometry and does not block other light.
+pub struct QuadLight {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Unit3,
+    /// `edge_u × edge_v`, scaled by the inverse of its squared norm, used to recover texture
+    /// coordinates from points on the quad.
+    inv_cross: Vec3,
+    area: f64,
+    emission: Arc<dyn Texture + Send + Sync>,
+    intensity: f64,
+}
+
+impl QuadLight {
+    /// Creates a light spanning the parallelogram with one corner at `corner` and sides `edge_u`
+    /// and `edge_v`, which map to the texture's `u` and `v` axes. Returns `None` if the edges are
+    /// degenerate.
+    pub fn new(
+        corner: Vec3,
+        edge_u: Vec3,
+        edge_v: Vec3,
+        emission: Arc<dyn Texture + Send + Sync>,
+    ) -> Option<Self> {
+        let cross = edge_u.cross(&edge_v);
+        let (normal, area) = Unit3::try_new_and_get(cross, 0.)?;
+
+        Some(Self {
+            corner,
+            edge_u,
+            edge_v,
+            normal,
+            inv_cross: cross / cross.norm_squared(),
+            area,
+            emission,
+            intensity: 1.,
+        })
+    }
+
+    /// Scales the emission texture by `intensity`.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity *= intensity;
+        self
+    }
+
+    fn radiance(&self, uv: Vec2) -> Vec3 {
+        self.intensity * self.emission.value(uv)
+    }
+
+    /// Finds where `ray` strikes the emitting side of the light, returning the distance along the
+    /// ray and the texture coordinates there.
+    fn intersect(&self, ray: &Ray) -> Option<(f64, Vec2)> {
+        let denom = self.normal.dot(&ray.dir);
+        if denom >= 0. {
+            return None;
+        }
+
+        let t = self.normal.dot(&(self.corner - ray.origin)) / denom;
+        if t < EPSILON {
+            return None;
+        }
+
+        let offset = ray.at(t) - self.corner;
+        let uv = Vec2::new(
+            self.inv_cross.dot(&offset.cross(&self.edge_v)),
+            self.inv_cross.dot(&self.edge_u.cross(&offset)),
+        );
+
+        if !(0. ..=1.).contains(&uv[0]) || !(0. ..=1.).contains(&uv[1]) {
+            return None;
+        }
+
+        Some((t, uv))
+    }
+
+    /// Returns the solid-angle density of sampling a direction that reaches the light at
+    /// distance `t`.
+    fn pdf_for(&self, dir: Unit3, t: f64) -> f64 {
+        t.powi(2) / (-self.normal.dot(&dir) * self.area)
+    }
+}
+
+impl Light for QuadLight {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        // Positions are sampled uniformly and weighted by the emission, which keeps the pdf simple
+        // at the cost of some noise where the texture is mostly dark.
+        let uv = Vec2::new(rng.gen(), rng.gen());
+        let point = self.corner + uv[0] * self.edge_u + uv[1] * self.edge_v;
+
+        let (dir, t) = Unit3::try_new_and_get(point - hit.point, 0.)?;
+        if self.normal.dot(&dir) >= 0. {
+            return None;
+        }
+
+        Some(SampledLightRadiance::new(
+            SampledRadiance::new_real(
+                hit.world_to_local(dir),
+                self.radiance(uv),
+                self.pdf_for(dir, t),
+            ),
+            t,
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        let dir = hit.local_to_world(local_dir);
+
+        match self.intersect(&hit.spawn_world_ray(dir)) {
+            Some((t, _)) => self.pdf_for(dir, t),
+            None => 0.,
+        }
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        let (t, uv) = self.intersect(ray)?;
+        Some(EmittedRadiance::new(self.radiance(uv), t))
+    }
+
+    fn sample_photon(&self, rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        let uv = Vec2::new(rng.gen(), rng.gen());
+        let point = self.corner + uv[0] * self.edge_u + uv[1] * self.edge_v;
+
+        Some(EmittedPhoton::new(
+            Ray::new(point, sample_cos_weighted_around(self.normal, rng)),
+            f64::consts::PI * self.area * self.radiance(uv),
+        ))
+    }
+}
+
+/// A one-sided area light spanning a triangle mesh, emitting the same radiance (its color)
+/// everywhere, such as a lamp of arbitrary shape.
+///
+/// Each triangle emits from the side toward which its vertices appear counterclockwise. The light
+/// is not part of the scene geometry, but does block its own light, so that only the triangles
+/// visible from a point light it. Every triangle is tested for intersections, so the light is best
+/// kept to modest triangle counts.
+pub struct MeshLight {
+    triangles: Vec<LightTriangle>,
+    /// Cumulative area of the triangles, normalized to end at 1.
+    cdf: Vec<f64>,
+    area: f64,
+    color: Vec3,
+}
+
+struct LightTriangle {
+    vertex: Vec3,
+    edge1: Vec3,
+    edge2: Vec3,
+    normal: Unit3,
+}
+
+impl LightTriangle {
+    /// Finds where `ray` strikes the triangle (from either side), returning the distance along
+    /// the ray.
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        let p = ray.dir.cross(&self.edge2);
+        let det = self.edge1.dot(&p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let inv_det = 1. / det;
+        let offset = ray.origin - self.vertex;
+
+        let u = offset.dot(&p) * inv_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+
+        let q = offset.cross(&self.edge1);
+        let v = ray.dir.dot(&q) * inv_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+
+        let t = self.edge2.dot(&q) * inv_det;
+        if t < EPSILON {
+            return None;
+        }
+
+        Some(t)
+    }
+
+    /// Maps a point in the unit square to a point uniformly distributed on the triangle.
+    fn point(&self, u: [f64; 2]) -> Vec3 {
+        let s = u[0].sqrt();
+        self.vertex + s * (1. - u[1]) * self.edge1 + s * u[1] * self.edge2
+    }
+}
+
+impl MeshLight {
+    /// Creates a light spanning the triangles of `indices`, each of which indexes three
+    /// `vertices`. Degenerate triangles are skipped; returns `None` if no triangle has any area.
+    ///
+    /// Panics if an index is out of bounds.
+    pub fn new(vertices: &[Vec3], indices: &[[usize; 3]], color: Vec3) -> Option<Self> {
+        let mut triangles = Vec::with_capacity(indices.len());
+        let mut cdf = Vec::with_capacity(indices.len());
+        let mut area = 0.;
+
+        for &[i0, i1, i2] in indices {
+            let edge1 = vertices[i1] - vertices[i0];
+            let edge2 = vertices[i2] - vertices[i0];
+            let (normal, double_area) = match Unit3::try_new_and_get(edge1.cross(&edge2), 0.) {
+                Some(normal) => normal,
+                None => continue,
+            };
+
+            triangles.push(LightTriangle {
+                vertex: vertices[i0],
+                edge1,
+                edge2,
+                normal,
+            });
+            area += double_area / 2.;
+            cdf.push(area);
+        }
+
+        if area <= 0. {
+            return None;
+        }
+
+        for value in &mut cdf {
+            *value /= area;
+        }
+
+        Some(Self {
+            triangles,
+            cdf,
+            area,
+            color,
+        })
+    }
+
+    /// See [`PointLight::with_intensity`].
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.color *= intensity;
+        self
+    }
+
+    /// Chooses a triangle with probability proportional to its area.
+    fn sample_triangle(&self, rng: &mut dyn RngCore) -> &LightTriangle {
+        let u: f64 = rng.gen();
+        let idx = self
+            .cdf
+            .partition_point(|&value| value <= u)
+            .min(self.cdf.len() - 1);
+
+        &self.triangles[idx]
+    }
+
+    /// Samples a point uniformly over the whole surface, returning it along with the normal there.
+    fn sample_point(&self, rng: &mut dyn RngCore) -> (Vec3, Unit3) {
+        let triangle = self.sample_triangle(rng);
+        (triangle.point([rng.gen(), rng.gen()]), triangle.normal)
+    }
+
+    /// Finds the triangle `ray` strikes first, returning the distance along the ray and the
+    /// triangle's normal.
+    fn intersect(&self, ray: &Ray) -> Option<(f64, Unit3)> {
+        self.triangles
+            .iter()
+            .filter_map(|triangle| Some((triangle.intersect(ray)?, triangle.normal)))
+            .min_by(|(t1, _), (t2, _)| t1.total_cmp(t2))
+    }
+
+    /// Returns the solid-angle density of sampling a direction that reaches the light at
+    /// distance `t` on a triangle with normal `normal`.
+    ///
+    /// Triangles are chosen in proportion to their area and points are then sampled uniformly
+    /// within them, so every point of the surface has the same area density `1 / area`.
+    fn pdf_for(&self, dir: Unit3, t: f64, normal: Unit3) -> f64 {
+        t.powi(2) / (-normal.dot(&dir) * self.area)
+    }
+}
+
+impl Light for MeshLight {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        let (point, normal) = self.sample_point(rng);
+
+        let (dir, t) = Unit3::try_new_and_get(point - hit.point, 0.)?;
+        if normal.dot(&dir) >= 0. {
+            return None;
+        }
+
+        // Points hidden behind other parts of the mesh are dark, as seen by `emitted`.
+        let (t_first, _) = self.intersect(&hit.spawn_world_ray(dir))?;
+        if t_first < t * (1. - 1e-6) - EPSILON {
+            return None;
+        }
+
+        Some(SampledLightRadiance::new(
+            SampledRadiance::new_real(
+                hit.world_to_local(dir),
+                self.color,
+                self.pdf_for(dir, t, normal),
+            ),
+            t,
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        let dir = hit.local_to_world(local_dir);
+
+        match self.intersect(&hit.spawn_world_ray(dir)) {
+            Some((t, normal)) if normal.dot(&dir) < 0. => self.pdf_for(dir, t, normal),
+            _ => 0.,
+        }
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        match self.intersect(ray)? {
+            (t, normal) if normal.dot(&ray.dir) < 0. => Some(EmittedRadiance::new(self.color, t)),
+            _ => None,
+        }
+    }
+
+    fn sample_photon(&self, rng: &mut dyn RngCore) -> Option<EmittedPhoton> {
+        let (point, normal) = self.sample_point(rng);
+
+        Some(EmittedPhoton::new(
+            Ray::new(point, sample_cos_weighted_around(normal, rng)),
+            f64::consts::PI * self.area * self.color,
+        ))
+    }
+
+    fn is_black(&self) -> bool {
+        self.color == Vec3::default()
+    }
+}
+
+/// A light infinitely far away, with its color as the radiance arriving from every direction in the
+/// hemisphere about `up`, such as an overcast sky.
+pub struct HemisphereLight {
+    basis: OrthoNormalBasis,
+    color: Vec3,
+}
+
+impl HemisphereLight {
+    pub fn new(up: Unit3, color: Vec3) -> Self {
+        Self {
+            basis: OrthoNormalBasis::from_w(up),
+            color,
+        }
+    }
+
+    /// See [`PointLight::with_intensity`].
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.color *= intensity;
+        self
+    }
+
+    fn world_pdf(&self, dir: Unit3) -> f64 {
+        dir.dot(&self.basis.w()).max(0.) * f64::consts::FRAC_1_PI
+    }
+}
+
+impl Light for HemisphereLight {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        let light_dir = CosWeightedHemisphere.sample(rng);
+        let dir = Unit3::new_unchecked(self.basis.trans_to_canonical(*light_dir));
+
+        Some(SampledLightRadiance::new_infinite(
+            SampledRadiance::new_real(
+                hit.world_to_local(dir),
+                self.color,
+                CosWeightedHemisphere.pdf(light_dir),
+            ),
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        self.world_pdf(hit.local_to_world(local_dir))
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        if ray.dir.dot(&self.basis.w()) > 0. {
+            Some(EmittedRadiance::new_infinite(self.color))
+        } else {
+            None
+        }
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
+    fn is_black(&self) -> bool {
+        self.color == Vec3::default()
+    }
+}
+
+/// A sky that blends between colors according to the height of the direction, sampled in
+/// proportion to its luminance.
+///
+/// By default, the color varies linearly from `bottom` straight down to `top` straight up. A
+/// separate horizon color splits the blend in two, for skies such as a sunset's that change most
+/// toward the horizon.
+pub struct GradientEnvironment {
+    bottom: Vec3,
+    top: Vec3,
+    horizon: Option<Vec3>,
+    bottom_luminance: f64,
+    top_luminance: f64,
+    horizon_luminance: f64,
+}
+
+impl GradientEnvironment {
+    pub fn new(bottom: Vec3, top: Vec3) -> Self {
+        Self {
+            bottom,
+            top,
+            horizon: None,
+            bottom_luminance: luminance(&bottom),
+            top_luminance: luminance(&top),
+            horizon_luminance: 0.,
+        }
+    }
+
+    /// Blends from `bottom` to `horizon` below the horizon and from `horizon` to `top` above it,
+    /// instead of directly from `bottom` to `top`.
+    pub fn with_horizon(mut self, horizon: Vec3) -> Self {
+        self.horizon = Some(horizon);
+        self.horizon_luminance = luminance(&horizon);
+        self
+    }
+
+    /// Scales every color of the sky by `intensity`.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.bottom *= intensity;
+        self.top *= intensity;
+        if let Some(horizon) = &mut self.horizon {
+            *horizon *= intensity;
+        }
+
+        self.bottom_luminance *= intensity;
+        self.top_luminance *= intensity;
+        self.horizon_luminance *= intensity;
+        self
+    }
+
+    fn color(&self, dir: Unit3) -> Vec3 {
+        match self.horizon {
+            None => self.bottom.lerp(&self.top, height_fraction(dir)),
+            Some(horizon) if dir[1] < 0. => self.bottom.lerp(&horizon, dir[1] + 1.),
+            Some(horizon) => horizon.lerp(&self.top, dir[1]),
+        }
+    }
+
+    fn luminance(&self, dir: Unit3) -> f64 {
+        let (l0, l1, t) = match self.horizon {
+            None => (
+                self.bottom_luminance,
+                self.top_luminance,
+                height_fraction(dir),
+            ),
+            Some(_) if dir[1] < 0. => (self.bottom_luminance, self.horizon_luminance, dir[1] + 1.),
+            Some(_) => (self.horizon_luminance, self.top_luminance, dir[1]),
+        };
+
+        l0 + t * (l1 - l0)
+    }
+
+    /// Returns the luminance integrated over the height of the direction, scaled so that a
+    /// uniform luminance of 1 gives 2.
+    fn total_luminance(&self) -> f64 {
+        match self.horizon {
+            None => self.bottom_luminance + self.top_luminance,
+            Some(_) => {
+                0.5 * (self.bottom_luminance + 2. * self.horizon_luminance + self.top_luminance)
+            }
+        }
+    }
+
+    fn world_pdf(&self, dir: Unit3) -> f64 {
+        let total = self.total_luminance();
+        if total <= 0. {
+            return 0.;
+        }
+
+        self.luminance(dir) / (f64::consts::TAU * total)
+    }
+
+    /// Chooses the height of a direction (its `y` coordinate) in proportion to the luminance.
+    fn sample_height(&self, u: f64) -> f64 {
+        let horizon_luminance = match self.horizon {
+            None => {
+                return 2. * sample_linear(u, self.bottom_luminance, self.top_luminance) - 1.;
+            }
+            Some(_) => self.horizon_luminance,
+        };
+
+        let lower = 0.5 * (self.bottom_luminance + horizon_luminance);
+        let split = u * self.total_luminance();
+
+        if split < lower {
+            sample_linear(split / lower, self.bottom_luminance, horizon_luminance) - 1.
+        } else {
+            let upper = 0.5 * (horizon_luminance + self.top_luminance);
+            sample_linear(
+                (split - lower) / upper,
+                horizon_luminance,
+                self.top_luminance,
+            )
+        }
+    }
+}
+
+/// Samples a point in `[0, 1]` with density proportional to the linear function running from `l0`
+/// to `l1`. If both are zero, there is no such density and 0 is returned.
+fn sample_linear(u: f64, l0: f64, l1: f64) -> f64 {
+    if l0 == 0. {
+        // The general formula below degenerates to `0 / 0` at `u = 0` here.
+        return if l1 == 0. { 0. } else { u.sqrt() };
+    }
+
+    // Invert the CDF, which is the root of a quadratic, rationalized to stay stable when the two
+    // ends are close.
+    u * (l0 + l1) / (l0 + (l0.powi(2) + (l1 - l0) * (l0 + l1) * u).sqrt())
+}
+
+impl Default for GradientEnvironment {
+    fn default() -> Self {
+        Self::new(Vec3::from_element(1.), Vec3::new(0.5, 0.7, 1.))
+    }
+}
+
+impl Light for GradientEnvironment {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        if self.total_luminance() <= 0. {
+            return None;
+        }
+
+        let y = self.sample_height(rng.gen()).clamp(-1., 1.);
+        let phi = rng.gen_range(0.0..f64::consts::TAU);
+        let radius = (1. - y.powi(2)).max(0.).sqrt();
+
+        let dir = Unit3::new_normalize(Vec3::new(radius * phi.cos(), y, radius * phi.sin()));
+
+        Some(SampledLightRadiance::new_infinite(
+            SampledRadiance::new_real(
+                hit.world_to_local(dir),
+                self.color(dir),
+                self.world_pdf(dir),
+            ),
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        self.world_pdf(hit.local_to_world(local_dir))
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        Some(EmittedRadiance::new_infinite(self.color(ray.dir)))
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
+    fn is_black(&self) -> bool {
+        self.bottom == Vec3::default()
+            && self.top == Vec3::default()
+            && self
+                .horizon
+                .is_none_or(|horizon| horizon == Vec3::default())
+    }
+}
+
+fn height_fraction(dir: Unit3) -> f64 {
+    0.5 * (dir[1] + 1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    use super::*;
+
+    #[test]
+    fn sample_linear_handles_black_start() {
+        assert_eq!(sample_linear(0., 0., 1.), 0.);
+        assert_eq!(sample_linear(0.25, 0., 1.), 0.5);
+        assert_eq!(sample_linear(1., 0., 1.), 1.);
+        assert_eq!(sample_linear(0., 0., 0.), 0.);
+        assert_eq!(sample_linear(0.5, 0., 0.), 0.);
+    }
+
+    #[test]
+    fn sample_linear_inverts_cdf() {
+        // The CDF of the density running linearly from `l0` to `l1`.
+        let cdf =
+            |x: f64, l0: f64, l1: f64| (l0 * x + (l1 - l0) * x.powi(2) / 2.) / ((l0 + l1) / 2.);
+
+        for &(l0, l1) in &[(1., 1.), (1., 3.), (3., 1.), (1., 0.), (0.5, 0.50001)] {
+            for i in 0..=10 {
+                let u = i as f64 / 10.;
+                let x = sample_linear(u, l0, l1);
+                assert!((0. ..=1.).contains(&x), "{} out of range", x);
+                assert!((cdf(x, l0, l1) - u).abs() < 1e-9, "cdf({}) != {}", x, u);
+            }
+        }
+    }
+
+    #[test]
+    fn black_nadir_samples_finite_directions() {
+        let sky = GradientEnvironment::new(Vec3::default(), Vec3::from_element(1.));
+        for i in 0..=10 {
+            let y = sky.sample_height(i as f64 / 10.);
+            assert!(y.is_finite() && (-1. ..=1.).contains(&y), "height {}", y);
+        }
+    }
+
+    #[test]
+    fn quad_light_emits_texture_at_hit() {
+        let light = QuadLight::new(
+            Vec3::new(-1., -1., 0.),
+            Vec3::new(2., 0., 0.),
+            Vec3::new(0., 4., 0.),
+            Arc::new(|uv: Vec2| Vec3::new(uv[0], uv[1], 0.)),
+        )
+        .unwrap()
+        .with_intensity(2.);
+
+        let toward = Ray::new(Vec3::new(0.5, 0., 3.), -Vec3::z_axis());
+        let emitted = light.emitted(&toward).unwrap();
+        assert!((emitted.color - Vec3::new(1.5, 0.5, 0.)).norm() < 1e-9);
+        assert!((emitted.dist.unwrap() - 3.).abs() < 1e-9);
+
+        // The back of the quad is dark, and rays beside it miss.
+        let behind = Ray::new(Vec3::new(0.5, 0., -3.), Vec3::z_axis());
+        assert!(light.emitted(&behind).is_none());
+        let beside = Ray::new(Vec3::new(1.5, 0., 3.), -Vec3::z_axis());
+        assert!(light.emitted(&beside).is_none());
+    }
+
+    #[test]
+    fn intensity_scales_brightness() {
+        let sphere = Sphere::new(Vec3::default(), 1.);
+        let ray = Ray::new(Vec3::new(0., 5., 0.), -Vec3::y_axis());
+        let hit = HitInfo::from_raw(&ray, &Geom::hit(&sphere, &ray, 0., f64::INFINITY).unwrap());
+        let mut rng = Pcg64::seed_from_u64(1);
+
+        let color = Vec3::new(0.2, 0.4, 0.8);
+        let lit = |light: &dyn Light, rng: &mut Pcg64| {
+            light
+                .sample_incident_at(&hit, rng)
+                .unwrap()
+                .radiance
+                .scaled_color()
+        };
+
+        let point = Vec3::new(0., 3., 0.);
+        let dim = lit(&PointLight::new(point, color).with_intensity(2.), &mut rng);
+        let bright = lit(&PointLight::new(point, color).with_intensity(6.), &mut rng);
+        assert!((bright - 3. * dim).norm() < 1e-9);
+
+        // Builders compound rather than replace one another.
+        let quad = |intensity| {
+            QuadLight::new(
+                Vec3::new(-1., 3., -1.),
+                Vec3::new(0., 0., 2.),
+                Vec3::new(2., 0., 0.),
+                Arc::new(move |_: Vec2| color),
+            )
+            .unwrap()
+            .with_intensity(intensity)
+        };
+        let down = Ray::new(Vec3::new(0., 5., 0.), -Vec3::y_axis());
+        let once = quad(2.).emitted(&down).unwrap().color;
+        let twice = quad(2.).with_intensity(2.).emitted(&down).unwrap().color;
+        assert!((once - 2. * color).norm() < 1e-9);
+        assert!((twice - 4. * color).norm() < 1e-9);
+
+        let sky = GradientEnvironment::new(color, 2. * color).with_horizon(color);
+        let up = Ray::new(Vec3::default(), Unit3::new_normalize(Vec3::new(1., 1., 0.)));
+        let base = sky.emitted(&up).unwrap().color;
+        let scaled = sky.with_intensity(3.).emitted(&up).unwrap().color;
+        assert!((scaled - 3. * base).norm() < 1e-9);
+
+        let sun = Unit3::new_normalize(Vec3::new(0., 1., 1.));
+        let preetham = || PreethamSky::new(sun, 3., Vec3::from_element(0.2));
+        for dir in [*sun, Vec3::new(1., 1., 0.), Vec3::new(0., -1., 0.)] {
+            let ray = Ray::new(Vec3::default(), Unit3::new_normalize(dir));
+            let base = preetham().emitted(&ray).unwrap().color;
+            let scaled = preetham().with_intensity(3.).emitted(&ray).unwrap().color;
+            assert!(
+                (scaled - 3. * base).norm() < 1e-9 * base.norm(),
+                "{:?}",
+                dir
+            );
+        }
+    }
 }
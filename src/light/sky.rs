@@ -0,0 +1,295 @@
+use std::array;
+use std::f64;
+
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, UnitSphere};
+
+use crate::geom::HitInfo;
+use crate::math::{OrthoNormalBasis, Ray, Unit3, Vec3};
+use crate::shading::SampledRadiance;
+use crate::spectral::xyz_to_srgb;
+
+use super::{sample_uniform_cone, uniform_cone_pdf, EmittedRadiance, Light, SampledLightRadiance};
+
+/// Scale from the model's luminances (in kcd/m²) to radiance, chosen so that a clear sky at noon
+/// is of the order of 1.
+const LUMINANCE_SCALE: f64 = 0.1;
+
+/// Luminance of the sun outside the atmosphere, in kcd/m².
+const SUN_LUMINANCE: f64 = 2e6;
+
+/// Angular radius of the sun as seen from the earth, in degrees.
+const SUN_ANGULAR_RADIUS: f64 = 0.2665;
+
+/// Wavelengths (in micrometers) standing in for the red, green and blue channels when attenuating
+/// sunlight.
+const CHANNEL_WAVELENGTHS: [f64; 3] = [0.65, 0.55, 0.45];
+
+/// Resolution of the grid over which the sky is integrated to light the ground.
+const GROUND_STEPS: (usize, usize) = (32, 128);
+
+/// A clear daytime sky lit by the sun, following the analytic model of Preetham et al. ("A
+/// Practical Analytic Model for Daylight", 1999), with `+y` up.
+///
+/// The sky's luminance and chromaticity come from Perez distributions fitted to the sun's
+/// position and the atmosphere's `turbidity` (2 for a very clear sky, up to about 10 for a hazy
+/// one). The sun itself is a disc attenuated by Rayleigh and aerosol scattering along its path
+/// through the atmosphere, turning red as it approaches the horizon. Directions below the horizon
+/// see a diffuse ground of color `ground_albedo` lit by the sky and sun.
+///
+/// Directions are importance sampled by choosing between the sun's disc and the whole sphere.
+pub struct PreethamSky {
+    sun_dir: Unit3,
+    sun_basis: OrthoNormalBasis,
+    cos_sun_radius: f64,
+    sun_radiance: Vec3,
+    /// Perez coefficients for the luminance and the two chromaticity coordinates.
+    perez: [[f64; 5]; 3],
+    /// Luminance and chromaticity at the zenith, divided by the Perez function there.
+    zenith: [f64; 3],
+    ground_radiance: Vec3,
+    sun_probability: f64,
+}
+
+impl PreethamSky {
+    /// Creates a sky with the sun in direction `sun_dir`. `turbidity` is clamped to the range
+    /// `[2, 10]` over which the model was fitted, and a sun below the horizon lights the sky as
+    /// though it were on the horizon.
+    pub fn new(sun_dir: Unit3, turbidity: f64, ground_albedo: Vec3) -> Self {
+        let turbidity = turbidity.clamp(2., 10.);
+        let sun_theta = sun_dir[1].clamp(0., 1.).acos();
+
+        let perez = perez_coefficients(turbidity);
+        let zenith_values = zenith_values(turbidity, sun_theta);
+
+        let zenith =
+            array::from_fn(|i| zenith_values[i] / perez_fn(&perez[i], 1., sun_theta.cos()));
+
+        let sun_radiance = if sun_dir[1] > 0. {
+            sun_transmittance(turbidity, sun_theta) * SUN_LUMINANCE * LUMINANCE_SCALE
+        } else {
+            Vec3::default()
+        };
+
+        let mut sky = Self {
+            sun_dir,
+            sun_basis: OrthoNormalBasis::from_w(sun_dir),
+            cos_sun_radius: SUN_ANGULAR_RADIUS.to_radians().cos(),
+            sun_radiance,
+            perez,
+            zenith,
+            ground_radiance: Vec3::default(),
+            sun_probability: if sun_radiance == Vec3::default() {
+                0.
+            } else {
+                0.5
+            },
+        };
+
+        sky.ground_radiance =
+            ground_albedo.component_mul(&sky.ground_irradiance()) / f64::consts::PI;
+        sky
+    }
+
+    /// Sets the angular radius of the sun's disc (in degrees), keeping the light it casts
+    /// unchanged. Larger suns cast softer shadows and are easier to see in small images.
+    pub fn with_sun_radius(mut self, radius: f64) -> Self {
+        let cos_radius = radius.to_radians().cos();
+        self.sun_radiance *= (1. - self.cos_sun_radius) / (1. - cos_radius);
+        self.cos_sun_radius = cos_radius;
+        self
+    }
+
+    /// Scales the radiance of the sky, the sun and the ground by `intensity`.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        // Sky radiance is proportional to the zenith luminance.
+        self.zenith[0] *= intensity;
+        self.sun_radiance *= intensity;
+        self.ground_radiance *= intensity;
+        self
+    }
+
+    fn color(&self, dir: Unit3) -> Vec3 {
+        if dir[1] < 0. {
+            return self.ground_radiance;
+        }
+
+        let sky = self.sky_radiance(dir);
+        if self.in_sun(dir) {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    /// Returns the radiance of the sky (without the sun) in the upper hemisphere direction `dir`.
+    fn sky_radiance(&self, dir: Unit3) -> Vec3 {
+        let gamma = dir.dot(&self.sun_dir).clamp(-1., 1.).acos();
+        let [lum, x, y]: [f64; 3] =
+            array::from_fn(|i| self.zenith[i] * perez_fn(&self.perez[i], dir[1], gamma));
+        if y <= 0. {
+            return Vec3::default();
+        }
+
+        let xyz = Vec3::new(x / y * lum, lum, (1. - x - y) / y * lum);
+        xyz_to_srgb(xyz).map(|c| c.max(0.)) * LUMINANCE_SCALE
+    }
+
+    fn in_sun(&self, dir: Unit3) -> bool {
+        self.sun_probability > 0. && dir.dot(&self.sun_dir) >= self.cos_sun_radius
+    }
+
+    /// Computes the irradiance falling on an upward-facing surface, integrating the sky over a
+    /// grid of directions uniform in `cos(theta)`.
+    fn ground_irradiance(&self) -> Vec3 {
+        let (theta_steps, phi_steps) = GROUND_STEPS;
+        let d_cos = 1. / theta_steps as f64;
+        let d_phi = f64::consts::TAU / phi_steps as f64;
+
+        let mut irradiance = Vec3::default();
+        for i in 0..theta_steps {
+            let cos_theta = (i as f64 + 0.5) * d_cos;
+            let sin_theta = (1. - cos_theta.powi(2)).sqrt();
+
+            for j in 0..phi_steps {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let dir = Unit3::new_unchecked(Vec3::new(
+                    sin_theta * phi.cos(),
+                    cos_theta,
+                    sin_theta * phi.sin(),
+                ));
+                irradiance += self.sky_radiance(dir) * cos_theta * d_cos * d_phi;
+            }
+        }
+
+        irradiance
+            + self.sun_radiance * self.sun_dir[1].max(0.) / uniform_cone_pdf(self.cos_sun_radius)
+    }
+
+    fn world_pdf(&self, dir: Unit3) -> f64 {
+        let sphere_pdf = (1. - self.sun_probability) / (2. * f64::consts::TAU);
+
+        if self.in_sun(dir) {
+            sphere_pdf + self.sun_probability * uniform_cone_pdf(self.cos_sun_radius)
+        } else {
+            sphere_pdf
+        }
+    }
+}
+
+impl Light for PreethamSky {
+    fn sample_incident_at(
+        &self,
+        hit: &HitInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledLightRadiance> {
+        let dir = if rng.gen::<f64>() < self.sun_probability {
+            let local = sample_uniform_cone(self.cos_sun_radius, rng);
+            Unit3::new_normalize(self.sun_basis.trans_to_canonical(*local))
+        } else {
+            let [x, y, z]: [f64; 3] = UnitSphere.sample(rng);
+            Unit3::new_normalize(Vec3::new(x, y, z))
+        };
+
+        Some(SampledLightRadiance::new_infinite(
+            SampledRadiance::new_real(
+                hit.world_to_local(dir),
+                self.color(dir),
+                self.world_pdf(dir),
+            ),
+        ))
+    }
+
+    fn pdf(&self, hit: &HitInfo, local_dir: Unit3) -> f64 {
+        self.world_pdf(hit.local_to_world(local_dir))
+    }
+
+    fn emitted(&self, ray: &Ray) -> Option<EmittedRadiance> {
+        Some(EmittedRadiance::new_infinite(self.color(ray.dir)))
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+/// Returns the Perez coefficients `A` through `E` for the luminance and the `x` and `y`
+/// chromaticity coordinates.
+fn perez_coefficients(turbidity: f64) -> [[f64; 5]; 3] {
+    let t = turbidity;
+    [
+        [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ],
+        [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ],
+        [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ],
+    ]
+}
+
+/// Evaluates the Perez distribution for a direction with zenith cosine `cos_theta`, at an angle of
+/// `gamma` from the sun.
+fn perez_fn(coeffs: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coeffs;
+    // Keep the exponent finite at the horizon.
+    let cos_theta = cos_theta.max(1e-3);
+
+    (1. + a * (b / cos_theta).exp()) * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Returns the luminance (in kcd/m²) and chromaticity of the zenith for a sun at zenith angle
+/// `sun_theta`.
+fn zenith_values(turbidity: f64, sun_theta: f64) -> [f64; 3] {
+    let t = turbidity;
+    let chi = (4. / 9. - t / 120.) * (f64::consts::PI - 2. * sun_theta);
+    let lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+    let thetas = Vec3::new(sun_theta.powi(3), sun_theta.powi(2), sun_theta);
+    let chromaticity = |rows: [[f64; 4]; 3]| {
+        let row = |r: [f64; 4]| thetas.dot(&Vec3::new(r[0], r[1], r[2])) + r[3];
+        t.powi(2) * row(rows[0]) + t * row(rows[1]) + row(rows[2])
+    };
+
+    let x = chromaticity([
+        [0.00166, -0.00375, 0.00209, 0.],
+        [-0.02903, 0.06377, -0.03202, 0.00394],
+        [0.11693, -0.21196, 0.06052, 0.25886],
+    ]);
+    let y = chromaticity([
+        [0.00275, -0.00610, 0.00317, 0.],
+        [-0.04214, 0.08970, -0.04153, 0.00516],
+        [0.15346, -0.26756, 0.06670, 0.26688],
+    ]);
+
+    [lum.max(0.), x, y]
+}
+
+/// Returns the fraction of sunlight in each channel that makes it through the atmosphere from a
+/// sun at zenith angle `sun_theta`, accounting for Rayleigh and aerosol scattering.
+fn sun_transmittance(turbidity: f64, sun_theta: f64) -> Vec3 {
+    // Relative optical air mass, as approximated by Kasten and Young.
+    let degrees = sun_theta.to_degrees();
+    let air_mass = 1. / (sun_theta.cos() + 0.50572 * (96.07995 - degrees).powf(-1.6364));
+
+    let beta = 0.04608 * turbidity - 0.04586;
+    Vec3::from(CHANNEL_WAVELENGTHS.map(|lambda| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    }))
+}
@@ -1,120 +1,832 @@
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::time::Instant;
 
-use light::PointLight;
 use structopt::StructOpt;
 
-use geom::Sphere;
-use material::{Dielectric, Lambertian, Mirror};
-use math::Vec3;
-use render::{Camera, CameraOptions, RenderOptions};
-use scene::{Scene, SceneBuilder};
-
-mod distr;
-mod geom;
-mod img;
-mod light;
-mod material;
-mod math;
-mod render;
-mod scene;
-mod shading;
+use rtow::animation::{self, AnimationOptions, Turntable};
+use rtow::config::{Config, Sky};
+use rtow::geom::Sphere;
+use rtow::img::{self, Exposure, ExposureMode, Image, OutputColorSpace, RgbaOptions};
+use rtow::light::{
+    AmbientLight, GradientEnvironment, HemisphereLight, PointLight, PreethamSky, QuadLight,
+    SpotLight,
+};
+use rtow::material::{Dielectric, Lambertian, Material, Mirror, ShadowCatcher};
+use rtow::math::{Unit3, Vec2, Vec3};
+use rtow::montage::{self, MontageLayout};
+use rtow::pointcloud;
+use rtow::render::{
+    self, AdaptiveOptions, Camera, CameraOptions, ErrorMetric, PhotonOptions, PixelSampling,
+    RenderOptions, Renderer,
+};
+use rtow::scene::{Scene, SceneBuilder};
 
 #[derive(StructOpt)]
 struct CliArgs {
+    /// Read settings from this file. Options given on the command line take precedence.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+
     /// Width of rendered image, in pixels
     #[structopt(long, short)]
-    pub width: u32,
+    pub width: Option<u32>,
 
     /// Height of rendered image, in pixels
     #[structopt(long, short)]
-    pub height: u32,
+    pub height: Option<u32>,
+
+    /// Vertical field of view, in degrees [default: 50]
+    #[structopt(long)]
+    pub vfov: Option<f64>,
+
+    /// Width of the camera aperture. Specify 0 for a pinhole camera. [default: 0]
+    #[structopt(long)]
+    pub aperture: Option<f64>,
+
+    /// Distance beyond which geometry is hidden from the camera [default: inf]
+    #[structopt(long)]
+    pub far: Option<f64>,
+
+    /// Maximum bounce depth [default: 10]
+    #[structopt(long)]
+    pub max_depth: Option<u32>,
+
+    /// Number of samples to gather per pixel [default: 100]
+    #[structopt(long = "spp")]
+    pub samples_per_pixel: Option<u32>,
+
+    /// Seed for reproducible renders. If omitted, every run produces different noise.
+    #[structopt(long)]
+    pub seed: Option<u64>,
+
+    /// Spread each pixel's samples over a jittered grid, both within the pixel and on the lens.
+    /// Works best with a square number of samples.
+    #[structopt(long)]
+    pub stratify: bool,
+
+    /// Place each pixel's samples along a scrambled Halton sequence, both within the pixel and on
+    /// the lens, which covers them evenly for any number of samples
+    #[structopt(long, conflicts_with = "stratify")]
+    pub halton: bool,
+
+    /// Place each pixel's samples, and the light and BSDF samples where they first hit a surface,
+    /// along correlated multi-jittered patterns, which stratify every pair of dimensions for any
+    /// number of samples
+    #[structopt(long, conflicts_with_all = &["stratify", "halton"])]
+    pub multi_jitter: bool,
+
+    /// Clamp every channel of each sample's indirect lighting to this value, suppressing
+    /// fireflies. Direct lighting is left untouched
+    #[structopt(long)]
+    pub clamp_indirect: Option<f64>,
+
+    /// Ignore light from the sky after this many bounces, trading a little energy for less noise
+    /// in mostly enclosed scenes. The sky seen directly is always kept
+    #[structopt(long)]
+    pub background_bounces: Option<u32>,
+
+    /// Widen glossy and specular surfaces to at least this roughness once a path has scattered
+    /// off a non-specular one, trading noisy caustics for slightly blurred ones
+    #[structopt(long)]
+    pub regularize: Option<f64>,
+
+    /// Treat points sampled on area lights closer than this as though they were this far away,
+    /// removing fireflies where surfaces touch lights at the cost of slightly darkening them
+    #[structopt(long)]
+    pub min_light_distance: Option<f64>,
+
+    /// Bend shadow rays toward point and spot lights through glass in the way, rendering the
+    /// caustics it focuses onto diffuse surfaces
+    #[structopt(long)]
+    pub refracted_connections: bool,
+
+    /// Sky lighting the scene: "hemisphere", "gradient", "sunset" or "none" [default: none]
+    #[structopt(long)]
+    pub sky: Option<Sky>,
+
+    /// Maximum number of primitives in each BVH leaf [default: 4]
+    #[structopt(long)]
+    pub max_leaf_size: Option<usize>,
+
+    /// Trace a single sample through the pixel at "x,y" and print its path instead of rendering
+    #[structopt(long)]
+    pub debug_pixel: Option<PixelCoord>,
+
+    /// Output filename [default: render.png]
+    #[structopt(short)]
+    pub output_filename: Option<PathBuf>,
+
+    /// Instead of rendering, write a false-color map of the average number of bounces taken by
+    /// each pixel's paths
+    #[structopt(long)]
+    pub debug_bounces: bool,
+
+    /// Instead of rendering, write a heatmap of the number of BVH nodes tested by each pixel's
+    /// camera rays
+    #[structopt(long)]
+    pub debug_bvh: bool,
+
+    /// Instead of rendering, write the albedo of the first surface seen by each pixel, as used to
+    /// guide denoisers
+    #[structopt(long)]
+    pub albedo: bool,
+
+    /// Transfer function for the output: "srgb", "linear" or "gamma:<value>" [default: srgb]
+    #[structopt(long)]
+    pub color_space: Option<OutputColorSpace>,
+
+    /// Luminance mapped to white by tone mapping, instead of that of the brightest pixel. Fixes
+    /// the exposure across animation frames and separate renders
+    #[structopt(long)]
+    pub white_point: Option<f64>,
+
+    /// Expose the image so that its log-average luminance becomes this key (0.18 for a
+    /// middle-gray scene), rather than leaving its luminances as they are
+    #[structopt(long, conflicts_with = "white-point")]
+    pub exposure_key: Option<f64>,
+
+    /// Highlight pixels that are clipped after tone mapping in the output
+    #[structopt(long)]
+    pub clip_overlay: bool,
+
+    /// Periodically write the in-progress render to this file
+    #[structopt(long)]
+    pub preview: Option<PathBuf>,
+
+    /// Number of passes between preview updates
+    #[structopt(long, default_value = "16")]
+    pub preview_interval: u32,
+
+    /// Sample adaptively, stopping in each pixel once its relative error drops below this value.
+    /// The sample count then serves as a per-pixel maximum.
+    #[structopt(long)]
+    pub adaptive_threshold: Option<f64>,
+
+    /// Judge convergence over blocks of neighboring pixels rather than pixel by pixel, so that
+    /// pixels on noisy edges and thin features keep sampling along with their neighbors
+    #[structopt(long, requires = "adaptive-threshold")]
+    pub adaptive_blocks: bool,
+
+    /// Write a heatmap of the number of samples taken in each pixel to this file (requires
+    /// --adaptive-threshold)
+    #[structopt(long, requires = "adaptive-threshold")]
+    pub heatmap: Option<PathBuf>,
+
+    /// Instead of a single view, render front, side, top and perspective views tiled in a 2×2
+    /// grid, each at half the image size
+    #[structopt(long)]
+    pub montage: bool,
+
+    /// Render a turntable animation with this many frames instead of a single image
+    #[structopt(long)]
+    pub turntable: Option<u32>,
+
+    /// Directory to which animation frames are written
+    #[structopt(long, default_value = ".")]
+    pub frame_dir: PathBuf,
+
+    /// Use the exposure of the first animation frame for all frames
+    #[structopt(long)]
+    pub lock_exposure: bool,
 
-    /// Vertical field of view, in degrees
-    #[structopt(long, default_value = "50")]
-    pub vfov: f64,
+    /// Also write the image split by light and by direct/indirect diffuse/specular lighting into
+    /// this directory, one file per layer. The layers share the exposure of the full image, so
+    /// with a linear color space they sum to it.
+    #[structopt(long)]
+    pub layer_dir: Option<PathBuf>,
 
-    /// Width of the camera aperture. Specify 0 for a pinhole camera.
-    #[structopt(long, default_value = "0")]
-    pub aperture: f64,
+    /// Make the ground a shadow catcher and write an RGBA image, transparent except for the
+    /// objects and the shadows they cast on the ground, for compositing over a photograph
+    #[structopt(long)]
+    pub shadow_catcher: bool,
 
-    /// Maximum bounce depth
-    #[structopt(long, default_value = "10")]
-    pub max_depth: u32,
+    /// Replace the key light above the camera with a spot light projecting a window pattern
+    #[structopt(long)]
+    pub gobo: bool,
 
-    /// Number of samples to gather per pixel
-    #[structopt(long = "spp", default_value = "100")]
-    pub samples_per_pixel: u32,
+    /// Add a screen behind the spheres displaying a color-bar test pattern, lighting the scene
+    #[structopt(long)]
+    pub screen: bool,
 
-    /// Output filename
-    #[structopt(short, default_value = "render.png")]
-    pub output_filename: PathBuf,
+    /// Add a constant gray ambient term of this radiance to the lighting, for previews
+    #[structopt(long)]
+    pub ambient: Option<f64>,
+
+    /// Rendering algorithm: "path" (path tracing) or "photon" (path tracing with caustics from
+    /// progressive photon mapping)
+    #[structopt(long, default_value = "path")]
+    pub integrator: Integrator,
+
+    /// Number of photons emitted per pass by the photon integrator [default: 100000]
+    #[structopt(long)]
+    pub photons: Option<u32>,
+
+    /// Initial photon gather radius of the photon integrator [default: 0.02]
+    #[structopt(long)]
+    pub photon_radius: Option<f64>,
+
+    /// Add the points of this PLY file to the scene
+    #[structopt(long)]
+    pub point_cloud: Option<PathBuf>,
+
+    /// Radius of the spheres representing point cloud points
+    #[structopt(long, default_value = "0.005")]
+    pub point_radius: f64,
+}
+
+/// Settings gathered from the command line, the config file and defaults, in that order of
+/// precedence.
+struct Settings {
+    width: u32,
+    height: u32,
+
+    vfov: f64,
+    aperture: f64,
+    far: f64,
+    origin: Vec3,
+    look_at: Vec3,
+    vup: Vec3,
+    focus_target: Option<Vec3>,
+
+    max_depth: u32,
+    samples_per_pixel: u32,
+    seed: Option<u64>,
+    indirect_clamp: Option<f64>,
+    max_background_bounces: Option<u32>,
+    path_regularization: Option<f64>,
+    min_light_distance: Option<f64>,
+    refracted_connections: bool,
+    sky: Sky,
+    sky_nadir: Vec3,
+    sky_horizon: Option<Vec3>,
+    sky_zenith: Vec3,
+    max_leaf_size: usize,
+
+    output_filename: PathBuf,
+    color_space: OutputColorSpace,
+    white_point: Option<f64>,
+    exposure_mode: ExposureMode,
+}
+
+impl Settings {
+    fn resolve(args: &CliArgs, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let sky = match (args.sky, &config.render.sky) {
+            (Some(sky), _) => sky,
+            (None, Some(sky)) => *sky,
+            (None, None) => Sky::None,
+        };
+
+        let sky_colors = [
+            config.render.sky_nadir,
+            config.render.sky_horizon,
+            config.render.sky_zenith,
+        ];
+        if sky != Sky::Gradient && sky_colors.iter().any(Option::is_some) {
+            return Err("sky colors can only be set for the gradient sky".into());
+        }
+
+        let color_space = match (args.color_space, &config.image.color_space) {
+            (Some(color_space), _) => color_space,
+            (None, Some(color_space)) => *color_space,
+            (None, None) => OutputColorSpace::default(),
+        };
+
+        let white_point = args.white_point.or(config.image.white_point);
+        if let Some(white_point) = white_point {
+            if white_point.is_nan() || white_point <= 0. {
+                return Err(format!("white point must be positive (got {})", white_point).into());
+            }
+        }
+
+        let exposure_key = args.exposure_key.or(config.image.exposure_key);
+        let exposure_mode = match exposure_key {
+            Some(key) if !(key.is_finite() && key > 0.) => {
+                return Err(format!("exposure key must be positive (got {})", key).into());
+            }
+            Some(_) if white_point.is_some() => {
+                return Err("the white point and exposure key cannot both be set".into());
+            }
+            Some(key) => ExposureMode::KeyValue { key },
+            None => ExposureMode::MaxLuminance,
+        };
+
+        Ok(Self {
+            width: args
+                .width
+                .or(config.image.width)
+                .ok_or("image width must be specified")?,
+            height: args
+                .height
+                .or(config.image.height)
+                .ok_or("image height must be specified")?,
+
+            vfov: args.vfov.or(config.camera.vfov).unwrap_or(50.),
+            aperture: args.aperture.or(config.camera.aperture).unwrap_or(0.),
+            far: args.far.or(config.camera.far).unwrap_or(f64::INFINITY),
+            origin: config
+                .camera
+                .origin
+                .unwrap_or_else(|| Vec3::new(0., 0., 0.5)),
+            look_at: config
+                .camera
+                .look_at
+                .unwrap_or_else(|| Vec3::new(0., 0., -0.5)),
+            vup: config.camera.vup.unwrap_or_else(|| Vec3::new(0., 1., 0.)),
+            focus_target: config.camera.focus_target,
+
+            max_depth: args.max_depth.or(config.render.max_depth).unwrap_or(10),
+            samples_per_pixel: args
+                .samples_per_pixel
+                .or(config.render.samples_per_pixel)
+                .unwrap_or(100),
+            seed: args.seed.or(config.render.seed),
+            indirect_clamp: args.clamp_indirect.or(config.render.clamp_indirect),
+            max_background_bounces: args.background_bounces.or(config.render.background_bounces),
+            path_regularization: args.regularize.or(config.render.regularize),
+            min_light_distance: args.min_light_distance.or(config.render.min_light_distance),
+            refracted_connections: args.refracted_connections
+                || config.render.refracted_connections.unwrap_or(false),
+            sky,
+            sky_nadir: config
+                .render
+                .sky_nadir
+                .unwrap_or_else(|| Vec3::from_element(1.)),
+            sky_horizon: config.render.sky_horizon,
+            sky_zenith: config
+                .render
+                .sky_zenith
+                .unwrap_or_else(|| Vec3::new(0.5, 0.7, 1.)),
+            max_leaf_size: args
+                .max_leaf_size
+                .or(config.render.max_leaf_size)
+                .unwrap_or(4),
+
+            output_filename: args
+                .output_filename
+                .clone()
+                .or_else(|| config.image.output.clone())
+                .unwrap_or_else(|| "render.png".into()),
+            color_space,
+            white_point,
+            exposure_mode,
+        })
+    }
+
+    /// Returns the exposure with which to tone map an image made up of `pixels`.
+    fn exposure(&self, pixels: &[Vec3]) -> Exposure {
+        match self.white_point {
+            Some(white_point) => Exposure::with_white_point(white_point),
+            None => self.exposure_mode.exposure(pixels),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Integrator {
+    Path,
+    Photon,
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Integrator::Path),
+            "photon" => Ok(Integrator::Photon),
+            _ => Err(format!("unknown integrator '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PixelCoord {
+    x: u32,
+    y: u32,
+}
+
+impl FromStr for PixelCoord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected 'x,y', got '{}'", s))?;
+
+        let parse = |coord: &str| {
+            coord
+                .trim()
+                .parse()
+                .map_err(|err| format!("invalid coordinate '{}': {}", coord, err))
+        };
+
+        Ok(Self {
+            x: parse(x)?,
+            y: parse(y)?,
+        })
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = CliArgs::from_args();
 
-    let scene = build_scene();
+    let config = match &args.config {
+        Some(filename) => Config::parse(&fs::read_to_string(filename)?)
+            .map_err(|err| format!("{}: {}", filename.display(), err))?,
+        None => Config::default(),
+    };
+
+    let settings = Settings::resolve(&args, &config)?;
+
+    let points = match &args.point_cloud {
+        Some(filename) => pointcloud::load_ply(filename)
+            .map_err(|err| format!("{}: {}", filename.display(), err))?,
+        None => Vec::new(),
+    };
+
+    let scene = build_scene(
+        &settings,
+        &points,
+        args.point_radius,
+        args.ambient,
+        args.shadow_catcher,
+        args.gobo,
+        args.screen,
+    );
+    for warning in scene.validate() {
+        eprintln!("Warning: {}", warning);
+    }
 
     let camera_opts = CameraOptions {
-        pixel_width: args.width,
-        pixel_height: args.height,
+        pixel_width: settings.width,
+        pixel_height: settings.height,
 
-        vert_fov: args.vfov,
-        aperture: args.aperture,
+        vert_fov: settings.vfov,
+        aperture: settings.aperture,
 
-        origin: Vec3::new(0., 0., 0.5),
-        look_at: Vec3::new(0., 0., -0.5),
-        vup: Vec3::new(0., 1., 0.),
+        origin: settings.origin,
+        look_at: settings.look_at,
+        vup: settings.vup,
+
+        focus_target: settings.focus_target,
+
+        far: settings.far,
     };
 
-    let camera = Camera::new(&camera_opts);
+    let camera = Camera::new(&camera_opts)?;
 
     let opts = RenderOptions {
-        samples_per_pixel: args.samples_per_pixel,
-        max_depth: args.max_depth,
+        samples_per_pixel: settings.samples_per_pixel,
+        max_depth: settings.max_depth,
+        seed: settings.seed,
+        sampling: if args.multi_jitter {
+            PixelSampling::MultiJittered
+        } else if args.halton {
+            PixelSampling::Halton
+        } else if args.stratify {
+            PixelSampling::Stratified
+        } else {
+            PixelSampling::Independent
+        },
+        indirect_clamp: settings.indirect_clamp,
+        max_background_bounces: settings.max_background_bounces,
+        path_regularization: settings.path_regularization,
+        min_light_distance: settings.min_light_distance,
+        refracted_connections: settings.refracted_connections,
     };
+    opts.validate()?;
+
+    if let Some(pixel) = args.debug_pixel {
+        if pixel.x >= camera.pixel_width() || pixel.y >= camera.pixel_height() {
+            return Err(format!("pixel ({}, {}) lies outside the image", pixel.x, pixel.y).into());
+        }
+
+        let mut rng = rand::thread_rng();
+        let ray = camera.cast_ray(pixel.x, pixel.y, &mut rng);
+        let record = render::trace_ray_debug(&scene, ray, camera.far(), &mut rng, opts.max_depth);
+
+        println!("{}", record);
+        return Ok(());
+    }
+
+    if args.debug_bounces {
+        let counts = render::render_bounce_counts(&scene, &camera, &opts);
+        let raw_pixels = img::heatmap(
+            &counts,
+            0.,
+            opts.max_depth as f64,
+            camera.pixel_width(),
+            camera.pixel_height(),
+        );
+
+        return write_png_file(
+            &settings.output_filename,
+            &raw_pixels,
+            camera.pixel_width(),
+            camera.pixel_height(),
+        );
+    }
+
+    if args.debug_bvh {
+        let counts = render::render_bvh_node_tests(&scene, &camera, &opts);
+        let min = counts.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = counts.iter().copied().fold(0., f64::max);
+        let raw_pixels = img::heatmap(
+            &counts,
+            min,
+            max,
+            camera.pixel_width(),
+            camera.pixel_height(),
+        );
+
+        return write_png_file(
+            &settings.output_filename,
+            &raw_pixels,
+            camera.pixel_width(),
+            camera.pixel_height(),
+        );
+    }
+
+    if args.albedo {
+        let albedo = render::render_albedo(&scene, &camera, &opts);
+
+        return write_png_file(
+            &settings.output_filename,
+            &img::unmapped_pixels_to_raw(&albedo, settings.color_space),
+            camera.pixel_width(),
+            camera.pixel_height(),
+        );
+    }
+
+    if args.integrator == Integrator::Photon
+        && (args.preview.is_some()
+            || args.adaptive_threshold.is_some()
+            || args.turntable.is_some()
+            || args.montage
+            || args.layer_dir.is_some())
+    {
+        return Err(
+            "the photon integrator does not support previews, adaptive sampling, animation, \
+             montages or layers"
+                .into(),
+        );
+    }
+
+    if settings.exposure_mode != ExposureMode::MaxLuminance
+        && (args.shadow_catcher || args.turntable.is_some())
+    {
+        return Err(
+            "shadow catcher renders and animation do not support key-value exposure".into(),
+        );
+    }
+
+    if args.integrator == Integrator::Photon && opts.path_regularization.is_some() {
+        return Err(
+            "the photon integrator gathers caustics from photons and cannot regularize paths"
+                .into(),
+        );
+    }
+
+    if args.shadow_catcher
+        && (args.integrator == Integrator::Photon
+            || args.preview.is_some()
+            || args.adaptive_threshold.is_some()
+            || args.turntable.is_some()
+            || args.montage
+            || args.layer_dir.is_some())
+    {
+        return Err(
+            "shadow catcher renders do not support the photon integrator, previews, adaptive \
+             sampling, animation, montages or layers"
+                .into(),
+        );
+    }
+
+    if args.montage {
+        println!(
+            "Rendering montage {} at {}×{}, {}spp, depth {}",
+            settings.output_filename.display(),
+            settings.width,
+            settings.height,
+            settings.samples_per_pixel,
+            settings.max_depth
+        );
+
+        let layout = MontageLayout {
+            columns: 2,
+            rows: 2,
+            thumbnail_width: settings.width / 2,
+            thumbnail_height: settings.height / 2,
+        };
+
+        let start_time = Instant::now();
+        let image = montage::render_montage(
+            &scene,
+            &montage::standard_views(&camera_opts),
+            &layout,
+            &opts,
+        )?;
+        println!("Rendered in {}s", start_time.elapsed().as_secs_f64());
+
+        let raw_pixels = img::exposed_pixels_to_raw(
+            image.pixels(),
+            settings.exposure(image.pixels()),
+            settings.color_space,
+            args.clip_overlay,
+        );
+
+        return write_png_file(
+            &settings.output_filename,
+            &raw_pixels,
+            image.width(),
+            image.height(),
+        );
+    }
+
+    if let Some(frame_count) = args.turntable {
+        println!(
+            "Rendering {} turntable frames to {}",
+            frame_count,
+            args.frame_dir.display()
+        );
+
+        // The ground sphere dominates the scene bounds, so orbit the point of interest instead.
+        let turntable = Turntable::new(camera_opts, camera_opts.look_at);
+        let anim_opts = AnimationOptions {
+            frame_count,
+            lock_exposure: args.lock_exposure,
+            white_point: settings.white_point,
+            color_space: settings.color_space,
+        };
+
+        let start_time = Instant::now();
+        animation::render_animation(&turntable, &scene, &opts, &anim_opts, &args.frame_dir)?;
+        println!("Rendered in {}s", start_time.elapsed().as_secs_f64());
+
+        return Ok(());
+    }
 
     println!(
         "Rendering {} at {}×{}, {}spp, depth {}",
-        args.output_filename.display(),
-        args.width,
-        args.height,
-        args.samples_per_pixel,
-        args.max_depth
+        settings.output_filename.display(),
+        settings.width,
+        settings.height,
+        settings.samples_per_pixel,
+        settings.max_depth
     );
 
     let start_time = Instant::now();
 
-    let mut pixels = vec![Vec3::default(); (camera.pixel_width() * camera.pixel_height()) as usize];
-    render::render_to(&mut pixels, &scene, &camera, &opts);
+    let renderer = Renderer::new(scene, camera, opts);
+
+    if args.shadow_catcher {
+        let (image, alpha) = renderer.render_with_alpha();
+        println!("Rendered in {}s", start_time.elapsed().as_secs_f64());
+
+        let raw_pixels = image.to_rgba8(&RgbaOptions {
+            max_luminance: settings.white_point,
+            color_space: settings.color_space,
+            coverage: Some(&alpha),
+        });
+
+        let mut writer = BufWriter::new(File::create(&settings.output_filename)?);
+        img::write_png_rgba(&mut writer, &raw_pixels, image.width(), image.height())?;
+        return Ok(());
+    }
+
+    let image = match (&args.layer_dir, &args.preview, args.adaptive_threshold) {
+        (Some(layer_dir), _, _) => {
+            let layers = renderer.render_layers();
+            let beauty = layers.beauty();
+            let exposure = settings.exposure(beauty.pixels());
+
+            for (layer, pixels) in layers.layers() {
+                write_png_file(
+                    &layer_dir.join(format!("{}.png", layer.name())),
+                    &img::exposed_pixels_to_raw(pixels, exposure, settings.color_space, false),
+                    layers.width(),
+                    layers.height(),
+                )?;
+            }
+
+            beauty
+        }
+        _ if args.integrator == Integrator::Photon => {
+            let default_opts = PhotonOptions::default();
+            let photon_opts = PhotonOptions {
+                photons_per_pass: args.photons.unwrap_or(default_opts.photons_per_pass),
+                initial_radius: args.photon_radius.unwrap_or(default_opts.initial_radius),
+                ..default_opts
+            };
+
+            renderer.render_photon_mapped(&photon_opts)
+        }
+        (None, _, Some(error_threshold)) => {
+            let adaptive = AdaptiveOptions {
+                error_threshold,
+                metric: if args.adaptive_blocks {
+                    ErrorMetric::Block
+                } else {
+                    ErrorMetric::PerPixel
+                },
+                ..AdaptiveOptions::default()
+            };
+
+            let (image, sample_counts) = renderer.render_adaptive(&adaptive);
+
+            if let Some(heatmap_filename) = &args.heatmap {
+                write_png_file(
+                    heatmap_filename,
+                    &img::samples_to_heatmap(&sample_counts, image.width(), image.height()),
+                    image.width(),
+                    image.height(),
+                )?;
+            }
+
+            image
+        }
+        (None, Some(preview_filename), None) => {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let (image, _passes) = renderer.render_progressive(cancel, |pixels, passes| {
+                if passes % args.preview_interval.max(1) != 0 {
+                    return;
+                }
+
+                let camera = renderer.camera();
+                let preview =
+                    Image::new(camera.pixel_width(), camera.pixel_height(), pixels.to_vec());
+
+                if let Err(err) = write_image(preview_filename, &preview) {
+                    eprintln!("Failed to write preview: {}", err);
+                }
+            });
+
+            image
+        }
+        (None, None, None) => renderer.render(),
+    };
 
     let elapsed = Instant::now() - start_time;
     println!("Rendered in {}s", elapsed.as_secs_f64());
 
-    let raw_pixels = img::pixels_to_srgb(&pixels);
-    let mut writer = BufWriter::new(File::create(args.output_filename)?);
-    img::write_png(
-        &mut writer,
+    let raw_pixels = img::exposed_pixels_to_raw(
+        image.pixels(),
+        settings.exposure(image.pixels()),
+        settings.color_space,
+        args.clip_overlay,
+    );
+
+    write_png_file(
+        &settings.output_filename,
         &raw_pixels,
-        camera.pixel_width(),
-        camera.pixel_height(),
-    )?;
+        image.width(),
+        image.height(),
+    )
+}
+
+fn write_image(filename: &Path, image: &Image) -> Result<(), Box<dyn Error>> {
+    write_png_file(filename, &image.to_srgb(), image.width(), image.height())
+}
+
+fn write_png_file(
+    filename: &Path,
+    raw_pixels: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    img::write_png(&mut writer, raw_pixels, width, height)?;
 
     Ok(())
 }
 
-fn build_scene() -> Scene {
-    let ground_material = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+fn build_scene(
+    settings: &Settings,
+    points: &[(Vec3, Vec3)],
+    point_radius: f64,
+    ambient: Option<f64>,
+    shadow_catcher: bool,
+    gobo: bool,
+    screen: bool,
+) -> Scene {
+    let ground_albedo = Vec3::new(0.5, 0.5, 0.5);
+    let ground_material: Arc<dyn Material + Send + Sync> = if shadow_catcher {
+        Arc::new(ShadowCatcher::new(ground_albedo))
+    } else {
+        Arc::new(Lambertian::new(ground_albedo))
+    };
     let pink_material = Arc::new(Lambertian::new(Vec3::new(1., 0.2, 0.2)));
     let gold_material = Arc::new(Mirror::new(Vec3::new(0.8, 0.6, 0.2)));
     let water_material = Arc::new(Dielectric::new(1.333));
 
     let mut builder = SceneBuilder::new();
+    builder.set_max_leaf_size(settings.max_leaf_size);
 
     builder.add_primitive(Sphere::new(Vec3::new(-0.5, 0., -1.), 0.5), pink_material);
     builder.add_primitive(Sphere::new(Vec3::new(0.5, 0., -1.), 0.5), gold_material);
@@ -124,10 +836,58 @@ fn build_scene() -> Scene {
         ground_material,
     );
 
-    builder.add_light(PointLight::new(
-        Vec3::new(0., 2., 0.5),
-        Vec3::from_element(10.),
-    ));
+    pointcloud::add_point_cloud(&mut builder, points, point_radius);
+
+    if gobo {
+        builder.add_light(
+            SpotLight::new(
+                Vec3::new(0., 2., 0.5),
+                Vec3::new(0., 0., -0.75),
+                30.,
+                Vec3::from_element(10.),
+            )
+            .with_gobo(Arc::new(|uv: Vec2| {
+                // A window with four round panes.
+                let pane = (2. * uv).map(|x| x.fract() - 0.5);
+                if pane.norm() < 0.4 {
+                    Vec3::from_element(1.)
+                } else {
+                    Vec3::default()
+                }
+            })),
+        );
+    } else {
+        builder.add_light(PointLight::new(
+            Vec3::new(0., 2., 0.5),
+            Vec3::from_element(10.),
+        ));
+    }
+
+    if screen {
+        builder.add_light(
+            QuadLight::new(
+                Vec3::new(-1.5, 0.2, -2.5),
+                Vec3::new(3., 0., 0.),
+                Vec3::new(0., 1., 0.),
+                Arc::new(|uv: Vec2| {
+                    // The seven color bars of a broadcast test pattern.
+                    const BARS: [[f64; 3]; 7] = [
+                        [1., 1., 1.],
+                        [1., 1., 0.],
+                        [0., 1., 1.],
+                        [0., 1., 0.],
+                        [1., 0., 1.],
+                        [1., 0., 0.],
+                        [0., 0., 1.],
+                    ];
+                    let bar = BARS[((uv[0] * 7.) as usize).min(6)];
+                    Vec3::new(bar[0], bar[1], bar[2])
+                }),
+            )
+            .unwrap()
+            .with_intensity(2.),
+        );
+    }
 
     builder.add_light(PointLight::new(
         Vec3::new(0.5, 2., -1.),
@@ -139,5 +899,91 @@ fn build_scene() -> Scene {
         10. * Vec3::new(0.5, 0.8, 0.5),
     ));
 
+    match settings.sky {
+        Sky::None => {}
+        Sky::Hemisphere => builder.add_light(HemisphereLight::new(
+            Vec3::y_axis(),
+            Vec3::from_element(0.2),
+        )),
+        Sky::Gradient => {
+            let gradient = GradientEnvironment::new(settings.sky_nadir, settings.sky_zenith);
+            match settings.sky_horizon {
+                Some(horizon) => builder.add_light(gradient.with_horizon(horizon)),
+                None => builder.add_light(gradient),
+            }
+        }
+        Sky::Sunset => builder.add_light(PreethamSky::new(
+            Unit3::new_normalize(Vec3::new(-1., 0.1, -1.)),
+            3.,
+            Vec3::from_element(0.2),
+        )),
+    }
+
+    if let Some(ambient) = ambient {
+        builder.set_ambient(AmbientLight::new(Vec3::from_element(ambient)));
+    }
+
     builder.build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], config: &str) -> Settings {
+        let args = CliArgs::from_iter(std::iter::once("rtow").chain(args.iter().copied()));
+        Settings::resolve(&args, &Config::parse(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn cli_flags_override_config() {
+        let config = r#"
+            [image]
+            width = 640
+            height = 360
+            color_space = "linear"
+
+            [camera]
+            vfov = 30
+
+            [render]
+            spp = 256
+            max_depth = 6
+            sky = "sunset"
+            refracted_connections = true
+        "#;
+
+        let settings = resolve(&["-w", "80", "--spp", "8", "--sky", "none"], config);
+
+        // Flags win over the file...
+        assert_eq!(settings.width, 80);
+        assert_eq!(settings.samples_per_pixel, 8);
+        assert_eq!(settings.sky, Sky::None);
+
+        // ...which wins over the defaults.
+        assert_eq!(settings.height, 360);
+        assert_eq!(settings.vfov, 30.);
+        assert_eq!(settings.max_depth, 6);
+        assert_eq!(settings.color_space, OutputColorSpace::Linear);
+        assert!(settings.refracted_connections);
+
+        let settings = resolve(&["-w", "80", "-h", "45"], "");
+        assert_eq!(settings.vfov, 50.);
+        assert_eq!(settings.samples_per_pixel, 100);
+        assert_eq!(settings.sky, Sky::None);
+        assert!(!settings.refracted_connections);
+
+        let settings = resolve(&["-w", "80", "-h", "45", "--refracted-connections"], "");
+        assert!(settings.refracted_connections);
+    }
+
+    #[test]
+    fn heatmap_requires_adaptive_sampling() {
+        let args = |args: &[&str]| {
+            CliArgs::from_iter_safe(std::iter::once("rtow").chain(args.iter().copied()))
+        };
+
+        assert!(args(&["--heatmap", "heat.png"]).is_err());
+        assert!(args(&["--heatmap", "heat.png", "--adaptive-threshold", "0.05"]).is_ok());
+    }
+}
@@ -1,12 +1,16 @@
-use std::f64;
+use std::sync::Arc;
+use std::{any, f64};
 
 use rand::{Rng, RngCore};
 use rand_distr::Distribution;
 
-use crate::distr::CosWeightedHemisphere;
+use crate::distr::{CosWeightedHemisphere, PowerCosineHemisphere};
 use crate::geom::HitSide;
-use crate::math::{Unit3, Vec3};
-use crate::shading::{self, same_hemisphere, SampledRadiance, ShadingInfo};
+use crate::math::{OrthoNormalBasis, Unit3, Vec3};
+use crate::microfacet::{self, Ggx};
+use crate::shading::{self, same_hemisphere, Pdf, RefractionEvent, SampledRadiance, ShadingInfo};
+
+pub mod debug;
 
 pub trait Material {
     fn sample_bsdf(
@@ -17,9 +21,90 @@ pub trait Material {
     fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3;
 
     fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64;
+
+    /// Returns the color of a diffuse surface standing in for the material at the hit described
+    /// by `shading_info`, for albedo passes such as those guiding denoisers. Materials without a
+    /// color of their own, such as clear glass, are white.
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        Vec3::from_element(1.)
+    }
+
+    /// Returns `true` if the material only ever scatters light specularly, unless it is blurred
+    /// for path regularization (see [`ShadingInfo::min_roughness`]).
     fn is_always_specular(&self) -> bool {
         false
     }
+
+    /// Returns how strongly light sampling should be favored over BSDF sampling at the hit
+    /// described by `shading_info`, from 0 (rely on BSDF sampling alone) to 1 (weigh the two by
+    /// their densities alone).
+    ///
+    /// The integrator treats this as the relative number of light samples when weighting the two
+    /// techniques by multiple importance sampling, so any value keeps the image unbiased; it only
+    /// shifts noise between them. Light from delta lights, which BSDF sampling can never find, is
+    /// always sampled.
+    fn light_sampling_weight(&self, _shading_info: &ShadingInfo) -> f64 {
+        1.
+    }
+
+    /// Returns the fraction of light passing straight through the surface at the hit described by
+    /// `shading_info`, or `None` if the material is opaque.
+    ///
+    /// This lets shadow rays pass through transparent objects instead of being blocked by them.
+    /// Refraction is ignored, so the light is not actually focused.
+    fn shadow_transmittance(&self, _shading_info: &ShadingInfo) -> Option<Vec3> {
+        None
+    }
+
+    /// Returns the (local) direction in which light arriving along `shading_info.outgoing`
+    /// continues when refracted through the surface, with the fraction of it transmitted, or
+    /// `None` if the material does not refract specularly.
+    ///
+    /// This lets shadow connections to delta lights be bent through refractive objects, so that
+    /// they focus light as they should.
+    fn specular_refraction(&self, _shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        None
+    }
+
+    /// Returns the refractive medium enclosed by surfaces of this material, or `None` if the
+    /// material does not enclose one.
+    ///
+    /// The integrator keeps track of the media a path has entered, so that it can tell the
+    /// material the refractive index on the far side of the surface (see
+    /// [`ShadingInfo::exterior_ior`]) when objects are nested inside one another.
+    fn interior_medium(&self) -> Option<Medium> {
+        None
+    }
+
+    /// Returns `true` if the surface stands in for the ground of a photograph, showing only the
+    /// shadows cast on it (see [`ShadowCatcher`]).
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if the material absorbs all light reaching it, ending every path that hits
+    /// it (see [`Absorber`]).
+    fn is_absorber(&self) -> bool {
+        false
+    }
+
+    /// A human-readable name for the material, used when debugging.
+    fn debug_name(&self) -> &'static str {
+        any::type_name::<Self>()
+    }
+}
+
+/// A refractive medium filling the inside of an object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Medium {
+    pub refractive_index: f64,
+    /// Decides which medium fills the space where objects overlap: the one with the highest
+    /// priority, or the one entered last among equals. Surfaces of a medium lying inside another
+    /// of higher priority are not really there, and are passed through undisturbed.
+    ///
+    /// This allows modeling a liquid that touches its container by letting the two overlap, and
+    /// giving the container the higher priority.
+    pub priority: u32,
 }
 
 pub struct SpecularScatter {
@@ -39,6 +124,37 @@ pub trait SpecularMaterial {
         shading_info: &ShadingInfo,
         rng: &mut dyn RngCore,
     ) -> Option<SpecularScatter>;
+
+    /// See [`Material::albedo_hint`].
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        Vec3::from_element(1.)
+    }
+
+    /// See [`Material::shadow_transmittance`].
+    fn shadow_transmittance(&self, _shading_info: &ShadingInfo) -> Option<Vec3> {
+        None
+    }
+
+    /// Returns the (local) direction in which light arriving along `shading_info.outgoing` is
+    /// reflected, with the fraction of it reflected, or `None` if the material does not reflect
+    /// specularly.
+    ///
+    /// Together with [`SpecularMaterial::specular_refraction`], this describes all the light the
+    /// material scatters, which lets it be blurred for path regularization (see
+    /// [`ShadingInfo::min_roughness`]). Materials that describe neither stay sharp.
+    fn specular_reflection(&self, _shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        None
+    }
+
+    /// See [`Material::specular_refraction`].
+    fn specular_refraction(&self, _shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        None
+    }
+
+    /// See [`Material::interior_medium`].
+    fn interior_medium(&self) -> Option<Medium> {
+        None
+    }
 }
 
 impl<M: SpecularMaterial> Material for M {
@@ -47,24 +163,47 @@ impl<M: SpecularMaterial> Material for M {
         shading_info: &ShadingInfo,
         rng: &mut dyn RngCore,
     ) -> Option<SampledRadiance> {
+        if let Some(blurred) = BlurredSpecular::new(self, shading_info) {
+            return blurred.sample(shading_info, rng);
+        }
+
         let scatter = self.sample_specular_scatter(shading_info, rng)?;
-        Some(SampledRadiance::new_delta(
+        Some(SampledRadiance::new_specular(
             scatter.dir,
-            scatter.attenuation / shading::cos_theta(scatter.dir),
+            scatter.attenuation,
         ))
     }
 
-    fn bsdf(&self, _shading_info: &ShadingInfo, _incoming: Unit3) -> Vec3 {
-        Vec3::default()
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        BlurredSpecular::new(self, shading_info).map_or(Vec3::default(), |blurred| {
+            blurred.bsdf(shading_info, incoming)
+        })
     }
 
-    fn pdf(&self, _shading_info: &ShadingInfo, _incoming: Unit3) -> f64 {
-        0.
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        BlurredSpecular::new(self, shading_info)
+            .map_or(0., |blurred| blurred.pdf(shading_info, incoming))
+    }
+
+    fn albedo_hint(&self, shading_info: &ShadingInfo) -> Vec3 {
+        SpecularMaterial::albedo_hint(self, shading_info)
     }
 
     fn is_always_specular(&self) -> bool {
         true
     }
+
+    fn shadow_transmittance(&self, shading_info: &ShadingInfo) -> Option<Vec3> {
+        SpecularMaterial::shadow_transmittance(self, shading_info)
+    }
+
+    fn specular_refraction(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        SpecularMaterial::specular_refraction(self, shading_info)
+    }
+
+    fn interior_medium(&self) -> Option<Medium> {
+        SpecularMaterial::interior_medium(self)
+    }
 }
 
 pub struct Lambertian {
@@ -87,12 +226,16 @@ impl Material for Lambertian {
         Some(SampledRadiance::new_real(
             dir,
             self.albedo * f64::consts::FRAC_1_PI,
-            shading::cos_theta(dir) * f64::consts::FRAC_1_PI,
+            CosWeightedHemisphere.pdf(dir),
         ))
     }
 
-    fn bsdf(&self, _shading_info: &ShadingInfo, _incoming: Unit3) -> Vec3 {
-        self.albedo * f64::consts::FRAC_1_PI
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        if same_hemisphere(*incoming, *shading_info.outgoing) {
+            self.albedo * f64::consts::FRAC_1_PI
+        } else {
+            Vec3::default()
+        }
     }
 
     fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
@@ -102,6 +245,210 @@ impl Material for Lambertian {
             0.
         }
     }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        self.albedo
+    }
+}
+
+/// A surface for compositing rendered objects into a photograph. Seen by the camera in renders
+/// with an alpha channel (see [`render_with_alpha`](crate::render::render_with_alpha)), it is
+/// transparent, except for the shadows the scene casts on it, which darken it and make it
+/// opaque in proportion to the light they block.
+///
+/// Everywhere else, such as in reflections and in renders without alpha, it is a diffuse surface
+/// of color `albedo`, standing in for the ground of the photograph so that objects still pick up
+/// its bounce light.
+pub struct ShadowCatcher {
+    lambertian: Lambertian,
+}
+
+impl ShadowCatcher {
+    pub fn new(albedo: Vec3) -> Self {
+        Self {
+            lambertian: Lambertian::new(albedo),
+        }
+    }
+}
+
+impl Material for ShadowCatcher {
+    fn sample_bsdf(
+        &self,
+        shading_info: &ShadingInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledRadiance> {
+        self.lambertian.sample_bsdf(shading_info, rng)
+    }
+
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        self.lambertian.bsdf(shading_info, incoming)
+    }
+
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        self.lambertian.pdf(shading_info, incoming)
+    }
+
+    fn albedo_hint(&self, shading_info: &ShadingInfo) -> Vec3 {
+        self.lambertian.albedo_hint(shading_info)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        true
+    }
+}
+
+/// A perfectly black surface absorbing all light reaching it, such as the lining of a light trap.
+/// It scatters nothing, so paths end wherever they hit it.
+///
+/// Unlike a surface that merely happens to reflect nothing (such as a black [`Lambertian`]), it
+/// identifies itself through [`Material::is_absorber`], and the integrator spends no light samples
+/// on it.
+pub struct Absorber;
+
+impl Material for Absorber {
+    fn sample_bsdf(
+        &self,
+        _shading_info: &ShadingInfo,
+        _rng: &mut dyn RngCore,
+    ) -> Option<SampledRadiance> {
+        None
+    }
+
+    fn bsdf(&self, _shading_info: &ShadingInfo, _incoming: Unit3) -> Vec3 {
+        Vec3::default()
+    }
+
+    fn pdf(&self, _shading_info: &ShadingInfo, _incoming: Unit3) -> f64 {
+        0.
+    }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        Vec3::default()
+    }
+
+    fn is_absorber(&self) -> bool {
+        true
+    }
+}
+
+/// A direction into which a [`SpecularMaterial`] scatters light, blurred for path regularization.
+struct BlurredLobe {
+    /// Basis whose `w` axis is the specular direction.
+    basis: OrthoNormalBasis,
+    attenuation: Vec3,
+    /// Whether the lobe lies on the same side of the surface as the outgoing direction.
+    reflects: bool,
+}
+
+/// The lobes of a [`SpecularMaterial`] blurred to the roughness requested by
+/// [`ShadingInfo::min_roughness`]. Every specular direction is spread into a Phong-like lobe about
+/// it, about as wide as a GGX reflection lobe of that roughness, that scatters the same fraction
+/// of light. The parts of a lobe that cross to the wrong side of the surface are lost.
+struct BlurredSpecular {
+    lobes: [Option<BlurredLobe>; 2],
+    distr: PowerCosineHemisphere,
+}
+
+impl BlurredSpecular {
+    fn new<M: SpecularMaterial + ?Sized>(material: &M, shading_info: &ShadingInfo) -> Option<Self> {
+        let roughness = Ggx::new_rough(shading_info.min_roughness)?.roughness();
+
+        let lobe = |scatter: Option<SpecularScatter>| {
+            scatter.map(|scatter| BlurredLobe {
+                basis: OrthoNormalBasis::from_w(scatter.dir),
+                attenuation: scatter.attenuation,
+                reflects: same_hemisphere(*scatter.dir, *shading_info.outgoing),
+            })
+        };
+
+        let lobes = [
+            lobe(material.specular_reflection(shading_info)),
+            lobe(material.specular_refraction(shading_info)),
+        ];
+        if lobes.iter().all(Option::is_none) {
+            return None;
+        }
+
+        // Phong exponent matching the width of the Beckmann distribution (which GGX resembles
+        // near its peak), divided by four since reflection doubles angles from the normal.
+        let alpha = roughness.powi(2);
+        let exponent = (2. / alpha.powi(2) - 2.).max(0.) / 4.;
+
+        Some(Self {
+            lobes,
+            distr: PowerCosineHemisphere::new(exponent),
+        })
+    }
+
+    fn lobes(&self) -> impl Iterator<Item = &BlurredLobe> {
+        self.lobes.iter().flatten()
+    }
+
+    /// Returns the probability of sampling `lobe`, in proportion to the light it scatters.
+    fn weight(&self, lobe: &BlurredLobe) -> f64 {
+        let total: f64 = self.lobes().map(|lobe| lobe.attenuation.sum()).sum();
+        if total > 0. {
+            lobe.attenuation.sum() / total
+        } else {
+            0.
+        }
+    }
+
+    /// Returns the lobes scattering light into `incoming`, on the side of the surface it lies on.
+    fn lobes_toward<'a>(
+        &'a self,
+        shading_info: &ShadingInfo,
+        incoming: Unit3,
+    ) -> impl Iterator<Item = (&'a BlurredLobe, f64)> + 'a {
+        let reflects = same_hemisphere(*incoming, *shading_info.outgoing);
+        self.lobes()
+            .filter(move |lobe| lobe.reflects == reflects)
+            .map(move |lobe| {
+                let local = Unit3::new_unchecked(lobe.basis.trans_from_canonical(*incoming));
+                (lobe, self.distr.pdf(local))
+            })
+    }
+
+    fn sample(&self, shading_info: &ShadingInfo, rng: &mut dyn RngCore) -> Option<SampledRadiance> {
+        let mut choice = rng.gen::<f64>();
+        let lobe = self.lobes().find(|lobe| {
+            choice -= self.weight(lobe);
+            choice < 0.
+        })?;
+
+        let dir = Unit3::new_normalize(lobe.basis.trans_to_canonical(*self.distr.sample(rng)));
+
+        let pdf = self.pdf(shading_info, dir);
+        if pdf == 0. {
+            return None;
+        }
+
+        Some(SampledRadiance::new_real(
+            dir,
+            self.bsdf(shading_info, dir),
+            pdf,
+        ))
+    }
+
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        // A specular lobe's attenuation already accounts for the cosine factor, which the BSDF
+        // must leave out.
+        let cos_theta = shading::cos_theta(incoming).abs();
+        if cos_theta == 0. {
+            return Vec3::default();
+        }
+
+        self.lobes_toward(shading_info, incoming)
+            .map(|(lobe, density)| lobe.attenuation * density)
+            .sum::<Vec3>()
+            / cos_theta
+    }
+
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        self.lobes_toward(shading_info, incoming)
+            .map(|(lobe, density)| self.weight(lobe) * density)
+            .sum()
+    }
 }
 
 pub struct Mirror {
@@ -120,12 +467,20 @@ impl SpecularMaterial for Mirror {
         shading_info: &ShadingInfo,
         _rng: &mut dyn RngCore,
     ) -> Option<SpecularScatter> {
+        self.specular_reflection(shading_info)
+    }
+
+    fn specular_reflection(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
         let reflected = reflect_z(*shading_info.outgoing);
         Some(SpecularScatter::new(
             Unit3::new_unchecked(reflected),
             self.color,
         ))
     }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        self.color
+    }
 }
 
 fn reflect_z(incoming: Vec3) -> Vec3 {
@@ -138,50 +493,637 @@ fn schlick_reflectance(r0: f64, cos_theta: f64) -> f64 {
 
 pub struct Dielectric {
     refractive_index: f64,
+    priority: u32,
 }
 
 impl Dielectric {
     pub fn new(refractive_index: f64) -> Self {
-        Self { refractive_index }
+        Self {
+            refractive_index,
+            priority: 0,
+        }
+    }
+
+    /// Sets the priority of the dielectric's medium where it overlaps others (see
+    /// [`Medium::priority`]). The default is 0.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl Dielectric {
+    fn refractive_ratio(&self, shading_info: &ShadingInfo) -> f64 {
+        match shading_info.side {
+            HitSide::Inside => self.refractive_index / shading_info.exterior_ior,
+            HitSide::Outside => shading_info.exterior_ior / self.refractive_index,
+        }
     }
 }
 
 impl SpecularMaterial for Dielectric {
+    fn shadow_transmittance(&self, shading_info: &ShadingInfo) -> Option<Vec3> {
+        let refractive_ratio = self.refractive_ratio(shading_info);
+
+        let transmitted = match refract_z(shading_info, refractive_ratio) {
+            RefractionEvent::Refract(_) => {
+                1. - dielectric_reflectance(shading_info.cos_theta(), refractive_ratio)
+            }
+            RefractionEvent::TotalInternalReflection(_) => 0.,
+        };
+
+        Some(Vec3::from_element(transmitted))
+    }
+
     fn sample_specular_scatter(
         &self,
         shading_info: &ShadingInfo,
         rng: &mut dyn RngCore,
     ) -> Option<SpecularScatter> {
-        let refractive_ratio = match shading_info.side {
-            HitSide::Inside => self.refractive_index,
-            HitSide::Outside => 1. / self.refractive_index,
+        let refractive_ratio = self.refractive_ratio(shading_info);
+
+        let dir = match refract_z(shading_info, refractive_ratio) {
+            RefractionEvent::Refract(refracted) => {
+                let reflectance =
+                    dielectric_reflectance(shading_info.cos_theta(), refractive_ratio);
+                if rng.gen::<f64>() < reflectance {
+                    reflect_z(*shading_info.outgoing)
+                } else {
+                    refracted
+                }
+            }
+            RefractionEvent::TotalInternalReflection(reflected) => reflected,
         };
 
-        let outgoing = *shading_info.outgoing;
-        let cos_theta = shading_info.cos_theta();
-        let sin_theta = shading_info.sin_theta();
+        Some(SpecularScatter::new(
+            Unit3::new_normalize(dir),
+            Vec3::from_element(1.),
+        ))
+    }
 
-        let dir = if refractive_ratio * sin_theta > 1.
-            || rng.gen::<f64>() < dielectric_reflectance(cos_theta, refractive_ratio)
-        {
-            reflect_z(outgoing)
-        } else {
-            let up = *Vec3::z_axis();
+    fn specular_reflection(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        let refractive_ratio = self.refractive_ratio(shading_info);
 
-            let refracted_perp = refractive_ratio * (cos_theta * up - outgoing);
-            let refracted_par = -(1. - refracted_perp.norm_squared()).sqrt() * up;
+        let reflected = match refract_z(shading_info, refractive_ratio) {
+            RefractionEvent::Refract(_) => {
+                dielectric_reflectance(shading_info.cos_theta(), refractive_ratio)
+            }
+            RefractionEvent::TotalInternalReflection(_) => 1.,
+        };
 
-            refracted_perp + refracted_par
+        Some(SpecularScatter::new(
+            Unit3::new_unchecked(reflect_z(*shading_info.outgoing)),
+            Vec3::from_element(reflected),
+        ))
+    }
+
+    fn specular_refraction(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        let refractive_ratio = self.refractive_ratio(shading_info);
+        let refracted = match refract_z(shading_info, refractive_ratio) {
+            RefractionEvent::Refract(refracted) => refracted,
+            RefractionEvent::TotalInternalReflection(_) => return None,
         };
 
+        let transmitted = 1. - dielectric_reflectance(shading_info.cos_theta(), refractive_ratio);
+
         Some(SpecularScatter::new(
-            Unit3::new_normalize(dir),
-            Vec3::from_element(1.),
+            Unit3::new_normalize(refracted),
+            Vec3::from_element(transmitted),
         ))
     }
+
+    fn interior_medium(&self) -> Option<Medium> {
+        Some(Medium {
+            refractive_index: self.refractive_index,
+            priority: self.priority,
+        })
+    }
+}
+
+/// A [`Dielectric`] that colors the light it transmits by `tint` at every refraction, leaving
+/// reflections untouched. This cheaply approximates colored glass without modeling absorption
+/// within the medium, so thin and thick objects are tinted alike.
+pub struct TintedDielectric {
+    dielectric: Dielectric,
+    tint: Vec3,
+}
+
+impl TintedDielectric {
+    pub fn new(refractive_index: f64, tint: Vec3) -> Self {
+        Self {
+            dielectric: Dielectric::new(refractive_index),
+            tint,
+        }
+    }
+
+    /// See [`Dielectric::with_priority`].
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.dielectric = self.dielectric.with_priority(priority);
+        self
+    }
+}
+
+impl SpecularMaterial for TintedDielectric {
+    fn shadow_transmittance(&self, shading_info: &ShadingInfo) -> Option<Vec3> {
+        let transmitted = SpecularMaterial::shadow_transmittance(&self.dielectric, shading_info)?;
+        Some(transmitted.component_mul(&self.tint))
+    }
+
+    fn sample_specular_scatter(
+        &self,
+        shading_info: &ShadingInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SpecularScatter> {
+        let scatter = self.dielectric.sample_specular_scatter(shading_info, rng)?;
+
+        if same_hemisphere(*scatter.dir, *shading_info.outgoing) {
+            Some(scatter)
+        } else {
+            Some(SpecularScatter::new(
+                scatter.dir,
+                scatter.attenuation.component_mul(&self.tint),
+            ))
+        }
+    }
+
+    fn specular_reflection(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        self.dielectric.specular_reflection(shading_info)
+    }
+
+    fn specular_refraction(&self, shading_info: &ShadingInfo) -> Option<SpecularScatter> {
+        let refraction = SpecularMaterial::specular_refraction(&self.dielectric, shading_info)?;
+        Some(SpecularScatter::new(
+            refraction.dir,
+            refraction.attenuation.component_mul(&self.tint),
+        ))
+    }
+
+    fn interior_medium(&self) -> Option<Medium> {
+        SpecularMaterial::interior_medium(&self.dielectric)
+    }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        self.tint
+    }
+}
+
+/// Refracts light leaving along `shading_info.outgoing` through a smooth surface with normal `z`,
+/// as with [`shading::refract_or_reflect`].
+fn refract_z(shading_info: &ShadingInfo, refractive_ratio: f64) -> RefractionEvent {
+    shading::refract_or_reflect(*shading_info.outgoing, *Vec3::z_axis(), refractive_ratio)
 }
 
 fn dielectric_reflectance(cos_theta: f64, refractive_ratio: f64) -> f64 {
     let r0 = ((1. - refractive_ratio) / (1. + refractive_ratio)).powi(2);
     schlick_reflectance(r0, cos_theta)
 }
+
+/// A diffuse base under a (possibly rough) dielectric coating.
+pub struct Plastic {
+    diffuse_albedo: Vec3,
+    /// The coating's microfacet distribution, or `None` if it is perfectly smooth.
+    distr: Option<Ggx>,
+    refractive_ratio: f64,
+}
+
+impl Plastic {
+    pub fn new(diffuse_albedo: Vec3, roughness: f64, refractive_index: f64) -> Self {
+        Self {
+            diffuse_albedo,
+            distr: Ggx::new_rough(roughness),
+            refractive_ratio: 1. / refractive_index,
+        }
+    }
+
+    fn specular_probability(&self, shading_info: &ShadingInfo) -> f64 {
+        dielectric_reflectance(shading_info.cos_theta(), self.refractive_ratio)
+    }
+
+    fn distr(&self, shading_info: &ShadingInfo) -> Option<Ggx> {
+        microfacet::regularize(self.distr, shading_info.min_roughness)
+    }
+}
+
+impl Material for Plastic {
+    fn sample_bsdf(
+        &self,
+        shading_info: &ShadingInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledRadiance> {
+        let dir = if rng.gen::<f64>() < self.specular_probability(shading_info) {
+            match self.distr(shading_info) {
+                Some(distr) => microfacet::reflect(shading_info.outgoing, distr.sample_half(rng)),
+                None => {
+                    // The coating's reflectance is exactly the probability with which we chose
+                    // it, so the two cancel out.
+                    let dir = Unit3::new_unchecked(reflect_z(*shading_info.outgoing));
+                    return Some(SampledRadiance::new_specular(dir, Vec3::from_element(1.)));
+                }
+            }
+        } else {
+            CosWeightedHemisphere.sample(rng)
+        };
+
+        let pdf = self.pdf(shading_info, dir);
+        if pdf == 0. {
+            return None;
+        }
+
+        Some(SampledRadiance::new_real(
+            dir,
+            self.bsdf(shading_info, dir),
+            pdf,
+        ))
+    }
+
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        let outgoing = shading_info.outgoing;
+        if !same_hemisphere(*incoming, *outgoing) || shading::cos_theta(incoming) <= 0. {
+            return Vec3::default();
+        }
+
+        let diffuse = (1. - self.specular_probability(shading_info))
+            * self.diffuse_albedo
+            * f64::consts::FRAC_1_PI;
+
+        let specular = match (
+            self.distr(shading_info),
+            microfacet::reflection_half(outgoing, incoming),
+        ) {
+            (Some(distr), Some(half)) => {
+                let fresnel = dielectric_reflectance(incoming.dot(&half), self.refractive_ratio);
+                distr.d(half) * distr.g(outgoing, incoming) * fresnel
+                    / (4. * shading_info.cos_theta() * shading::cos_theta(incoming))
+            }
+            _ => 0.,
+        };
+
+        diffuse + Vec3::from_element(specular)
+    }
+
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        if !same_hemisphere(*incoming, *shading_info.outgoing) || shading::cos_theta(incoming) <= 0.
+        {
+            return 0.;
+        }
+
+        let specular_probability = self.specular_probability(shading_info);
+        let specular_pdf = self.distr(shading_info).map_or(0., |distr| {
+            distr.reflection_pdf(shading_info.outgoing, incoming)
+        });
+
+        shading::combine_pdfs(&[
+            (specular_probability, specular_pdf),
+            (
+                1. - specular_probability,
+                CosWeightedHemisphere.pdf(incoming),
+            ),
+        ])
+    }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        self.diffuse_albedo
+    }
+}
+
+/// A metal with a (possibly rough) GGX microfacet surface.
+pub struct RoughConductor {
+    /// Reflectance at normal incidence, used with Schlick's approximation.
+    color: Vec3,
+    /// The surface's microfacet distribution, or `None` if it is perfectly smooth.
+    distr: Option<Ggx>,
+    energy_compensation: bool,
+}
+
+impl RoughConductor {
+    pub fn new(color: Vec3, roughness: f64) -> Self {
+        Self {
+            color,
+            distr: Ggx::new_rough(roughness),
+            energy_compensation: false,
+        }
+    }
+
+    /// Creates a metal whose reflections are blurred according to `gloss`, from 0 (roughest) to 1
+    /// (a perfect mirror), in the role of the "fuzz" (`1 - gloss`) of simpler metal models.
+    ///
+    /// Unlike fuzz, which perturbs reflected directions without a known density, the blur comes
+    /// from a microfacet distribution, so glossy metals are also lit by light sampling.
+    pub fn glossy(color: Vec3, gloss: f64) -> Self {
+        Self::new(color, 1. - gloss.clamp(0., 1.))
+    }
+
+    /// Adds back the energy lost to light scattering between microfacets more than once, using
+    /// the Kulla-Conty approximation. Without this, rough metals appear too dark.
+    pub fn with_energy_compensation(mut self) -> Self {
+        self.energy_compensation = true;
+        self
+    }
+
+    fn distr(&self, shading_info: &ShadingInfo) -> Option<Ggx> {
+        microfacet::regularize(self.distr, shading_info.min_roughness)
+    }
+
+    /// Returns the probability with which the compensation lobe is sampled for `shading_info`.
+    fn compensation_probability(&self, shading_info: &ShadingInfo) -> f64 {
+        match self.distr(shading_info) {
+            Some(distr) if self.energy_compensation => {
+                1. - microfacet::albedo_table().albedo(&distr, shading_info.cos_theta())
+            }
+            _ => 0.,
+        }
+    }
+
+    fn multiple_scattering(&self, distr: &Ggx, cos_outgoing: f64, cos_incoming: f64) -> Vec3 {
+        let table = microfacet::albedo_table();
+
+        let average_albedo = table.average_albedo(distr);
+        if average_albedo >= 1. {
+            return Vec3::default();
+        }
+
+        let lobe = (1. - table.albedo(distr, cos_outgoing))
+            * (1. - table.albedo(distr, cos_incoming))
+            / (f64::consts::PI * (1. - average_albedo));
+
+        // The energy that eventually leaves after several bounces is tinted by the Fresnel
+        // reflectance of every bounce, not just the first.
+        let average_fresnel = (20. * self.color).add_scalar(1.) / 21.;
+        let tint =
+            average_fresnel.map(|f| f.powi(2) * average_albedo / (1. - f * (1. - average_albedo)));
+
+        lobe * tint
+    }
+}
+
+impl Material for RoughConductor {
+    fn sample_bsdf(
+        &self,
+        shading_info: &ShadingInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledRadiance> {
+        let distr = match self.distr(shading_info) {
+            Some(distr) => distr,
+            None => {
+                let dir = Unit3::new_unchecked(reflect_z(*shading_info.outgoing));
+                return Some(SampledRadiance::new_specular(
+                    dir,
+                    schlick_fresnel(self.color, shading_info.cos_theta()),
+                ));
+            }
+        };
+
+        let dir = if rng.gen::<f64>() < self.compensation_probability(shading_info) {
+            CosWeightedHemisphere.sample(rng)
+        } else {
+            microfacet::reflect(shading_info.outgoing, distr.sample_half(rng))
+        };
+
+        let pdf = self.pdf(shading_info, dir);
+        if pdf == 0. {
+            return None;
+        }
+
+        Some(SampledRadiance::new_real(
+            dir,
+            self.bsdf(shading_info, dir),
+            pdf,
+        ))
+    }
+
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        let outgoing = shading_info.outgoing;
+        if !same_hemisphere(*incoming, *outgoing) || shading::cos_theta(incoming) <= 0. {
+            return Vec3::default();
+        }
+
+        let distr = match self.distr(shading_info) {
+            Some(distr) => distr,
+            None => return Vec3::default(),
+        };
+
+        let single = match microfacet::reflection_half(outgoing, incoming) {
+            Some(half) => {
+                schlick_fresnel(self.color, incoming.dot(&half))
+                    * (distr.d(half) * distr.g(outgoing, incoming)
+                        / (4. * shading_info.cos_theta() * shading::cos_theta(incoming)))
+            }
+            None => Vec3::default(),
+        };
+
+        if !self.energy_compensation {
+            return single;
+        }
+
+        single
+            + self.multiple_scattering(
+                &distr,
+                shading_info.cos_theta(),
+                shading::cos_theta(incoming),
+            )
+    }
+
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        if !same_hemisphere(*incoming, *shading_info.outgoing) || shading::cos_theta(incoming) <= 0.
+        {
+            return 0.;
+        }
+
+        let distr = match self.distr(shading_info) {
+            Some(distr) => distr,
+            None => return 0.,
+        };
+
+        let compensation_probability = self.compensation_probability(shading_info);
+
+        shading::combine_pdfs(&[
+            (
+                1. - compensation_probability,
+                distr.reflection_pdf(shading_info.outgoing, incoming),
+            ),
+            (
+                compensation_probability,
+                CosWeightedHemisphere.pdf(incoming),
+            ),
+        ])
+    }
+
+    fn albedo_hint(&self, _shading_info: &ShadingInfo) -> Vec3 {
+        self.color
+    }
+}
+
+fn schlick_fresnel(f0: Vec3, cos_theta: f64) -> Vec3 {
+    f0.map(|r0| schlick_reflectance(r0, cos_theta))
+}
+
+/// Stochastically layers one material over another, choosing between them according to the
+/// dielectric Fresnel reflectance at the outgoing angle.
+///
+/// `top` is seen in the proportion of light reflected by an interface with refractive index
+/// `ior`, and `bottom` in the proportion transmitted through it, so the blend becomes dominated by
+/// `top` at grazing angles.
+pub struct FresnelBlend {
+    refractive_ratio: f64,
+    top: Arc<dyn Material + Send + Sync>,
+    bottom: Arc<dyn Material + Send + Sync>,
+}
+
+impl FresnelBlend {
+    pub fn new(
+        ior: f64,
+        top: Arc<dyn Material + Send + Sync>,
+        bottom: Arc<dyn Material + Send + Sync>,
+    ) -> Self {
+        Self {
+            refractive_ratio: 1. / ior,
+            top,
+            bottom,
+        }
+    }
+
+    fn top_weight(&self, shading_info: &ShadingInfo) -> f64 {
+        dielectric_reflectance(shading_info.cos_theta().abs(), self.refractive_ratio)
+    }
+}
+
+impl Material for FresnelBlend {
+    fn sample_bsdf(
+        &self,
+        shading_info: &ShadingInfo,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledRadiance> {
+        let top_weight = self.top_weight(shading_info);
+        let chosen = if rng.gen::<f64>() < top_weight {
+            &self.top
+        } else {
+            &self.bottom
+        };
+
+        let sample = chosen.sample_bsdf(shading_info, rng)?;
+
+        match sample.pdf {
+            // The other material can't produce this exact direction, and the blend weight cancels
+            // out with the probability of choosing the material.
+            Pdf::Delta => Some(sample),
+            Pdf::Real(_) => {
+                let pdf = self.pdf(shading_info, sample.dir);
+                if pdf == 0. {
+                    return None;
+                }
+
+                Some(SampledRadiance::new_real(
+                    sample.dir,
+                    self.bsdf(shading_info, sample.dir),
+                    pdf,
+                ))
+            }
+        }
+    }
+
+    fn bsdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> Vec3 {
+        let top_weight = self.top_weight(shading_info);
+        top_weight * self.top.bsdf(shading_info, incoming)
+            + (1. - top_weight) * self.bottom.bsdf(shading_info, incoming)
+    }
+
+    fn pdf(&self, shading_info: &ShadingInfo, incoming: Unit3) -> f64 {
+        let top_weight = self.top_weight(shading_info);
+        shading::combine_pdfs(&[
+            (top_weight, self.top.pdf(shading_info, incoming)),
+            (1. - top_weight, self.bottom.pdf(shading_info, incoming)),
+        ])
+    }
+
+    fn albedo_hint(&self, shading_info: &ShadingInfo) -> Vec3 {
+        let top_weight = self.top_weight(shading_info);
+        top_weight * self.top.albedo_hint(shading_info)
+            + (1. - top_weight) * self.bottom.albedo_hint(shading_info)
+    }
+
+    fn is_always_specular(&self) -> bool {
+        self.top.is_always_specular() && self.bottom.is_always_specular()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    use super::*;
+
+    fn outside(outgoing: Unit3) -> ShadingInfo {
+        ShadingInfo {
+            side: HitSide::Outside,
+            outgoing,
+            point: Vec3::default(),
+            normal: Vec3::z_axis(),
+            exterior_ior: 1.,
+            min_roughness: 0.,
+        }
+    }
+
+    /// Samples a direction uniformly from the cone of half-angle `angle` around `axis`.
+    fn sample_cone(axis: Unit3, angle: f64, rng: &mut dyn RngCore) -> Unit3 {
+        let cos_theta = rng.gen_range(angle.cos()..=1.);
+        let sin_theta = (1. - cos_theta.powi(2)).sqrt();
+        let phi = rng.gen_range(0.0..f64::consts::TAU);
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        Unit3::new_normalize(OrthoNormalBasis::from_w(axis).trans_to_canonical(local))
+    }
+
+    #[test]
+    fn plastic_reports_combined_pdf() {
+        const SAMPLES: u32 = 400_000;
+        const CONE_ANGLE: f64 = 0.15;
+
+        let material = Plastic::new(Vec3::from_element(0.5), 0.3, 1.5);
+        let outgoing = Unit3::new_normalize(Vec3::new(1., 0., 1.));
+        let shading_info = outside(outgoing);
+        let mut rng = Pcg64::seed_from_u64(126);
+
+        let sampled: Vec<_> = (0..SAMPLES)
+            .filter_map(|_| material.sample_bsdf(&shading_info, &mut rng))
+            .collect();
+
+        // Whichever lobe produced a direction, the sample reports the density of both combined.
+        for sample in &sampled {
+            match sample.pdf {
+                Pdf::Real(pdf) => assert_eq!(pdf, material.pdf(&shading_info, sample.dir)),
+                Pdf::Delta => panic!("rough plastic sampled a delta direction"),
+            }
+        }
+
+        // Around the mirror direction (where the specular lobe dominates), the normal and a
+        // grazing direction (where the diffuse lobe does), compare the fraction of samples
+        // landing in a small cone with the integral of the reported pdf over it.
+        let cone_solid_angle = f64::consts::TAU * (1. - CONE_ANGLE.cos());
+        for &axis in &[
+            Vec3::new(-1., 0., 1.),
+            Vec3::new(0., 0., 1.),
+            Vec3::new(0., 1., 0.6),
+        ] {
+            let axis = Unit3::new_normalize(axis);
+
+            let in_cone = sampled
+                .iter()
+                .filter(|sample| sample.dir.dot(&axis) >= CONE_ANGLE.cos())
+                .count();
+            let measured = in_cone as f64 / SAMPLES as f64;
+
+            let pdf_sum: f64 = (0..20_000)
+                .map(|_| material.pdf(&shading_info, sample_cone(axis, CONE_ANGLE, &mut rng)))
+                .sum();
+            let expected = pdf_sum / 20_000. * cone_solid_angle;
+
+            assert!(
+                (measured / expected - 1.).abs() < 0.05,
+                "around {:?}: sampled {}, pdf integrates to {}",
+                axis,
+                measured,
+                expected
+            );
+        }
+    }
+}
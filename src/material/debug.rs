@@ -0,0 +1,63 @@
+//! Utilities for checking the behavior of materials outside of a render.
+
+use rand::RngCore;
+
+use crate::geom::HitSide;
+use crate::math::{Unit3, Vec3};
+use crate::shading::ShadingInfo;
+
+use super::Material;
+
+/// Draws `n_samples` directions from the BSDF of `material` for light leaving in direction
+/// `outgoing` (in the local shading frame). Samples the material rejects are omitted.
+pub fn sample_lobe(
+    material: &dyn Material,
+    outgoing: Unit3,
+    n_samples: usize,
+    rng: &mut dyn RngCore,
+) -> Vec<Unit3> {
+    let shading_info = outside_shading_info(outgoing);
+
+    (0..n_samples)
+        .filter_map(|_| material.sample_bsdf(&shading_info, rng))
+        .map(|sample| sample.dir)
+        .collect()
+}
+
+/// Estimates the fraction of light leaving `material` in direction `outgoing` under uniform
+/// unit illumination (a "white furnace" test), by integrating the BSDF against the cosine term over
+/// `n_samples` importance-sampled directions.
+///
+/// Energy-conserving materials should never exceed 1 in any channel.
+pub fn hemispherical_reflectance(
+    material: &dyn Material,
+    outgoing: Unit3,
+    n_samples: usize,
+    rng: &mut dyn RngCore,
+) -> Vec3 {
+    if n_samples == 0 {
+        return Vec3::default();
+    }
+
+    let shading_info = outside_shading_info(outgoing);
+
+    let total: Vec3 = (0..n_samples)
+        .filter_map(|_| material.sample_bsdf(&shading_info, rng))
+        .map(|sample| sample.scaled_color())
+        .sum();
+
+    total / n_samples as f64
+}
+
+/// Returns the shading info of a hit on the outside of a surface at the origin, whose shading
+/// frame matches world space.
+fn outside_shading_info(outgoing: Unit3) -> ShadingInfo {
+    ShadingInfo {
+        side: HitSide::Outside,
+        outgoing,
+        point: Vec3::zeros(),
+        normal: Vec3::z_axis(),
+        exterior_ior: 1.,
+        min_roughness: 0.,
+    }
+}
@@ -1,9 +1,11 @@
-use nalgebra::{Unit, Vector3};
+use nalgebra::{Affine3, Unit, Vector2, Vector3};
 
 pub const EPSILON: f64 = 1e-9;
 
+pub type Vec2 = Vector2<f64>;
 pub type Vec3 = Vector3<f64>;
 pub type Unit3 = Unit<Vec3>;
+pub type Transform3 = Affine3<f64>;
 
 pub struct OrthoNormalBasis {
     u: Unit3,
@@ -12,22 +14,30 @@ pub struct OrthoNormalBasis {
 }
 
 impl OrthoNormalBasis {
-    pub fn from_wv(w: Unit3, v: Vec3) -> Self {
-        let u = Unit3::new_normalize(v.cross(&w));
+    /// Builds a basis with the specified `w`, choosing `v` to be as close as possible to the
+    /// provided vector. Returns `None` if `v` is (nearly) parallel to `w`, as it does not determine
+    /// a plane in that case.
+    pub fn from_wv(w: Unit3, v: Vec3) -> Option<Self> {
+        let u = Unit3::try_new(v.cross(&w), EPSILON)?;
         let v = Unit3::new_unchecked(w.cross(&u));
 
-        Self { u, v, w }
+        Some(Self { u, v, w })
     }
 
+    /// Builds an arbitrary basis with the specified `w`. The remaining axes vary continuously with
+    /// `w` everywhere except across the `z = 0` plane.
     pub fn from_w(w: Unit3) -> Self {
-        let other = if w.dot(&Vec3::x_axis()) > 0.9999 {
-            Vec3::y_axis()
-        } else {
-            Vec3::x_axis()
-        };
+        // Duff et al., "Building an Orthonormal Basis, Revisited" (2017).
+        let sign = 1f64.copysign(w[2]);
+        let a = -1. / (sign + w[2]);
+        let b = w[0] * w[1] * a;
 
-        let u = Unit3::new_normalize(w.cross(&other));
-        let v = Unit3::new_unchecked(w.cross(&u));
+        let u = Unit3::new_unchecked(Vec3::new(
+            1. + sign * w[0].powi(2) * a,
+            sign * b,
+            -sign * w[0],
+        ));
+        let v = Unit3::new_unchecked(Vec3::new(b, sign + w[1].powi(2) * a, -w[1]));
 
         Self { u, v, w }
     }
@@ -53,29 +63,90 @@ impl OrthoNormalBasis {
     }
 }
 
+/// What a ray is traced for, which decides the primitives it can hit (see
+/// [`Visibility`](crate::scene::Visibility)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RayKind {
+    /// A ray leaving the camera, including where it carries on unchanged past a surface that does
+    /// not scatter light.
+    Camera,
+    /// A ray testing whether light reaches a point.
+    Shadow,
+    /// Any other ray, such as one scattered by a surface.
+    Secondary,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub dir: Unit3,
+    /// The instant at which the ray is traced, within the camera's shutter interval. Moving
+    /// geometry is intersected in its position at this time.
+    pub time: f64,
+    pub kind: RayKind,
 }
 
 impl Ray {
     pub fn new(origin: Vec3, dir: Unit3) -> Self {
-        Self { origin, dir }
-    }
-
-    pub fn pointing_through(origin: Vec3, target: Vec3) -> Self {
         Self {
             origin,
-            dir: Unit3::new_normalize(target - origin),
+            dir,
+            time: 0.,
+            kind: RayKind::Secondary,
         }
     }
 
+    pub fn pointing_through(origin: Vec3, target: Vec3) -> Self {
+        Self::new(origin, Unit3::new_normalize(target - origin))
+    }
+
+    pub fn with_time(mut self, time: f64) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: RayKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
     pub fn at(&self, t: f64) -> Vec3 {
         self.origin + t * self.dir.into_inner()
     }
 }
 
+/// A camera ray along with auxiliary rays through the neighboring pixels to the right (`dx`) and
+/// below (`dy`), which together track the ray's footprint (Igehy, "Tracing Ray Differentials",
+/// 1999). This is what texture lookups need to pick a filter width.
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub ray: Ray,
+    pub dx: Ray,
+    pub dy: Ray,
+}
+
+impl RayDifferential {
+    /// Returns the change in the hit point per pixel to the right and per pixel down, for a hit
+    /// at `point` on a surface with the given `normal`. The auxiliary rays are intersected with
+    /// the surface's tangent plane, so the result is exact only for flat surfaces.
+    ///
+    /// Returns `None` if an auxiliary ray runs parallel to the plane, which happens only at
+    /// grazing angles where the footprint is unbounded anyway.
+    pub fn footprint(&self, point: Vec3, normal: Unit3) -> Option<[Vec3; 2]> {
+        let offset = |aux: &Ray| {
+            let cos = aux.dir.dot(&normal);
+            if cos == 0. {
+                return None;
+            }
+
+            let t = (point - aux.origin).dot(&normal) / cos;
+            Some(aux.at(t) - point)
+        };
+
+        Some([offset(&self.dx)?, offset(&self.dy)?])
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Aabb {
     pub min_point: Vec3,
@@ -111,10 +182,45 @@ impl Aabb {
         }
     }
 
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (min, max) = (self.min_point, self.max_point);
+
+        [
+            Vec3::new(min[0], min[1], min[2]),
+            Vec3::new(max[0], min[1], min[2]),
+            Vec3::new(min[0], max[1], min[2]),
+            Vec3::new(max[0], max[1], min[2]),
+            Vec3::new(min[0], min[1], max[2]),
+            Vec3::new(max[0], min[1], max[2]),
+            Vec3::new(min[0], max[1], max[2]),
+            Vec3::new(max[0], max[1], max[2]),
+        ]
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        (0..3).all(|i| (self.min_point[i]..=self.max_point[i]).contains(&point[i]))
+    }
+
     pub fn centroid(&self) -> Vec3 {
         (self.min_point + self.max_point) / 2.
     }
 
+    /// Returns the vector from the minimum corner to the maximum corner.
+    pub fn diagonal(&self) -> Vec3 {
+        self.max_point - self.min_point
+    }
+
+    /// Returns the total area of the box's six faces, which is zero for flat boxes.
+    pub fn surface_area(&self) -> f64 {
+        let d = self.diagonal();
+        2. * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+    }
+
+    /// Returns the axis along which the box is longest, preferring the lower axis on ties.
+    pub fn max_extent_axis(&self) -> usize {
+        self.diagonal().imax()
+    }
+
     pub fn hit(&self, ray: &Ray, mut t_min: f64, mut t_max: f64) -> bool {
         for i in 0..3 {
             let inv_d = 1. / ray.dir[i];
@@ -141,3 +247,20 @@ impl Aabb {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surface_area() {
+        let unit = Aabb::new(Vec3::default(), Vec3::from_element(1.));
+        assert_eq!(unit.surface_area(), 6.);
+
+        let shifted = Aabb::new(Vec3::new(-1., 2., 3.), Vec3::new(1., 5., 7.));
+        assert_eq!(shifted.surface_area(), 2. * (2. * 3. + 3. * 4. + 4. * 2.));
+
+        let flat = Aabb::new(Vec3::default(), Vec3::new(2., 3., 0.));
+        assert_eq!(flat.surface_area(), 12.);
+    }
+}
@@ -0,0 +1,121 @@
+use std::f64;
+
+use rand::{Rng, RngCore};
+
+use crate::math::{Unit3, Vec3};
+use crate::shading;
+
+pub use self::albedo::{albedo_table, AlbedoTable};
+
+mod albedo;
+
+/// Roughness below which microfacet lobes are treated as perfectly smooth. The GGX distribution
+/// becomes too sharply peaked to evaluate reliably well before it reaches zero roughness.
+pub const MIN_ROUGHNESS: f64 = 1e-3;
+
+/// The GGX (Trowbridge-Reitz) microfacet distribution with Smith shadowing-masking, expressed in
+/// the local shading frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Ggx {
+    alpha: f64,
+}
+
+impl Ggx {
+    pub fn new(roughness: f64) -> Self {
+        Self {
+            alpha: roughness.max(MIN_ROUGHNESS).powi(2),
+        }
+    }
+
+    /// Creates a distribution with the specified roughness, or `None` if the surface should be
+    /// treated as perfectly smooth instead.
+    pub fn new_rough(roughness: f64) -> Option<Self> {
+        if roughness < MIN_ROUGHNESS {
+            None
+        } else {
+            Some(Self::new(roughness))
+        }
+    }
+
+    /// Returns the roughness the distribution was created with (after clamping).
+    pub fn roughness(&self) -> f64 {
+        self.alpha.sqrt()
+    }
+
+    pub fn d(&self, half: Unit3) -> f64 {
+        let cos_theta = shading::cos_theta(half);
+        if cos_theta <= 0. {
+            return 0.;
+        }
+
+        let alpha2 = self.alpha.powi(2);
+        alpha2 / (f64::consts::PI * (cos_theta.powi(2) * (alpha2 - 1.) + 1.).powi(2))
+    }
+
+    pub fn g(&self, outgoing: Unit3, incoming: Unit3) -> f64 {
+        1. / (1. + self.lambda(outgoing) + self.lambda(incoming))
+    }
+
+    /// Samples a microfacet normal proportionally to `d(half) * cos_theta(half)`.
+    pub fn sample_half(&self, rng: &mut dyn RngCore) -> Unit3 {
+        let u: f64 = rng.gen();
+        let phi = rng.gen_range(0.0..f64::consts::TAU);
+
+        let tan2_theta = self.alpha.powi(2) * u / (1. - u);
+        let cos_theta = 1. / (1. + tan2_theta).sqrt();
+        let sin_theta = (1. - cos_theta.powi(2)).max(0.).sqrt();
+
+        Unit3::new_normalize(Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+
+    /// Returns the density with which reflecting `outgoing` about normals drawn by `sample_half`
+    /// produces `incoming`.
+    pub fn reflection_pdf(&self, outgoing: Unit3, incoming: Unit3) -> f64 {
+        let half = match reflection_half(outgoing, incoming) {
+            Some(half) => half,
+            None => return 0.,
+        };
+
+        self.d(half) * shading::cos_theta(half) / (4. * outgoing.dot(&half).abs())
+    }
+
+    fn lambda(&self, dir: Unit3) -> f64 {
+        let cos2_theta = shading::cos_theta(dir).powi(2);
+        if cos2_theta == 0. {
+            return f64::INFINITY;
+        }
+
+        let tan2_theta = (1. - cos2_theta).max(0.) / cos2_theta;
+        ((1. + self.alpha.powi(2) * tan2_theta).sqrt() - 1.) / 2.
+    }
+}
+
+/// Widens `distr` (with `None` meaning perfectly smooth) to a roughness of at least
+/// `min_roughness`, as requested by [`ShadingInfo::min_roughness`].
+///
+/// [`ShadingInfo::min_roughness`]: shading::ShadingInfo::min_roughness
+pub fn regularize(distr: Option<Ggx>, min_roughness: f64) -> Option<Ggx> {
+    match distr {
+        Some(distr) if distr.roughness() >= min_roughness => Some(distr),
+        _ => Ggx::new_rough(min_roughness).or(distr),
+    }
+}
+
+/// Returns the normal about which `outgoing` reflects into `incoming`, facing up.
+pub fn reflection_half(outgoing: Unit3, incoming: Unit3) -> Option<Unit3> {
+    let half = *outgoing + *incoming;
+    if half.norm_squared() == 0. {
+        return None;
+    }
+
+    let half = Unit3::new_normalize(half);
+    Some(if half[2] < 0. { -half } else { half })
+}
+
+pub fn reflect(outgoing: Unit3, normal: Unit3) -> Unit3 {
+    Unit3::new_unchecked(2. * outgoing.dot(&normal) * *normal - *outgoing)
+}
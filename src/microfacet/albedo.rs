@@ -0,0 +1,129 @@
+use std::sync::OnceLock;
+
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+use crate::math::{Unit3, Vec3};
+use crate::shading;
+
+use super::{reflect, Ggx};
+
+/// Number of table entries along each of the roughness and cosine axes.
+const RESOLUTION: usize = 32;
+
+const SAMPLES_PER_ENTRY: usize = 4096;
+
+/// Fixed seed used when estimating albedos, so that every run builds exactly the same table.
+const SEED: u64 = 0x6b75_6c6c_6120_636f;
+
+/// Directional albedos of the single-scattering GGX BRDF with perfect (unit) Fresnel reflectance,
+/// tabulated over roughness and the cosine of the outgoing direction.
+///
+/// These are the quantities needed by the Kulla-Conty multiple-scattering approximation: `E(μ)`,
+/// the energy reflected for light leaving at cosine `μ`, and `E_avg`, its cosine-weighted average
+/// over the hemisphere. The energy missing from `1 - E` is what the microfacet model loses by
+/// ignoring light bouncing between microfacets more than once.
+pub struct AlbedoTable {
+    /// `albedo[r][c]` holds `E` at roughness `r / (RESOLUTION - 1)` and cosine
+    /// `c / (RESOLUTION - 1)`.
+    albedo: Vec<[f64; RESOLUTION]>,
+    average: [f64; RESOLUTION],
+}
+
+impl AlbedoTable {
+    fn generate() -> Self {
+        let mut rng = Pcg64::seed_from_u64(SEED);
+
+        let albedo: Vec<_> = (0..RESOLUTION)
+            .map(|r| {
+                let mut row = [1.; RESOLUTION];
+
+                // Smooth surfaces reflect everything in a single bounce, so leave the first row at
+                // exactly 1 to make the compensation vanish there.
+                if r > 0 {
+                    let distr = Ggx::new(axis_value(r));
+                    for (c, entry) in row.iter_mut().enumerate() {
+                        // Grazing directions are numerically hopeless; take a tiny cosine instead.
+                        let cos_theta = axis_value(c).max(1e-3);
+                        *entry = estimate_albedo(&distr, cos_theta, &mut rng);
+                    }
+                }
+
+                row
+            })
+            .collect();
+
+        let mut average = [0.; RESOLUTION];
+        for (avg, row) in average.iter_mut().zip(&albedo) {
+            // E_avg = 2∫E(μ)μ dμ, integrated with the trapezoid rule.
+            let step = 1. / (RESOLUTION - 1) as f64;
+            let integrand = |c: usize| row[c] * axis_value(c);
+
+            *avg = 2.
+                * step
+                * ((1..RESOLUTION - 1).map(integrand).sum::<f64>()
+                    + (integrand(0) + integrand(RESOLUTION - 1)) / 2.);
+        }
+
+        Self { albedo, average }
+    }
+
+    /// Returns `E(cos_theta)` for `distr`.
+    pub fn albedo(&self, distr: &Ggx, cos_theta: f64) -> f64 {
+        let (r0, r1, rt) = axis_lerp(distr.roughness());
+        let (c0, c1, ct) = axis_lerp(cos_theta);
+
+        let row = |r: usize| lerp(self.albedo[r][c0], self.albedo[r][c1], ct);
+        lerp(row(r0), row(r1), rt)
+    }
+
+    /// Returns `E_avg` for `distr`.
+    pub fn average_albedo(&self, distr: &Ggx) -> f64 {
+        let (r0, r1, rt) = axis_lerp(distr.roughness());
+        lerp(self.average[r0], self.average[r1], rt)
+    }
+}
+
+/// Returns the shared albedo table, generating it on first use.
+pub fn albedo_table() -> &'static AlbedoTable {
+    static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+    TABLE.get_or_init(AlbedoTable::generate)
+}
+
+/// Estimates `E(cos_theta)` by importance sampling microfacet normals.
+fn estimate_albedo(distr: &Ggx, cos_theta: f64, rng: &mut Pcg64) -> f64 {
+    let outgoing = Unit3::new_normalize(Vec3::new((1. - cos_theta.powi(2)).sqrt(), 0., cos_theta));
+
+    let total: f64 = (0..SAMPLES_PER_ENTRY)
+        .map(|_| {
+            let half = distr.sample_half(rng);
+            let incoming = reflect(outgoing, half);
+
+            if shading::cos_theta(incoming) <= 0. {
+                return 0.;
+            }
+
+            // f * cos(incoming) / pdf, with the D terms cancelling.
+            distr.g(outgoing, incoming) * outgoing.dot(&half).abs()
+                / (cos_theta * shading::cos_theta(half))
+        })
+        .sum();
+
+    total / SAMPLES_PER_ENTRY as f64
+}
+
+fn axis_value(idx: usize) -> f64 {
+    idx as f64 / (RESOLUTION - 1) as f64
+}
+
+/// Returns the indices of the table entries surrounding `x` in `[0, 1]`, and the interpolation
+/// parameter between them.
+fn axis_lerp(x: f64) -> (usize, usize, f64) {
+    let pos = x.clamp(0., 1.) * (RESOLUTION - 1) as f64;
+    let lo = (pos.floor() as usize).min(RESOLUTION - 2);
+    (lo, lo + 1, pos - lo as f64)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + t * (b - a)
+}
@@ -0,0 +1,126 @@
+//! Contact sheets tiling several views of a scene into one image.
+
+use std::error::Error;
+use std::f64;
+use std::fmt;
+
+use nalgebra::Rotation3;
+
+use crate::img::Image;
+use crate::math::{OrthoNormalBasis, Unit3, Vec3};
+use crate::render::{self, Camera, CameraError, CameraOptions, RenderOptions};
+use crate::scene::Scene;
+
+/// The grid into which a montage's views are tiled.
+#[derive(Debug, Clone, Copy)]
+pub struct MontageLayout {
+    pub columns: u32,
+    pub rows: u32,
+    /// Size of each view, overriding the pixel size of its camera.
+    pub thumbnail_width: u32,
+    pub thumbnail_height: u32,
+}
+
+impl MontageLayout {
+    pub fn cell_count(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+}
+
+#[derive(Debug)]
+pub enum MontageError {
+    TooManyViews { views: usize, cells: usize },
+    Camera(usize, CameraError),
+}
+
+impl fmt::Display for MontageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MontageError::TooManyViews { views, cells } => {
+                write!(f, "{} views do not fit in a grid of {} cells", views, cells)
+            }
+            MontageError::Camera(view, err) => write!(f, "view {}: {}", view, err),
+        }
+    }
+}
+
+impl Error for MontageError {}
+
+/// Renders `scene` from each of `views` at the thumbnail size of `layout`, tiling the results row
+/// by row from the top left. Cells left over once the views run out are black.
+///
+/// Views are told apart only by their position in the grid. They are all rendered with the same
+/// `opts`, and the montage is meant to be tone mapped as a whole so that they share an exposure.
+pub fn render_montage(
+    scene: &Scene,
+    views: &[CameraOptions],
+    layout: &MontageLayout,
+    opts: &RenderOptions,
+) -> Result<Image, MontageError> {
+    if views.len() > layout.cell_count() {
+        return Err(MontageError::TooManyViews {
+            views: views.len(),
+            cells: layout.cell_count(),
+        });
+    }
+
+    let width = layout.columns * layout.thumbnail_width;
+    let height = layout.rows * layout.thumbnail_height;
+    let mut montage = Image::new(
+        width,
+        height,
+        vec![Vec3::default(); (width * height) as usize],
+    );
+
+    for (index, view) in views.iter().enumerate() {
+        let camera = Camera::new(&CameraOptions {
+            pixel_width: layout.thumbnail_width,
+            pixel_height: layout.thumbnail_height,
+            ..*view
+        })
+        .map_err(|err| MontageError::Camera(index, err))?;
+
+        let mut pixels =
+            vec![Vec3::default(); (layout.thumbnail_width * layout.thumbnail_height) as usize];
+        render::render_to(&mut pixels, scene, &camera, opts);
+
+        let thumbnail = Image::new(layout.thumbnail_width, layout.thumbnail_height, pixels);
+        let (column, row) = (index as u32 % layout.columns, index as u32 / layout.columns);
+        montage.paste(
+            &thumbnail,
+            column * layout.thumbnail_width,
+            row * layout.thumbnail_height,
+        );
+    }
+
+    Ok(montage)
+}
+
+/// Returns front, side, top and perspective views of the point `base.look_at`, in that order.
+///
+/// The perspective view is `base` itself. The others keep its distance from the point, looking at
+/// it horizontally (from the side `base` views it from, and from a quarter turn around it) and
+/// straight down along `base.vup`.
+pub fn standard_views(base: &CameraOptions) -> [CameraOptions; 4] {
+    let up = Unit3::new_normalize(base.vup);
+    let offset = base.origin - base.look_at;
+    let distance = offset.norm();
+
+    let horizontal = offset - offset.dot(&up) * *up;
+    let front =
+        Unit3::try_new(horizontal, 1e-9).unwrap_or_else(|| OrthoNormalBasis::from_w(up).u());
+    let side = Rotation3::from_axis_angle(&up, f64::consts::FRAC_PI_2) * front;
+
+    let looking_from = |dir: Vec3, vup: Vec3| CameraOptions {
+        origin: base.look_at + distance * dir,
+        vup,
+        ..*base
+    };
+
+    [
+        looking_from(*front, *up),
+        looking_from(*side, *up),
+        looking_from(*up, -*front),
+        *base,
+    ]
+}
@@ -0,0 +1,362 @@
+//! Loading point clouds from PLY files and turning them into scene geometry.
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, fs, io, str};
+
+use crate::geom::Sphere;
+use crate::img;
+use crate::material::Lambertian;
+use crate::math::Vec3;
+use crate::scene::SceneBuilder;
+
+/// Color given to points in files that do not specify one.
+const DEFAULT_COLOR: Vec3 = Vec3::new(0.8, 0.8, 0.8);
+
+#[derive(Debug)]
+pub enum PlyError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlyError::Io(err) => write!(f, "{}", err),
+            PlyError::Format(msg) => write!(f, "malformed PLY file: {}", msg),
+        }
+    }
+}
+
+impl Error for PlyError {}
+
+impl From<io::Error> for PlyError {
+    fn from(err: io::Error) -> Self {
+        PlyError::Io(err)
+    }
+}
+
+fn format_err<T>(msg: impl Into<String>) -> Result<T, PlyError> {
+    Err(PlyError::Format(msg.into()))
+}
+
+/// Loads the vertices of the PLY file at `path` as `(position, color)` pairs.
+///
+/// ASCII and both binary encodings are supported. Colors are read from the `red`, `green` and
+/// `blue` properties, which are normalized to `[0, 1]` when stored as integers and decoded from
+/// sRGB to linear; points without colors are light gray.
+pub fn load_ply(path: &Path) -> Result<Vec<(Vec3, Vec3)>, PlyError> {
+    parse_ply(&fs::read(path)?)
+}
+
+/// Adds every point in `points` to `builder` as a sphere of radius `radius`, with a diffuse
+/// material of the point's color.
+pub fn add_point_cloud(builder: &mut SceneBuilder, points: &[(Vec3, Vec3)], radius: f64) {
+    for &(position, color) in points {
+        builder.add_primitive(
+            Sphere::new(position, radius),
+            Arc::new(Lambertian::new(color)),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self, PlyError> {
+        Ok(match name {
+            "char" | "int8" => ScalarType::I8,
+            "uchar" | "uint8" => ScalarType::U8,
+            "short" | "int16" => ScalarType::I16,
+            "ushort" | "uint16" => ScalarType::U16,
+            "int" | "int32" => ScalarType::I32,
+            "uint" | "uint32" => ScalarType::U32,
+            "float" | "float32" => ScalarType::F32,
+            "double" | "float64" => ScalarType::F64,
+            _ => return format_err(format!("unknown type '{}'", name)),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+
+    /// Returns the scale that maps the type's range onto `[0, 1]` for color channels.
+    fn color_scale(&self) -> f64 {
+        match self {
+            ScalarType::U8 => 1. / 255.,
+            ScalarType::U16 => 1. / 65535.,
+            _ => 1.,
+        }
+    }
+}
+
+enum PropertyType {
+    Scalar(ScalarType),
+    List { count: ScalarType, item: ScalarType },
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+struct Header {
+    encoding: Encoding,
+    elements: Vec<Element>,
+}
+
+fn parse_ply(data: &[u8]) -> Result<Vec<(Vec3, Vec3)>, PlyError> {
+    const END_HEADER: &[u8] = b"end_header";
+
+    let header_end = data
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .ok_or(PlyError::Format("missing end_header".into()))?;
+
+    let body_start = data[header_end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(data.len(), |pos| header_end + pos + 1);
+
+    let header_text =
+        str::from_utf8(&data[..header_end]).or_else(|_| format_err("header is not valid text"))?;
+    let header = parse_header(header_text)?;
+
+    let mut reader: Box<dyn ValueReader + '_> = match header.encoding {
+        Encoding::Ascii => Box::new(AsciiReader {
+            tokens: str::from_utf8(&data[body_start..])
+                .or_else(|_| format_err("body is not valid text"))?
+                .split_ascii_whitespace(),
+        }),
+        Encoding::BinaryLittleEndian => Box::new(BinaryReader {
+            data: &data[body_start..],
+            big_endian: false,
+        }),
+        Encoding::BinaryBigEndian => Box::new(BinaryReader {
+            data: &data[body_start..],
+            big_endian: true,
+        }),
+    };
+
+    let mut points = Vec::new();
+
+    for element in &header.elements {
+        if element.name != "vertex" {
+            for _ in 0..element.count {
+                for property in &element.properties {
+                    read_property(&mut *reader, &property.ty)?;
+                }
+            }
+            continue;
+        }
+
+        let index_of = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let position_indices = match (index_of("x"), index_of("y"), index_of("z")) {
+            (Some(x), Some(y), Some(z)) => [x, y, z],
+            _ => return format_err("vertices lack x, y and z"),
+        };
+        let color_indices = match (index_of("red"), index_of("green"), index_of("blue")) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+
+        points.reserve(element.count);
+        let mut values = vec![0.; element.properties.len()];
+
+        for _ in 0..element.count {
+            for (value, property) in values.iter_mut().zip(&element.properties) {
+                *value = read_property(&mut *reader, &property.ty)?;
+            }
+
+            let position = Vec3::from_fn(|i, _| values[position_indices[i]]);
+            let color = match color_indices {
+                Some(indices) => img::srgb_to_linear(Vec3::from_fn(|i, _| {
+                    let property = &element.properties[indices[i]];
+                    let scale = match property.ty {
+                        PropertyType::Scalar(ty) => ty.color_scale(),
+                        PropertyType::List { .. } => 1.,
+                    };
+                    values[indices[i]] * scale
+                })),
+                None => DEFAULT_COLOR,
+            };
+
+            points.push((position, color));
+        }
+    }
+
+    Ok(points)
+}
+
+fn parse_header(text: &str) -> Result<Header, PlyError> {
+    let mut lines = text.lines().map(str::trim);
+
+    if lines.next() != Some("ply") {
+        return format_err("missing 'ply' magic");
+    }
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+
+    for line in lines {
+        let mut words = line.split_ascii_whitespace();
+
+        match words.next() {
+            Some("format") => {
+                encoding = Some(match words.next() {
+                    Some("ascii") => Encoding::Ascii,
+                    Some("binary_little_endian") => Encoding::BinaryLittleEndian,
+                    Some("binary_big_endian") => Encoding::BinaryBigEndian,
+                    other => {
+                        return format_err(format!("unknown format '{}'", other.unwrap_or("")))
+                    }
+                });
+            }
+            Some("element") => {
+                let (name, count) = match (words.next(), words.next()) {
+                    (Some(name), Some(count)) => (name, count),
+                    _ => return format_err(format!("bad element line '{}'", line)),
+                };
+
+                elements.push(Element {
+                    name: name.to_owned(),
+                    count: count
+                        .parse()
+                        .or_else(|_| format_err(format!("bad element count '{}'", count)))?,
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let element = match elements.last_mut() {
+                    Some(element) => element,
+                    None => return format_err("property outside of element"),
+                };
+
+                let words: Vec<_> = words.collect();
+                let (ty, name) = match words.as_slice() {
+                    ["list", count, item, name] => (
+                        PropertyType::List {
+                            count: ScalarType::parse(count)?,
+                            item: ScalarType::parse(item)?,
+                        },
+                        name,
+                    ),
+                    [ty, name] => (PropertyType::Scalar(ScalarType::parse(ty)?), name),
+                    _ => return format_err(format!("bad property line '{}'", line)),
+                };
+
+                element.properties.push(Property {
+                    name: (*name).to_owned(),
+                    ty,
+                });
+            }
+            Some("comment") | Some("obj_info") | None => {}
+            Some(other) => return format_err(format!("unknown header keyword '{}'", other)),
+        }
+    }
+
+    Ok(Header {
+        encoding: encoding.ok_or(PlyError::Format("missing format".into()))?,
+        elements,
+    })
+}
+
+/// Reads a property's value, returning the value itself for scalars. Lists are consumed and
+/// yield 0, as no list is needed for points.
+fn read_property(reader: &mut dyn ValueReader, ty: &PropertyType) -> Result<f64, PlyError> {
+    match *ty {
+        PropertyType::Scalar(ty) => reader.read(ty),
+        PropertyType::List { count, item } => {
+            let count = reader.read(count)? as usize;
+            for _ in 0..count {
+                reader.read(item)?;
+            }
+            Ok(0.)
+        }
+    }
+}
+
+trait ValueReader {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, PlyError>;
+}
+
+struct AsciiReader<'a> {
+    tokens: str::SplitAsciiWhitespace<'a>,
+}
+
+impl ValueReader for AsciiReader<'_> {
+    fn read(&mut self, _ty: ScalarType) -> Result<f64, PlyError> {
+        let token = match self.tokens.next() {
+            Some(token) => token,
+            None => return format_err("unexpected end of data"),
+        };
+
+        token
+            .parse()
+            .or_else(|_| format_err(format!("bad value '{}'", token)))
+    }
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl ValueReader for BinaryReader<'_> {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, PlyError> {
+        let size = ty.size();
+        if self.data.len() < size {
+            return format_err("unexpected end of data");
+        }
+
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        let mut buf = [0; 8];
+        buf[..size].copy_from_slice(bytes);
+        if self.big_endian {
+            buf[..size].reverse();
+        }
+
+        Ok(match ty {
+            ScalarType::I8 => buf[0] as i8 as f64,
+            ScalarType::U8 => buf[0] as f64,
+            ScalarType::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            ScalarType::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            ScalarType::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            ScalarType::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            ScalarType::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            ScalarType::F64 => f64::from_le_bytes(buf),
+        })
+    }
+}
@@ -1,25 +1,68 @@
-use std::{f64, iter};
+use std::cell::RefCell;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::{f64, fmt};
 
-use rand::prelude::SliceRandom;
-use rand::{Rng, RngCore};
+use rand::{Rng, RngCore, SeedableRng};
 use rand_distr::{Distribution, UnitDisc};
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use rand_pcg::Pcg64;
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+};
+use rayon::slice::ParallelSliceMut;
 
+use crate::distr;
+use crate::geom::HitSide;
+use crate::img::{luminance, Image};
 use crate::light::Light;
-use crate::math::{OrthoNormalBasis, Ray, Unit3, Vec3, EPSILON};
+use crate::math::{OrthoNormalBasis, Ray, RayDifferential, RayKind, Unit3, Vec3, EPSILON};
+use crate::microfacet::MIN_ROUGHNESS;
 use crate::scene::{PrimitiveHit, Scene};
-use crate::shading::{Pdf, ShadingInfo};
+use crate::shading::{same_hemisphere, Pdf, ShadingInfo};
 
+pub use self::adaptive::{render_adaptive, AdaptiveOptions, ErrorMetric};
+pub use self::alpha::render_with_alpha;
+pub use self::aperture::ApertureMask;
+pub use self::layers::{render_layers, Layer, MultiLayerImage};
+pub use self::photon::{render_photon_mapped, PhotonOptions};
+pub use self::tiles::{render_tiles_iter, Tile};
+
+use self::cmj::{MultiJitteredRng, MultiJitteredSample};
+use self::media::{Interface, MediumStack};
+use self::photon::PhotonMap;
+
+mod adaptive;
+mod alpha;
+mod aperture;
+mod cmj;
+mod halton;
+mod layers;
+mod manifold;
+mod media;
+mod photon;
+mod tiles;
+
+#[derive(Debug, Clone, Copy)]
 pub struct CameraOptions {
     pub pixel_width: u32,
     pub pixel_height: u32,
 
     pub vert_fov: f64,
+    /// Diameter of the lens. Zero makes a pinhole camera, with everything in focus.
     pub aperture: f64,
 
     pub origin: Vec3,
     pub look_at: Vec3,
     pub vup: Vec3,
+
+    /// A point that should be in perfect focus. If this is `None`, `look_at` is used instead.
+    pub focus_target: Option<Vec3>,
+
+    /// Distance beyond which geometry is invisible to camera rays, as if it were not there.
+    /// Secondary rays are not limited.
+    pub far: f64,
 }
 
 pub struct Camera {
@@ -31,7 +74,17 @@ pub struct Camera {
     horiz: Vec3,
     vert: Vec3,
 
+    /// Width and height of the viewport at unit distance from the camera.
+    viewport: [f64; 2],
+
     lens_radius: f64,
+    aperture_mask: Option<ApertureMask>,
+    /// Times at which the shutter opens and closes.
+    shutter: (f64, f64),
+    /// Coefficients of `r²` and `r⁴` in the radial distortion polynomial.
+    distortion: [f64; 2],
+    vignetting: bool,
+    far: f64,
 
     pixel_width: u32,
     pixel_height: u32,
@@ -40,16 +93,87 @@ pub struct Camera {
     inv_height: f64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraError {
+    EmptyImage,
+    InvalidFov(f64),
+    InvalidAperture(f64),
+    InvalidFarPlane(f64),
+    DegenerateViewDirection,
+    DegenerateUpVector,
+    FocusBehindCamera,
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CameraError::EmptyImage => write!(f, "image dimensions must be nonzero"),
+            CameraError::InvalidFov(fov) => write!(
+                f,
+                "vertical field of view must lie strictly between 0 and 180 degrees (got {})",
+                fov
+            ),
+            CameraError::InvalidAperture(aperture) => {
+                write!(
+                    f,
+                    "aperture must be a non-negative number (got {})",
+                    aperture
+                )
+            }
+            CameraError::InvalidFarPlane(far) => {
+                write!(f, "far plane distance must be positive (got {})", far)
+            }
+            CameraError::DegenerateViewDirection => {
+                write!(f, "camera origin and look-at point coincide")
+            }
+            CameraError::DegenerateUpVector => {
+                write!(f, "up vector is zero or parallel to the view direction")
+            }
+            CameraError::FocusBehindCamera => write!(f, "focus target lies behind the camera"),
+        }
+    }
+}
+
+impl Error for CameraError {}
+
 impl Camera {
-    pub fn new(opts: &CameraOptions) -> Self {
+    pub fn new(opts: &CameraOptions) -> Result<Self, CameraError> {
+        if opts.pixel_width == 0 || opts.pixel_height == 0 {
+            return Err(CameraError::EmptyImage);
+        }
+
+        if !(opts.vert_fov > 0. && opts.vert_fov < 180.) {
+            return Err(CameraError::InvalidFov(opts.vert_fov));
+        }
+
+        if opts.aperture.is_nan() || opts.aperture < 0. {
+            return Err(CameraError::InvalidAperture(opts.aperture));
+        }
+
+        if opts.far.is_nan() || opts.far <= 0. {
+            return Err(CameraError::InvalidFarPlane(opts.far));
+        }
+
         let aspect_ratio = opts.pixel_width as f64 / opts.pixel_height as f64;
 
         let viewport_height = 2. * (opts.vert_fov * f64::consts::PI / 360.).tan();
         let viewport_width = aspect_ratio * viewport_height;
 
-        let (w, focus_dist) = Unit3::new_and_get(opts.origin - opts.look_at);
+        let (w, look_dist) = Unit3::try_new_and_get(opts.origin - opts.look_at, EPSILON)
+            .ok_or(CameraError::DegenerateViewDirection)?;
+
+        // The focal plane is perpendicular to the view direction, so only the depth of the target
+        // along it matters.
+        let focus_dist = opts
+            .focus_target
+            .map_or(look_dist, |target| (opts.origin - target).dot(&w));
+
+        if focus_dist <= 0. {
+            return Err(CameraError::FocusBehindCamera);
+        }
 
-        let basis = OrthoNormalBasis::from_wv(w, opts.vup);
+        let basis =
+            OrthoNormalBasis::from_wv(w, opts.vup).ok_or(CameraError::DegenerateUpVector)?;
 
         let horiz = focus_dist * viewport_width * *basis.u();
         let vert = focus_dist * viewport_height * *basis.v();
@@ -58,7 +182,7 @@ impl Camera {
                 focus_dist * Vec3::new(viewport_width / 2., viewport_height / 2., 1.),
             );
 
-        Self {
+        Ok(Self {
             origin: opts.origin,
             bottom_left,
 
@@ -67,34 +191,303 @@ impl Camera {
             horiz,
             vert,
 
+            viewport: [viewport_width, viewport_height],
+
             lens_radius: opts.aperture / 2.,
+            aperture_mask: None,
+            shutter: (0., 0.),
+            distortion: [0., 0.],
+            vignetting: false,
+            far: opts.far,
 
             pixel_width: opts.pixel_width,
             pixel_height: opts.pixel_height,
 
             inv_width: 1. / opts.pixel_width as f64,
             inv_height: 1. / opts.pixel_height as f64,
+        })
+    }
+
+    /// Shapes the aperture by `mask` instead of leaving it circular. The mask spans the square
+    /// circumscribing the lens, whose size is still set by [`CameraOptions::aperture`]; it has no
+    /// effect on pinhole cameras.
+    pub fn with_aperture_mask(mut self, mask: ApertureMask) -> Self {
+        self.aperture_mask = Some(mask);
+        self
+    }
+
+    /// Keeps the shutter open from time `open` to time `close`, spreading camera rays uniformly
+    /// over the interval so that moving geometry blurs. Moving geometry is keyed at times 0 and 1.
+    /// By default, the shutter is instantaneous at time 0.
+    pub fn with_shutter(mut self, open: f64, close: f64) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+
+    /// Distorts the image radially, as real lenses do: the point at distance `r` from the center of
+    /// the image (with `r = 1` at the corners) shows what an ideal lens would show at distance
+    /// `r (1 + k1 r² + k2 r⁴)`. Positive coefficients give barrel distortion, bowing straight lines
+    /// outward, and negative ones pincushion distortion. Coefficients so negative that the factor
+    /// drops to zero within the image fold it over itself.
+    pub fn with_distortion(mut self, k1: f64, k2: f64) -> Self {
+        self.distortion = [k1, k2];
+        self
+    }
+
+    /// Darkens the image away from its center by the natural vignetting of a lens, the `cos⁴`
+    /// falloff of the light reaching the film at an angle to the optical axis (see
+    /// [`Camera::vignetting`]).
+    pub fn with_vignetting(mut self) -> Self {
+        self.vignetting = true;
+        self
+    }
+
+    /// Returns the factor by which the final value of the given pixel is scaled to simulate
+    /// vignetting: `cos⁴` of the angle between the optical axis and the ray through the pixel's
+    /// center, or 1 if the camera has no vignetting.
+    pub fn vignetting(&self, pixel_x: u32, pixel_y: u32) -> f64 {
+        if !self.vignetting {
+            return 1.;
+        }
+
+        let [u, v] = self.distort([
+            (pixel_x as f64 + 0.5) * self.inv_width,
+            1. - (pixel_y as f64 + 0.5) * self.inv_height,
+        ]);
+
+        let tan2_theta =
+            ((u - 0.5) * self.viewport[0]).powi(2) + ((v - 0.5) * self.viewport[1]).powi(2);
+        (1. + tan2_theta).recip().powi(2)
+    }
+
+    /// Samples an instant at which the shutter is open.
+    pub fn sample_time(&self, rng: &mut dyn RngCore) -> f64 {
+        let (open, close) = self.shutter;
+        if open < close {
+            rng.gen_range(open..close)
+        } else {
+            open
         }
     }
 
     pub fn cast_ray(&self, pixel_x: u32, pixel_y: u32, rng: &mut dyn RngCore) -> Ray {
-        let pixel_x = pixel_x as f64 + rng.gen::<f64>();
-        let pixel_y = pixel_y as f64 + rng.gen::<f64>();
+        let pixel_offset = [rng.gen::<f64>(), rng.gen::<f64>()];
+        let lens_point = self.sample_lens_point(rng);
+
+        self.ray_through(pixel_x, pixel_y, pixel_offset, lens_point)
+            .with_time(self.sample_time(rng))
+    }
+
+    /// Like [`Camera::cast_ray`], but also casts the auxiliary rays through the same point of the
+    /// next pixels to the right and below, leaving the lens at the same point and at the same
+    /// time, so that the footprint of the ray can be tracked.
+    pub fn cast_ray_differential(
+        &self,
+        pixel_x: u32,
+        pixel_y: u32,
+        rng: &mut dyn RngCore,
+    ) -> RayDifferential {
+        let pixel_offset = [rng.gen::<f64>(), rng.gen::<f64>()];
+        let lens_point = self.sample_lens_point(rng);
+        let time = self.sample_time(rng);
+
+        let cast = |pixel_offset| {
+            self.ray_through(pixel_x, pixel_y, pixel_offset, lens_point)
+                .with_time(time)
+        };
+
+        RayDifferential {
+            ray: cast(pixel_offset),
+            dx: cast([pixel_offset[0] + 1., pixel_offset[1]]),
+            dy: cast([pixel_offset[0], pixel_offset[1] + 1.]),
+        }
+    }
+
+    /// Like [`Camera::cast_ray`], but places the ray as `sample` of the pixel's set of samples,
+    /// which are spread over a jittered grid both within the pixel and on the lens.
+    ///
+    /// The largest square number of samples in the set is stratified, so the set should have a
+    /// square size for best results; any remaining samples are placed independently. The lens
+    /// strata are shuffled relative to the pixel strata, differently in every pixel according to
+    /// its position and `scramble` (as with [`Camera::cast_halton_ray`]), so that the two do not
+    /// correlate.
+    pub fn cast_stratified_ray(
+        &self,
+        pixel_x: u32,
+        pixel_y: u32,
+        sample: PixelSample,
+        scramble: u64,
+        rng: &mut dyn RngCore,
+    ) -> Ray {
+        let grid_size = sample.grid_size();
+        let stratum_count = grid_size * grid_size;
+        if sample.index >= stratum_count {
+            return self.cast_ray(pixel_x, pixel_y, rng);
+        }
+
+        let pixel_offset = jitter_stratum(sample.index, grid_size, rng);
+
+        let lens_point = if self.lens_radius > 0. {
+            let key = pixel_key(scramble, pixel_x, pixel_y) as u32;
+            let stratum = permute(sample.index, stratum_count, key);
+            self.lens_point_from(jitter_stratum(stratum, grid_size, rng))
+        } else {
+            [0., 0.]
+        };
+
+        self.ray_through(pixel_x, pixel_y, pixel_offset, lens_point)
+            .with_time(self.sample_time(rng))
+    }
+
+    /// Like [`Camera::cast_ray`], but places the ray within the pixel and on the lens at point
+    /// `index` of a scrambled Halton sequence. The sequence is low-discrepancy, covering both
+    /// evenly for any number of samples, so it also suits renders that add samples progressively.
+    ///
+    /// Every pixel scrambles the sequence differently, according to its position and `scramble`,
+    /// so that neighboring pixels do not repeat the same pattern; renders meant to be independent
+    /// should use different values of `scramble`. Dimensions beyond those of the sequence (the
+    /// shutter time, for now) are sampled from `rng` instead.
+    pub fn cast_halton_ray(
+        &self,
+        pixel_x: u32,
+        pixel_y: u32,
+        index: u32,
+        scramble: u64,
+        rng: &mut dyn RngCore,
+    ) -> Ray {
+        let pixel_key = pixel_key(scramble, pixel_x, pixel_y);
+
+        let mut dimension = 0;
+        let mut next = |rng: &mut dyn RngCore| {
+            let value = halton::scrambled_halton(
+                dimension,
+                index,
+                stream_seed(pixel_key, dimension as u64),
+            )
+            .unwrap_or_else(|| rng.gen());
+            dimension += 1;
+            value
+        };
+
+        let pixel_offset = [next(rng), next(rng)];
+
+        let lens_point = if self.lens_radius > 0. {
+            let u = [next(rng), next(rng)];
+            self.lens_point_from(u)
+        } else {
+            [0., 0.]
+        };
+
+        let (open, close) = self.shutter;
+        let time = if open < close {
+            open + next(rng) * (close - open)
+        } else {
+            open
+        };
+
+        self.ray_through(pixel_x, pixel_y, pixel_offset, lens_point)
+            .with_time(time)
+    }
+
+    /// Like [`Camera::cast_ray`], but places the ray within the pixel and on the lens as `sample`
+    /// of a pair of correlated multi-jittered patterns, which cover the pixel, the lens and each of
+    /// their axes evenly for any number of samples.
+    ///
+    /// As with [`Camera::cast_halton_ray`], every pixel shuffles its patterns differently
+    /// according to its position and `scramble`. The shutter time is sampled from `rng`.
+    pub fn cast_multi_jittered_ray(
+        &self,
+        pixel_x: u32,
+        pixel_y: u32,
+        sample: PixelSample,
+        scramble: u64,
+        rng: &mut dyn RngCore,
+    ) -> Ray {
+        let sample = MultiJitteredSample {
+            sample,
+            key: pixel_key(scramble, pixel_x, pixel_y),
+        };
+
+        let lens_point = if self.lens_radius > 0. {
+            self.lens_point_from(sample.point(1))
+        } else {
+            [0., 0.]
+        };
+
+        self.ray_through(pixel_x, pixel_y, sample.point(0), lens_point)
+            .with_time(self.sample_time(rng))
+    }
+
+    /// Maps `u` (in `[0, 1)²`) to a point on the unit disc (or the aperture mask's square), for
+    /// placing rays on the lens.
+    fn lens_point_from(&self, u: [f64; 2]) -> [f64; 2] {
+        match &self.aperture_mask {
+            Some(mask) => mask.sample(u),
+            None => distr::square_to_disc(u),
+        }
+    }
+
+    /// Samples the point on the unit disc (or the aperture mask's square) at which a ray leaves the
+    /// lens.
+    fn sample_lens_point(&self, rng: &mut dyn RngCore) -> [f64; 2] {
+        if self.lens_radius > 0. {
+            match &self.aperture_mask {
+                Some(mask) => mask.sample([rng.gen(), rng.gen()]),
+                None => UnitDisc.sample(rng),
+            }
+        } else {
+            [0., 0.]
+        }
+    }
+
+    /// Builds the ray through `pixel_offset` (in `[0, 1)²`) within the given pixel, leaving the
+    /// lens at `lens_point` on the unit disc.
+    fn ray_through(
+        &self,
+        pixel_x: u32,
+        pixel_y: u32,
+        pixel_offset: [f64; 2],
+        lens_point: [f64; 2],
+    ) -> Ray {
+        let pixel_x = pixel_x as f64 + pixel_offset[0];
+        let pixel_y = pixel_y as f64 + pixel_offset[1];
 
         let dof_offset = if self.lens_radius > 0. {
-            let [rdx, rdy]: [f64; 2] = UnitDisc.sample(rng);
-            self.lens_radius * (rdx * *self.u + rdy * *self.v)
+            self.lens_radius * (lens_point[0] * *self.u + lens_point[1] * *self.v)
         } else {
             Vec3::default()
         };
 
-        let u = pixel_x * self.inv_width;
-        let v = 1. - pixel_y * self.inv_height;
+        let [u, v] = self.distort([pixel_x * self.inv_width, 1. - pixel_y * self.inv_height]);
 
         Ray::pointing_through(
             self.origin + dof_offset,
             self.bottom_left + u * self.horiz + v * self.vert,
         )
+        .with_kind(RayKind::Camera)
+    }
+
+    /// Maps a point of the image, in viewport coordinates (`[0, 1]²`, from the bottom left), to the
+    /// point of the viewport it shows under the camera's distortion.
+    fn distort(&self, [u, v]: [f64; 2]) -> [f64; 2] {
+        if self.distortion == [0., 0.] {
+            return [u, v];
+        }
+
+        let [width, height] = self.viewport;
+        let (x, y) = (u - 0.5, v - 0.5);
+
+        let r2 =
+            ((x * width).powi(2) + (y * height).powi(2)) * 4. / (width.powi(2) + height.powi(2));
+        let [k1, k2] = self.distortion;
+        let scale = 1. + k1 * r2 + k2 * r2.powi(2);
+
+        [0.5 + x * scale, 0.5 + y * scale]
+    }
+
+    /// The maximum distance at which camera rays may hit geometry.
+    pub fn far(&self) -> f64 {
+        self.far
     }
 
     pub fn pixel_width(&self) -> u32 {
@@ -106,62 +499,1069 @@ impl Camera {
     }
 }
 
+/// Identifies one of the samples taken in a pixel, for [`Camera::cast_stratified_ray`] and
+/// [`Camera::cast_multi_jittered_ray`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelSample {
+    /// Position of the sample in the pixel's set, below `count`.
+    pub index: u32,
+    /// Number of samples in the set.
+    pub count: u32,
+}
+
+impl PixelSample {
+    /// Returns the number of strata along each side of the grid covering the set.
+    fn grid_size(&self) -> u32 {
+        let mut size = (self.count as f64).sqrt() as u32;
+        while size * size > self.count {
+            size -= 1;
+        }
+        while (size + 1) * (size + 1) <= self.count {
+            size += 1;
+        }
+        size
+    }
+}
+
+/// Returns a random point in cell `stratum` of a `grid_size` × `grid_size` grid over `[0, 1)²`.
+fn jitter_stratum(stratum: u32, grid_size: u32, rng: &mut dyn RngCore) -> [f64; 2] {
+    let inv_size = 1. / grid_size as f64;
+    [
+        ((stratum % grid_size) as f64 + rng.gen::<f64>()) * inv_size,
+        ((stratum / grid_size) as f64 + rng.gen::<f64>()) * inv_size,
+    ]
+}
+
+/// Maps `index` to its image under a pseudorandom permutation of `0..len` chosen by `key`.
+///
+/// This is the hash-based permutation of Kensler's "Correlated Multi-Jittered Sampling", which
+/// needs no storage, so that every sample of a pixel can be placed independently.
+fn permute(mut index: u32, len: u32, key: u32) -> u32 {
+    let mut mask = len - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    // Permute within the enclosing power of two, walking the cycle until landing in range.
+    loop {
+        index ^= key;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= key >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= key >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= key >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | key >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= mask;
+        index ^= index >> 5;
+
+        if index < len {
+            break;
+        }
+    }
+
+    ((index as u64 + key as u64) % len as u64) as u32
+}
+
+/// How the camera rays of a pixel's samples are placed within the pixel and on the lens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelSampling {
+    /// Every ray is placed independently at random.
+    #[default]
+    Independent,
+    /// Rays are spread over a jittered grid, as with [`Camera::cast_stratified_ray`], with lens
+    /// strata shuffled according to the render's seed, as with `Halton`.
+    Stratified,
+    /// Rays follow a scrambled Halton sequence, as with [`Camera::cast_halton_ray`], scrambled
+    /// according to the render's seed. Renders without a seed all share the same scrambling.
+    Halton,
+    /// Rays follow correlated multi-jittered patterns, as with
+    /// [`Camera::cast_multi_jittered_ray`], and so do the light and BSDF samples taken where they
+    /// first hit a surface, each pair of dimensions with its own pattern. Later bounces are
+    /// sampled independently. Patterns are shuffled according to the render's seed, as with
+    /// `Halton`.
+    MultiJittered,
+}
+
 pub struct RenderOptions {
+    /// Number of samples averaged in every pixel, which must be positive.
     pub samples_per_pixel: u32,
+    /// Maximum number of surface interactions along a path. With 0, only light sources seen
+    /// directly by the camera show up.
     pub max_depth: u32,
+    /// Seed from which every pixel's random sequence is derived, making renders reproducible. If
+    /// this is `None`, samples are drawn from the thread-local generator instead.
+    pub seed: Option<u64>,
+    /// How the camera rays of each pixel are placed within the pixel and on the lens. Adaptive
+    /// renders, whose sample counts vary, ignore this and place them independently.
+    pub sampling: PixelSampling,
+    /// Largest value any channel of a sample's indirect radiance may take, or `None` to leave it
+    /// unclamped. Clamping suppresses fireflies from rare, bright indirect paths at the cost of
+    /// some of their energy. Direct light (see [`Layer`]) is never clamped, so brightly lit
+    /// surfaces keep their full brightness.
+    pub indirect_clamp: Option<f64>,
+    /// Number of surface interactions after which light from lights at infinity (such as skies,
+    /// see [`Light::is_infinite`]) is ignored, or `None` to keep it at any depth. The background
+    /// seen directly by the camera is always kept. In mostly enclosed scenes, background light
+    /// reaching deep into a path does so only rarely and adds mostly noise; cutting it off trades
+    /// that noise for a little lost energy.
+    pub max_background_bounces: Option<u32>,
+    /// Roughness to which glossy and specular surfaces are widened once a path has scattered off
+    /// a non-specular one, or `None` to leave them as they are. This path regularization lets
+    /// light sampling find caustics, which otherwise show up only when a path happens to hit a
+    /// light through specular bounces, trading their noise for some blur. Ignored by
+    /// [`render_photon_mapped`], which gathers caustics from photons instead.
+    pub path_regularization: Option<f64>,
+    /// Distance below which points sampled on area lights are treated as though they were this
+    /// far away, or `None` to leave them as they are. Light from an area light falls off with the
+    /// square of the distance, so points sampled on it right next to a surface contribute huge
+    /// values and show up as fireflies along the contact. Bounding the falloff removes them at the
+    /// cost of slightly darkening surfaces within this distance of lights; farther away, rendering
+    /// is unaffected. Point and spot lights, whose light needs no sampling, are never bounded.
+    pub min_light_distance: Option<f64>,
+    /// Whether shadow rays toward point and spot lights that would pass through a single
+    /// refractive object are bent through it when possible, rendering the caustics it focuses
+    /// onto diffuse surfaces (which paths can never find on their own, as they cannot hit such
+    /// lights). Without a valid bent connection, or with this off, light passes straight
+    /// through transparent objects instead.
+    pub refracted_connections: bool,
+}
+
+impl RenderOptions {
+    /// Checks that the options describe a render that can produce an image.
+    ///
+    /// Rendering functions panic when given options that fail this check.
+    pub fn validate(&self) -> Result<(), RenderOptionsError> {
+        if self.samples_per_pixel == 0 {
+            return Err(RenderOptionsError::NoSamples);
+        }
+
+        if let Some(clamp) = self.indirect_clamp {
+            if clamp.is_nan() || clamp <= 0. {
+                return Err(RenderOptionsError::InvalidIndirectClamp(clamp));
+            }
+        }
+
+        if let Some(roughness) = self.path_regularization {
+            if roughness.is_nan() || roughness <= 0. || roughness > 1. {
+                return Err(RenderOptionsError::InvalidPathRegularization(roughness));
+            }
+        }
+
+        if let Some(dist) = self.min_light_distance {
+            if dist.is_nan() || dist <= 0. {
+                return Err(RenderOptionsError::InvalidMinLightDistance(dist));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn assert_valid(&self) {
+        if let Err(err) = self.validate() {
+            panic!("invalid render options: {}", err);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderOptionsError {
+    NoSamples,
+    InvalidIndirectClamp(f64),
+    InvalidPathRegularization(f64),
+    InvalidMinLightDistance(f64),
+}
+
+impl fmt::Display for RenderOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderOptionsError::NoSamples => {
+                write!(f, "the number of samples per pixel must be positive")
+            }
+            RenderOptionsError::InvalidIndirectClamp(clamp) => {
+                write!(f, "indirect clamp must be positive (got {})", clamp)
+            }
+            RenderOptionsError::InvalidPathRegularization(roughness) => write!(
+                f,
+                "path regularization roughness must lie in (0, 1] (got {})",
+                roughness
+            ),
+            RenderOptionsError::InvalidMinLightDistance(dist) => {
+                write!(f, "minimum light distance must be positive (got {})", dist)
+            }
+        }
+    }
+}
+
+impl Error for RenderOptionsError {}
+
+/// A rectangle of pixels within the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn full(camera: &Camera) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: camera.pixel_width(),
+            height: camera.pixel_height(),
+        }
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+}
+
+pub struct Renderer {
+    scene: Scene,
+    camera: Camera,
+    opts: RenderOptions,
+}
+
+impl Renderer {
+    pub fn new(scene: Scene, camera: Camera, opts: RenderOptions) -> Self {
+        Self {
+            scene,
+            camera,
+            opts,
+        }
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn opts(&self) -> &RenderOptions {
+        &self.opts
+    }
+
+    pub fn render(&self) -> Image {
+        self.render_region(Region::full(&self.camera))
+    }
+
+    /// Renders only the pixels within `region`, returning an image the size of the region.
+    pub fn render_region(&self, region: Region) -> Image {
+        let mut pixels = vec![Vec3::default(); region.pixel_count()];
+        render_region_to(&mut pixels, &self.scene, &self.camera, &self.opts, &region);
+        Image::new(region.width, region.height, pixels)
+    }
+
+    /// See [`render_adaptive`].
+    pub fn render_adaptive(&self, adaptive: &AdaptiveOptions) -> (Image, Vec<u32>) {
+        let (pixels, sample_counts) =
+            render_adaptive(&self.scene, &self.camera, &self.opts, adaptive);
+
+        let image = Image::new(
+            self.camera.pixel_width(),
+            self.camera.pixel_height(),
+            pixels,
+        );
+
+        (image, sample_counts)
+    }
+
+    /// See [`render_with_alpha`].
+    pub fn render_with_alpha(&self) -> (Image, Vec<f64>) {
+        let (pixels, alpha) = render_with_alpha(&self.scene, &self.camera, &self.opts);
+        let image = Image::new(
+            self.camera.pixel_width(),
+            self.camera.pixel_height(),
+            pixels,
+        );
+
+        (image, alpha)
+    }
+
+    /// See [`render_layers`].
+    pub fn render_layers(&self) -> MultiLayerImage {
+        render_layers(&self.scene, &self.camera, &self.opts)
+    }
+
+    /// See [`render_photon_mapped`].
+    pub fn render_photon_mapped(&self, photon_opts: &PhotonOptions) -> Image {
+        let pixels = render_photon_mapped(&self.scene, &self.camera, &self.opts, photon_opts);
+
+        Image::new(
+            self.camera.pixel_width(),
+            self.camera.pixel_height(),
+            pixels,
+        )
+    }
+
+    /// See [`render_progressive`].
+    pub fn render_progressive(
+        &self,
+        cancel: Arc<AtomicBool>,
+        callback: impl FnMut(&[Vec3], u32),
+    ) -> (Image, u32) {
+        let (pixels, passes) =
+            render_progressive(&self.scene, &self.camera, &self.opts, cancel, callback);
+
+        let image = Image::new(
+            self.camera.pixel_width(),
+            self.camera.pixel_height(),
+            pixels,
+        );
+
+        (image, passes)
+    }
 }
 
 pub fn render_to(buf: &mut [Vec3], scene: &Scene, camera: &Camera, opts: &RenderOptions) {
-    let pixel_height = camera.pixel_height();
-    let pixel_width = camera.pixel_width();
+    render_region_to(buf, scene, camera, opts, &Region::full(camera));
+}
 
-    assert_eq!(buf.len(), (pixel_width * pixel_height) as usize);
+pub fn render_region_to(
+    buf: &mut [Vec3],
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+    region: &Region,
+) {
+    assert!(region.x + region.width <= camera.pixel_width());
+    assert!(region.y + region.height <= camera.pixel_height());
+    assert_eq!(buf.len(), region.pixel_count());
+    opts.assert_valid();
 
     buf.par_iter_mut().enumerate().for_each(|(idx, pixel)| {
         let idx = idx as u32;
 
-        let px = idx % pixel_width;
-        let py = idx / pixel_width;
+        let px = region.x + idx % region.width;
+        let py = region.y + idx / region.width;
 
-        let mut rng = rand::thread_rng();
+        let stream = (py * camera.pixel_width() + px) as u64;
 
-        *pixel = iter::repeat_with(|| {
-            let ray = camera.cast_ray(px, py, &mut rng);
-            trace_ray(scene, ray, &mut rng, opts.max_depth)
-        })
-        .take(opts.samples_per_pixel as usize)
-        .sum::<Vec3>()
-            / (opts.samples_per_pixel as f64);
+        *pixel = with_rng(opts.seed, stream, |rng| {
+            pairwise_sum(0..opts.samples_per_pixel, &mut |index| {
+                sample_pixel(scene, camera, px, py, index, rng, opts)
+            }) / (opts.samples_per_pixel as f64)
+        }) * camera.vignetting(px, py);
     });
 }
 
-fn trace_ray(scene: &Scene, mut ray: Ray, rng: &mut dyn RngCore, max_depth: u32) -> Vec3 {
+/// Number of terms below which [`pairwise_sum`] adds terms one by one.
+const PAIRWISE_SUM_BLOCK: u32 = 8;
+
+/// Sums `f(index)` over `indices` by pairwise summation, evaluating the terms in order.
+///
+/// Adding samples one by one to a running total loses precision as the total grows, with an error
+/// growing linearly in the number of samples, while summing halves separately and then adding them
+/// keeps the error logarithmic. The grouping depends only on the number of terms, so the result
+/// does not depend on how (or in what order) the halves are evaluated.
+fn pairwise_sum(indices: Range<u32>, f: &mut impl FnMut(u32) -> Vec3) -> Vec3 {
+    if indices.len() <= PAIRWISE_SUM_BLOCK as usize {
+        return indices.map(f).sum();
+    }
+
+    let mid = indices.start + (indices.end - indices.start) / 2;
+    pairwise_sum(indices.start..mid, f) + pairwise_sum(mid..indices.end, f)
+}
+
+/// Merges independent renders of the same image, given as `(pixels, samples_per_pixel)` pairs,
+/// into the mean of all of their samples.
+///
+/// Every render is the mean of its own samples, so weighting each by its sample count makes
+/// merging K renders of `spp` samples equivalent in expectation to a single render of `K * spp`
+/// samples, and equally noisy when the renders are independent. For that, they must be rendered
+/// with different seeds: renders sharing a seed are identical, and merging them gains nothing.
+/// Stratified renders remain unbiased, but are only stratified within each render.
+///
+/// Renders without samples are ignored, and the result is black if no render has any. Panics if
+/// the buffers differ in size.
+pub fn merge_renders(buffers: &[(Vec<Vec3>, u32)]) -> Vec<Vec3> {
+    let pixel_count = buffers.first().map_or(0, |(pixels, _)| pixels.len());
+    assert!(
+        buffers
+            .iter()
+            .all(|(pixels, _)| pixels.len() == pixel_count),
+        "merged renders differ in size"
+    );
+
+    let mut merged = vec![Vec3::default(); pixel_count];
+
+    let total_samples: u64 = buffers.iter().map(|&(_, spp)| spp as u64).sum();
+    if total_samples == 0 {
+        return merged;
+    }
+
+    for (pixels, spp) in buffers.iter().filter(|&&(_, spp)| spp > 0) {
+        let weight = *spp as f64 / total_samples as f64;
+        merged
+            .par_iter_mut()
+            .zip(pixels.par_iter())
+            .for_each(|(merged, pixel)| *merged += weight * pixel);
+    }
+
+    merged
+}
+
+/// Renders `opts.samples_per_pixel` passes of a single sample per pixel, invoking `callback` with
+/// the current estimate and the number of completed passes after each one.
+///
+/// Every pass is finished across all threads before the callback runs, so the snapshot it receives
+/// is always consistent. Setting `cancel`, which the caller can share with another thread, stops
+/// the render within a row's worth of work; the pass in flight is then discarded.
+///
+/// Returns the final image along with the number of passes that went into it.
+pub fn render_progressive(
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+    cancel: Arc<AtomicBool>,
+    mut callback: impl FnMut(&[Vec3], u32),
+) -> (Vec<Vec3>, u32) {
+    let pixel_width = camera.pixel_width();
+    let pixel_count = (pixel_width * camera.pixel_height()) as usize;
+
+    let mut accum = vec![Vec3::default(); pixel_count];
+    let mut pass_buf = vec![Vec3::default(); pixel_count];
+    let mut snapshot = vec![Vec3::default(); pixel_count];
+
+    for pass in 1..=opts.samples_per_pixel {
+        pass_buf
+            .par_chunks_mut(pixel_width as usize)
+            .enumerate()
+            .for_each(|(py, row)| {
+                if cancel.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let stream = ((pass as u64) << 32) | py as u64;
+
+                with_rng(opts.seed, stream, |rng| {
+                    for (px, pixel) in row.iter_mut().enumerate() {
+                        *pixel =
+                            sample_pixel(scene, camera, px as u32, py as u32, pass - 1, rng, opts);
+                    }
+                });
+            });
+
+        if cancel.load(Ordering::Relaxed) {
+            return (snapshot, pass - 1);
+        }
+
+        let inv_pass = 1. / pass as f64;
+        accum
+            .par_iter_mut()
+            .zip(snapshot.par_iter_mut())
+            .zip(pass_buf.par_iter())
+            .enumerate()
+            .for_each(|(idx, ((sum, pixel), sample))| {
+                let px = idx as u32 % pixel_width;
+                let py = idx as u32 / pixel_width;
+
+                *sum += sample;
+                *pixel = *sum * inv_pass * camera.vignetting(px, py);
+            });
+
+        callback(&snapshot, pass);
+    }
+
+    (snapshot, opts.samples_per_pixel)
+}
+
+thread_local! {
+    /// Generator used by the current worker thread for unseeded renders.
+    static WORKER_RNG: RefCell<Pcg64> = RefCell::new(worker_rng());
+}
+
+/// Creates a generator for the current rayon worker, on a stream distinct from those of all other
+/// workers.
+fn worker_rng() -> Pcg64 {
+    static BASE_STATE: OnceLock<u128> = OnceLock::new();
+    let state = *BASE_STATE.get_or_init(rand::random);
+
+    // Threads outside the pool (such as the one calling into it) share a stream; they never
+    // sample concurrently with a worker on the same stream for long enough to matter.
+    let worker = rayon::current_thread_index().map_or(u128::MAX, |idx| idx as u128);
+    Pcg64::new(state, worker)
+}
+
+/// Runs `f` with a generator for the independent random stream `stream`, derived from `seed` if
+/// there is one.
+///
+/// Seeded renders get a fresh generator for every stream, so that the result does not depend on
+/// how work is divided between threads. Otherwise, `f` borrows the current worker's generator,
+/// which is much cheaper than `thread_rng` but means that `f` must not re-enter rayon.
+fn with_rng<R>(seed: Option<u64>, stream: u64, f: impl FnOnce(&mut dyn RngCore) -> R) -> R {
+    match seed {
+        Some(seed) => f(&mut Pcg64::seed_from_u64(stream_seed(seed, stream))),
+        None => WORKER_RNG.with(|rng| f(&mut *rng.borrow_mut())),
+    }
+}
+
+/// Hashes `seed` and `stream` into the seed for that stream's generator.
+///
+/// Streams are typically consecutive pixel indices, and combining them with the seed directly
+/// (say, by xor) gives neighboring pixels initial states differing in only a few bits, as well as
+/// making `(seed, stream)` pairs collide across seeds. Hashing both with a strong finalizer avoids
+/// this.
+fn stream_seed(seed: u64, stream: u64) -> u64 {
+    splitmix64(splitmix64(seed) ^ stream)
+}
+
+/// The output function of Vigna's SplitMix64 generator, which is a bijective mixer of 64-bit
+/// values.
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Casts the camera ray for sample `index` of the pixel, placed as `opts.sampling` asks.
+fn cast_sample_ray(
+    camera: &Camera,
+    px: u32,
+    py: u32,
+    index: u32,
+    rng: &mut dyn RngCore,
+    opts: &RenderOptions,
+) -> Ray {
+    match opts.sampling {
+        PixelSampling::Independent => camera.cast_ray(px, py, rng),
+        PixelSampling::Stratified => {
+            let sample = PixelSample {
+                index,
+                count: opts.samples_per_pixel,
+            };
+            camera.cast_stratified_ray(px, py, sample, opts.seed.unwrap_or(0), rng)
+        }
+        PixelSampling::Halton => camera.cast_halton_ray(px, py, index, opts.seed.unwrap_or(0), rng),
+        PixelSampling::MultiJittered => {
+            let sample = PixelSample {
+                index,
+                count: opts.samples_per_pixel,
+            };
+            camera.cast_multi_jittered_ray(px, py, sample, opts.seed.unwrap_or(0), rng)
+        }
+    }
+}
+
+/// Returns the key from which the sequences of the pixel at (`px`, `py`) are scrambled.
+fn pixel_key(scramble: u64, px: u32, py: u32) -> u64 {
+    stream_seed(scramble, ((py as u64) << 32) | px as u64)
+}
+
+fn sample_pixel(
+    scene: &Scene,
+    camera: &Camera,
+    px: u32,
+    py: u32,
+    index: u32,
+    rng: &mut dyn RngCore,
+    opts: &RenderOptions,
+) -> Vec3 {
+    let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+    trace_path(
+        scene,
+        ray,
+        camera.far(),
+        rng,
+        PathSettings::for_sample(opts, px, py, index),
+        PathOutputs::default(),
+    )
+    .0
+}
+
+/// A single surface interaction along a path traced by [`trace_ray_debug`].
+#[derive(Debug, Clone)]
+pub struct PathVertex {
+    pub point: Vec3,
+    /// The (world-space) direction of the ray arriving at the vertex.
+    pub incoming: Unit3,
+    /// The (world-space) direction in which the path continues, or `None` if the material
+    /// absorbed it.
+    pub scattered: Option<Unit3>,
+    pub side: HitSide,
+    pub material: &'static str,
+    /// The path throughput upon reaching the vertex.
+    pub throughput: Vec3,
+    /// Radiance contributed by direct lighting at the vertex, already scaled by `throughput`.
+    pub direct: Vec3,
+}
+
+/// A record of a single path, as traced by [`trace_ray_debug`].
+#[derive(Debug, Clone, Default)]
+pub struct PathRecord {
+    pub vertices: Vec<PathVertex>,
+    /// Radiance contributed by light sources seen directly from the camera or through specular
+    /// bounces, rather than through light sampling, already scaled by the throughput.
+    pub emitted: Vec3,
+    /// The total radiance carried by the path.
+    pub radiance: Vec3,
+}
+
+impl fmt::Display for PathRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn vec(v: &Vec3) -> String {
+            format!("({:.4}, {:.4}, {:.4})", v[0], v[1], v[2])
+        }
+
+        for (i, vertex) in self.vertices.iter().enumerate() {
+            writeln!(
+                f,
+                "#{}: {} ({:?}) at {}",
+                i,
+                vertex.material,
+                vertex.side,
+                vec(&vertex.point)
+            )?;
+            writeln!(f, "    incoming:   {}", vec(&vertex.incoming))?;
+            match &vertex.scattered {
+                Some(scattered) => writeln!(f, "    scattered:  {}", vec(scattered))?,
+                None => writeln!(f, "    scattered:  absorbed")?,
+            }
+            writeln!(f, "    throughput: {}", vec(&vertex.throughput))?;
+            writeln!(f, "    direct:     {}", vec(&vertex.direct))?;
+        }
+
+        writeln!(f, "emitted: {}", vec(&self.emitted))?;
+        write!(f, "radiance: {}", vec(&self.radiance))
+    }
+}
+
+/// Traces `ray` through `scene` exactly as the renderer would (without clamping indirect light),
+/// recording every bounce along the way. Only hits closer than `t_max` are considered for the
+/// first bounce.
+pub fn trace_ray_debug(
+    scene: &Scene,
+    ray: Ray,
+    t_max: f64,
+    rng: &mut dyn RngCore,
+    max_depth: u32,
+) -> PathRecord {
+    let mut record = PathRecord::default();
+    let outputs = PathOutputs {
+        record: Some(&mut record),
+        ..PathOutputs::default()
+    };
+    let settings = PathSettings {
+        max_depth,
+        ..PathSettings::default()
+    };
+    record.radiance = trace_path(scene, ray, t_max, rng, settings, outputs).0;
+    record
+}
+
+fn trace_ray(
+    scene: &Scene,
+    ray: Ray,
+    t_max: f64,
+    rng: &mut dyn RngCore,
+    opts: &RenderOptions,
+) -> Vec3 {
+    trace_path(
+        scene,
+        ray,
+        t_max,
+        rng,
+        PathSettings::new(opts),
+        PathOutputs::default(),
+    )
+    .0
+}
+
+/// Renders the average number of surface interactions along each pixel's paths before they
+/// escaped the scene, were absorbed, or were terminated by Russian roulette or `max_depth`.
+pub fn render_bounce_counts(scene: &Scene, camera: &Camera, opts: &RenderOptions) -> Vec<f64> {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let mut counts = vec![0.; (pixel_width * camera.pixel_height()) as usize];
+
+    counts.par_iter_mut().enumerate().for_each(|(idx, count)| {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+
+        *count = with_rng(opts.seed, idx as u64, |rng| {
+            (0..opts.samples_per_pixel)
+                .map(|index| {
+                    let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+                    trace_path(
+                        scene,
+                        ray,
+                        camera.far(),
+                        rng,
+                        PathSettings::for_sample(opts, px, py, index),
+                        PathOutputs::default(),
+                    )
+                    .1
+                })
+                .sum::<u32>() as f64
+                / opts.samples_per_pixel as f64
+        });
+    });
+
+    counts
+}
+
+/// Renders the average albedo (see [`Material::albedo_hint`]) of the first surface seen by each
+/// pixel's camera rays, as an auxiliary image for denoisers. Rays that hit nothing count as black.
+///
+/// [`Material::albedo_hint`]: crate::material::Material::albedo_hint
+pub fn render_albedo(scene: &Scene, camera: &Camera, opts: &RenderOptions) -> Vec<Vec3> {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let mut pixels = vec![Vec3::default(); (pixel_width * camera.pixel_height()) as usize];
+
+    pixels.par_iter_mut().enumerate().for_each(|(idx, pixel)| {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+
+        *pixel = with_rng(opts.seed, idx as u64, |rng| {
+            (0..opts.samples_per_pixel)
+                .map(|index| {
+                    let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+                    scene
+                        .hit(&ray, EPSILON, camera.far())
+                        .map_or(Vec3::default(), |hit| {
+                            hit.material.albedo_hint(&hit.shading_info(&ray))
+                        })
+                })
+                .sum::<Vec3>()
+                / opts.samples_per_pixel as f64
+        });
+    });
+
+    pixels
+}
+
+/// Renders the average number of BVH nodes tested by each pixel's camera rays.
+pub fn render_bvh_node_tests(scene: &Scene, camera: &Camera, opts: &RenderOptions) -> Vec<f64> {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let mut counts = vec![0.; (pixel_width * camera.pixel_height()) as usize];
+
+    counts.par_iter_mut().enumerate().for_each(|(idx, count)| {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+
+        *count = with_rng(opts.seed, idx as u64, |rng| {
+            (0..opts.samples_per_pixel)
+                .map(|index| {
+                    let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+                    scene.count_bvh_node_tests(&ray, EPSILON, camera.far())
+                })
+                .sum::<u32>() as f64
+                / opts.samples_per_pixel as f64
+        });
+    });
+
+    counts
+}
+
+/// Dimension of a pixel's multi-jittered sample at which the samples of the first bounce start,
+/// following the pixel and lens positions of the camera ray.
+const FIRST_BOUNCE_DIMENSION: u64 = 2;
+
+/// Settings of [`trace_path`], shared by every path of a render except for `first_bounce`.
+#[derive(Clone, Copy, Default)]
+struct PathSettings<'a> {
+    max_depth: u32,
+    /// See [`RenderOptions::indirect_clamp`].
+    indirect_clamp: Option<f64>,
+    /// See [`RenderOptions::max_background_bounces`].
+    max_background_bounces: Option<u32>,
+    /// See [`RenderOptions::path_regularization`].
+    path_regularization: Option<f64>,
+    /// See [`RenderOptions::min_light_distance`].
+    min_light_distance: Option<f64>,
+    /// See [`RenderOptions::refracted_connections`].
+    refracted_connections: bool,
+    /// If set, light reaching non-specular surfaces by specular bounces alone is gathered from
+    /// these photons rather than found by the path itself.
+    caustics: Option<&'a PhotonMap>,
+    /// If set, the first surface hit is shaded with values drawn from this sample's patterns
+    /// rather than from the path's generator.
+    first_bounce: Option<MultiJitteredSample>,
+}
+
+impl PathSettings<'_> {
+    fn new(opts: &RenderOptions) -> Self {
+        Self {
+            max_depth: opts.max_depth,
+            indirect_clamp: opts.indirect_clamp,
+            max_background_bounces: opts.max_background_bounces,
+            path_regularization: opts.path_regularization,
+            min_light_distance: opts.min_light_distance,
+            refracted_connections: opts.refracted_connections,
+            caustics: None,
+            first_bounce: None,
+        }
+    }
+
+    /// Like [`PathSettings::new`], but for the path of sample `index` of the pixel at
+    /// (`px`, `py`), whose first bounce may be stratified according to `opts.sampling`.
+    fn for_sample(opts: &RenderOptions, px: u32, py: u32, index: u32) -> Self {
+        let first_bounce = match opts.sampling {
+            PixelSampling::MultiJittered => Some(MultiJitteredSample {
+                sample: PixelSample {
+                    index,
+                    count: opts.samples_per_pixel,
+                },
+                key: pixel_key(opts.seed.unwrap_or(0), px, py),
+            }),
+            _ => None,
+        };
+
+        Self {
+            first_bounce,
+            ..Self::new(opts)
+        }
+    }
+}
+
+/// Optional outputs of [`trace_path`], in addition to the radiance it returns.
+#[derive(Default)]
+struct PathOutputs<'a> {
+    /// Receives a description of every vertex of the path.
+    record: Option<&'a mut PathRecord>,
+    /// Receives the radiance split into layers, indexed by [`Layer::index`].
+    layers: Option<&'a mut [Vec3]>,
+    /// Receives the opacity of the first surface hit: 1 for objects, and the fraction of light
+    /// blocked for [shadow catchers](crate::material::ShadowCatcher), which end the path.
+    alpha: Option<&'a mut f64>,
+}
+
+/// Traces a path starting along `ray`, returning the radiance it carries and its number of
+/// surface interactions.
+fn trace_path(
+    scene: &Scene,
+    mut ray: Ray,
+    t_max: f64,
+    rng: &mut dyn RngCore,
+    settings: PathSettings<'_>,
+    outputs: PathOutputs<'_>,
+) -> (Vec3, u32) {
     const MIN_RR_DEPTH: u32 = 5;
 
+    let PathSettings {
+        max_depth,
+        indirect_clamp,
+        max_background_bounces,
+        path_regularization,
+        min_light_distance,
+        refracted_connections,
+        caustics,
+        first_bounce,
+    } = settings;
+
+    let PathOutputs {
+        mut record,
+        mut layers,
+        mut alpha,
+    } = outputs;
+
     let mut radiance = Vec3::default();
+    // Indirect light by layer, to be clamped once the path is complete.
+    let mut indirect = [
+        (Layer::IndirectDiffuse, Vec3::default()),
+        (Layer::IndirectSpecular, Vec3::default()),
+    ];
+    let mut add_radiance = |layer: Layer, contribution: Vec3| {
+        radiance += contribution;
+        if let Some(layers) = layers.as_deref_mut() {
+            layers[layer.index()] += contribution;
+        }
+        match layer {
+            Layer::IndirectDiffuse => indirect[0].1 += contribution,
+            Layer::IndirectSpecular => indirect[1].1 += contribution,
+            _ => {}
+        }
+    };
+
+    let mut bounces = 0;
     let mut throughput = Vec3::from_element(1.);
+    let mut specular_bounce = false;
+    let mut nonspecular_bounce = false;
+    let mut first_specular = false;
+    let mut media = MediumStack::default();
+    // Whether light from infinity still counts after `bounces` surface interactions.
+    let background_lit =
+        |bounces: u32| max_background_bounces.is_none_or(|max_bounces| bounces <= max_bounces);
 
-    for depth in 0..max_depth {
-        let hit = match scene.hit(&ray, f64::INFINITY) {
-            Some(hit) => hit,
-            None => {
-                break;
+    // Even without any surface interactions, light sources in view should still show up.
+    for depth in 0..max_depth.max(1) {
+        let t_max = if depth == 0 { t_max } else { f64::INFINITY };
+
+        let mut jittered_rng;
+        let rng: &mut dyn RngCore = match first_bounce {
+            Some(sample) if depth == 0 => {
+                jittered_rng = MultiJitteredRng::new(sample, FIRST_BOUNCE_DIMENSION);
+                &mut jittered_rng
+            }
+            _ => &mut *rng,
+        };
+
+        let hit = scene.hit(&ray, EPSILON, t_max);
+
+        // Light reaching us after a diffuse bounce has already been accounted for by
+        // `sample_single_light`; only add it here when that could not have happened. Light
+        // focused by specular surfaces onto an earlier diffuse one is a caustic, which comes from
+        // the photon map when there is one.
+        let caustic = caustics.is_some() && nonspecular_bounce;
+        if (depth == 0 || specular_bounce) && !caustic {
+            let t_hit = hit.as_ref().map_or(f64::INFINITY, |hit| hit.geom_hit.t);
+
+            for (idx, light) in scene.lights().iter().enumerate() {
+                if light.is_infinite() && !background_lit(bounces) {
+                    continue;
+                }
+
+                let emitted = match light.emitted(&ray) {
+                    Some(emitted) if emitted.dist.unwrap_or(f64::INFINITY) <= t_hit => {
+                        throughput.component_mul(&emitted.color)
+                    }
+                    _ => continue,
+                };
+
+                add_radiance(Layer::emitted(depth, first_specular, idx), emitted);
+
+                if let Some(record) = record.as_deref_mut() {
+                    record.emitted += emitted;
+                }
+            }
+        }
+
+        let hit = match hit {
+            Some(hit) if max_depth > 0 => hit,
+            _ => break,
+        };
+
+        let shading_info = match media.interface(&hit) {
+            Some(Interface::Real { exterior_ior }) => {
+                hit.shading_info(&ray).with_exterior_ior(exterior_ior)
+            }
+            Some(Interface::False) => {
+                media.cross(&hit);
+                ray = hit.geom_hit.spawn_world_ray(ray.dir).with_kind(ray.kind);
+                continue;
+            }
+            None => hit.shading_info(&ray),
+        };
+
+        // Surfaces seen directly or through specular bounces alone stay sharp.
+        let min_roughness = match path_regularization {
+            Some(roughness) if nonspecular_bounce => roughness,
+            _ => 0.,
+        };
+        let shading_info = shading_info.with_min_roughness(min_roughness);
+        let specular_only = hit.material.is_always_specular() && min_roughness < MIN_ROUGHNESS;
+
+        if bounces == 0 {
+            if let Some(alpha) = alpha.take() {
+                if hit.material.is_shadow_catcher() {
+                    *alpha += shadow_catcher_opacity(scene, &hit, &shading_info, rng);
+                    break;
+                }
+
+                *alpha += 1.;
+            }
+        }
+
+        bounces += 1;
+
+        let direct = if specular_only || hit.material.is_absorber() {
+            Vec3::default()
+        } else {
+            let lighting = DirectLighting {
+                transparent_shadows: caustics.is_none(),
+                infinite_lights: background_lit(bounces),
+                min_light_distance,
+                refracted_connections,
+            };
+
+            match sample_single_light(scene, &hit, &shading_info, lighting, rng) {
+                Some((idx, direct)) => {
+                    let direct = throughput.component_mul(&direct);
+                    add_radiance(Layer::sampled(depth, first_specular, idx), direct);
+                    direct
+                }
+                None => Vec3::default(),
             }
         };
 
-        let shading_info = hit.shading_info(&ray);
+        if let Some(ambient) = scene.ambient() {
+            if !specular_only {
+                // A single BSDF sample gives an unbiased estimate of the albedo.
+                let albedo = hit
+                    .material
+                    .sample_bsdf(&shading_info, rng)
+                    .filter(|sample| hit.geom_hit.is_consistent(sample.dir))
+                    .map_or(Vec3::default(), |sample| sample.scaled_color());
+
+                add_radiance(
+                    Layer::indirect(depth > 0 && first_specular),
+                    throughput.component_mul(&ambient.color.component_mul(&albedo)),
+                );
+            }
+        }
+
+        if let Some(caustics) = caustics {
+            if !specular_only {
+                add_radiance(
+                    Layer::indirect(depth > 0 && first_specular),
+                    throughput.component_mul(&caustics.caustic_radiance(&hit, &shading_info)),
+                );
+            }
+        }
+
+        let sample = hit
+            .material
+            .sample_bsdf(&shading_info, rng)
+            .filter(|sample| hit.geom_hit.is_consistent(sample.dir));
 
-        if !hit.material.is_always_specular() {
-            radiance +=
-                throughput.component_mul(&sample_single_light(scene, &hit, &shading_info, rng));
+        if let Some(record) = record.as_deref_mut() {
+            record.vertices.push(PathVertex {
+                point: hit.geom_hit.point,
+                incoming: ray.dir,
+                scattered: sample.map(|sample| hit.geom_hit.local_to_world(sample.dir)),
+                side: hit.geom_hit.side,
+                material: hit.material.debug_name(),
+                throughput,
+                direct,
+            });
         }
 
-        let sample = match hit.material.sample_bsdf(&shading_info, rng) {
+        let sample = match sample {
             Some(sample) => sample,
             None => break,
         };
 
+        if !same_hemisphere(*sample.dir, *shading_info.outgoing) {
+            media.cross(&hit);
+        }
+
         throughput.component_mul_assign(&sample.scaled_color());
+        specular_bounce = matches!(sample.pdf, Pdf::Delta);
+        nonspecular_bounce |= !specular_bounce;
+        if depth == 0 {
+            first_specular = specular_bounce;
+        }
 
         if depth > MIN_RR_DEPTH {
             let q = throughput.max();
@@ -181,27 +1581,80 @@ fn trace_ray(scene: &Scene, mut ray: Ray, rng: &mut dyn RngCore, max_depth: u32)
         ray = hit.geom_hit.spawn_local_ray(sample.dir);
     }
 
-    radiance
+    if let Some(clamp) = indirect_clamp {
+        let total = indirect[0].1 + indirect[1].1;
+        if total.max() > clamp {
+            // Scale the indirect light down uniformly, keeping its hue.
+            let excess = 1. - clamp / total.max();
+            for (layer, sum) in indirect {
+                radiance -= excess * sum;
+                if let Some(layers) = layers.as_deref_mut() {
+                    layers[layer.index()] -= excess * sum;
+                }
+            }
+        }
+    }
+
+    (radiance, bounces)
+}
+
+/// Settings of [`sample_single_light`].
+#[derive(Clone, Copy)]
+struct DirectLighting {
+    /// Whether light from delta lights passes through transparent objects (see
+    /// [`Scene::transmittance`]) rather than being blocked by them.
+    transparent_shadows: bool,
+    /// Whether lights at infinity are included at all.
+    infinite_lights: bool,
+    /// See [`RenderOptions::min_light_distance`].
+    min_light_distance: Option<f64>,
+    /// See [`RenderOptions::refracted_connections`]. Only applies with `transparent_shadows`.
+    refracted_connections: bool,
 }
 
+/// Estimates the light arriving at `hit` directly from a light chosen at random among those whose
+/// [`Light::bounds`] contain it, returning the index of the light along with the estimate.
 fn sample_single_light(
     scene: &Scene,
     hit: &PrimitiveHit<'_>,
     shading_info: &ShadingInfo,
+    lighting: DirectLighting,
     rng: &mut dyn RngCore,
-) -> Vec3 {
-    let light = match scene.lights().choose(rng) {
-        Some(light) => &**light,
-        None => return Vec3::default(),
+) -> Option<(usize, Vec3)> {
+    // Lights that cannot reach the hit contribute nothing, so leaving them out of the choice
+    // keeps the estimate unbiased.
+    let point = hit.geom_hit.point;
+    let lights = scene.lights();
+    let reachable = || {
+        lights
+            .iter()
+            .map(|light| &**light)
+            .enumerate()
+            .filter(|(_, light)| lighting.infinite_lights || !light.is_infinite())
+            .filter(|(_, light)| light.bounds().is_none_or(|bounds| bounds.contains(point)))
     };
 
+    let candidates = reachable().count();
+    if candidates == 0 {
+        return None;
+    }
+
+    let (idx, light) = reachable().nth(rng.gen_range(0..candidates))?;
+
+    let light_weight = hit
+        .material
+        .light_sampling_weight(shading_info)
+        .clamp(0., 1.);
+
     let from_light =
-        sample_lighting_from_light(light, scene, hit, shading_info, rng).unwrap_or_default();
+        sample_lighting_from_light(light, scene, hit, shading_info, light_weight, lighting, rng)
+            .unwrap_or_default();
 
     let from_object =
-        sample_lighting_from_object(light, scene, hit, shading_info, rng).unwrap_or_default();
+        sample_lighting_from_object(light, scene, hit, shading_info, light_weight, rng)
+            .unwrap_or_default();
 
-    (from_light + from_object) * scene.lights().len() as f64
+    Some((idx, (from_light + from_object) * candidates as f64))
 }
 
 fn sample_lighting_from_light(
@@ -209,21 +1662,100 @@ fn sample_lighting_from_light(
     scene: &Scene,
     hit: &PrimitiveHit<'_>,
     shading_info: &ShadingInfo,
+    light_weight: f64,
+    lighting: DirectLighting,
     rng: &mut dyn RngCore,
 ) -> Option<Vec3> {
     let geom_hit = &hit.geom_hit;
     let material = hit.material;
 
     let sample = light.sample_incident_at(geom_hit, rng)?;
-    let shadow_ray = geom_hit.spawn_local_ray(sample.radiance.dir);
-
-    if scene.hit(&shadow_ray, sample.t - EPSILON).is_some() {
+    if !geom_hit.is_consistent(sample.radiance.dir) {
         return None;
     }
 
-    let weight = match sample.radiance.pdf {
-        Pdf::Real(pdf) => power_weight(pdf, material.pdf(shading_info, sample.radiance.dir)),
-        Pdf::Delta => 1.,
+    // Light the material cannot scatter toward the viewer (such as light from behind a diffuse
+    // surface) needs no shadow ray.
+    let bsdf = material.bsdf(shading_info, sample.radiance.dir);
+    let unlit = bsdf == Vec3::default();
+
+    let shadow_ray = geom_hit
+        .spawn_local_ray(sample.radiance.dir)
+        .with_kind(RayKind::Shadow);
+
+    let (weight, transmittance) = match sample.radiance.pdf {
+        // BSDF sampling accounts for all of this light; spare the shadow ray.
+        Pdf::Real(_) if light_weight == 0. || unlit => return None,
+        Pdf::Real(pdf) => {
+            // Paths through transparent objects reach lights like this one on their own, by
+            // specular bounces, so only opaque-free lines of sight count here.
+            if scene
+                .hit(&shadow_ray, EPSILON, shadow_t_max(sample.dist))
+                .is_some()
+            {
+                return None;
+            }
+
+            let weight = power_weight(
+                light_weight * pdf,
+                material.pdf(shading_info, sample.radiance.dir),
+            );
+
+            // Bound the falloff of light from points sampled right next to the hit.
+            let falloff = match (lighting.min_light_distance, sample.dist) {
+                (Some(min_dist), Some(dist)) if dist < min_dist => (dist / min_dist).powi(2),
+                _ => 1.,
+            };
+
+            (weight * falloff, Vec3::from_element(1.))
+        }
+        Pdf::Delta if !lighting.transparent_shadows => {
+            if unlit
+                || scene
+                    .hit(&shadow_ray, EPSILON, shadow_t_max(sample.dist))
+                    .is_some()
+            {
+                return None;
+            }
+
+            (1., Vec3::from_element(1.))
+        }
+        Pdf::Delta => {
+            // No path can ever hit a delta light, so bend the connection through refractive
+            // objects in the way when asked to and possible, focusing the light as they should.
+            let refracted = sample
+                .dist
+                .filter(|_| lighting.refracted_connections)
+                .and_then(|dist| {
+                    let light_point = shadow_ray.at(dist);
+                    manifold::sample_refracted_light(light, scene, geom_hit, light_point, rng)
+                });
+
+            if let Some(refracted) = refracted {
+                if !geom_hit.is_consistent(refracted.dir) {
+                    return None;
+                }
+
+                return Some(
+                    refracted
+                        .scaled_color()
+                        .component_mul(&material.bsdf(shading_info, refracted.dir)),
+                );
+            }
+
+            if unlit {
+                return None;
+            }
+
+            // Otherwise, at least let the light through transparent objects rather than leaving
+            // them with pitch-black shadows.
+            let transmittance = scene.transmittance(&shadow_ray, shadow_t_max(sample.dist));
+            if transmittance == Vec3::default() {
+                return None;
+            }
+
+            (1., transmittance)
+        }
     };
 
     Some(
@@ -231,7 +1763,8 @@ fn sample_lighting_from_light(
             * sample
                 .radiance
                 .scaled_color()
-                .component_mul(&material.bsdf(shading_info, sample.radiance.dir)),
+                .component_mul(&bsdf)
+                .component_mul(&transmittance),
     )
 }
 
@@ -240,28 +1773,204 @@ fn sample_lighting_from_object(
     scene: &Scene,
     hit: &PrimitiveHit<'_>,
     shading_info: &ShadingInfo,
+    light_weight: f64,
     rng: &mut dyn RngCore,
 ) -> Option<Vec3> {
     let geom_hit = &hit.geom_hit;
     let material = hit.material;
 
     let sample = material.sample_bsdf(shading_info, rng)?;
+    if !geom_hit.is_consistent(sample.dir) {
+        return None;
+    }
+
     let pdf = match sample.pdf {
         Pdf::Real(pdf) => pdf,
         Pdf::Delta => return None,
     };
 
-    let shadow_ray = geom_hit.spawn_local_ray(sample.dir);
+    let shadow_ray = geom_hit
+        .spawn_local_ray(sample.dir)
+        .with_kind(RayKind::Shadow);
     let emitted = light.emitted(&shadow_ray)?;
 
-    if scene.hit(&shadow_ray, emitted.t - EPSILON).is_some() {
+    if scene
+        .hit(&shadow_ray, EPSILON, shadow_t_max(emitted.dist))
+        .is_some()
+    {
         return None;
     }
 
-    let weight = power_weight(pdf, light.pdf(geom_hit, sample.dir));
+    let weight = power_weight(pdf, light_weight * light.pdf(geom_hit, sample.dir));
     Some(weight * sample.scaled_color().component_mul(&emitted.color))
 }
 
+/// Estimates the fraction of the direct light reaching the shadow catcher at `hit` that the scene
+/// blocks, weighing the lights by the luminance they contribute. Every light that may reach the
+/// hit is sampled once.
+fn shadow_catcher_opacity(
+    scene: &Scene,
+    hit: &PrimitiveHit<'_>,
+    shading_info: &ShadingInfo,
+    rng: &mut dyn RngCore,
+) -> f64 {
+    let geom_hit = &hit.geom_hit;
+
+    let mut unshadowed = 0.;
+    let mut shadowed = 0.;
+
+    for light in scene.lights() {
+        if !light
+            .bounds()
+            .is_none_or(|bounds| bounds.contains(geom_hit.point))
+        {
+            continue;
+        }
+
+        let sample = match light.sample_incident_at(geom_hit, rng) {
+            Some(sample) if geom_hit.is_consistent(sample.radiance.dir) => sample,
+            _ => continue,
+        };
+
+        let contribution = luminance(
+            &sample
+                .radiance
+                .scaled_color()
+                .component_mul(&hit.material.bsdf(shading_info, sample.radiance.dir)),
+        );
+        if contribution.is_nan() || contribution <= 0. {
+            continue;
+        }
+
+        let shadow_ray = geom_hit
+            .spawn_local_ray(sample.radiance.dir)
+            .with_kind(RayKind::Shadow);
+        let transmittance = scene.transmittance(&shadow_ray, shadow_t_max(sample.dist));
+
+        unshadowed += contribution;
+        shadowed += contribution * luminance(&transmittance);
+    }
+
+    if unshadowed > 0. {
+        (1. - shadowed / unshadowed).clamp(0., 1.)
+    } else {
+        0.
+    }
+}
+
+/// Returns the range within which geometry occludes a light at distance `dist` (`None` meaning
+/// infinitely far away).
+fn shadow_t_max(dist: Option<f64>) -> f64 {
+    dist.map_or(f64::INFINITY, |dist| dist - EPSILON)
+}
+
 fn power_weight(f: f64, g: f64) -> f64 {
     f.powi(2) / (f.powi(2) + g.powi(2))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::geom::Sphere;
+    use crate::light::PointLight;
+    use crate::material::Lambertian;
+    use crate::scene::SceneBuilder;
+
+    use super::*;
+
+    fn test_scene() -> Scene {
+        let mut builder = SceneBuilder::new();
+        builder.add_primitive(
+            Sphere::new(Vec3::new(0., 0., -1.), 0.5),
+            Arc::new(Lambertian::new(Vec3::from_element(0.5))),
+        );
+        builder.add_light(PointLight::new(
+            Vec3::new(0., 2., 0.),
+            Vec3::from_element(10.),
+        ));
+        builder.build()
+    }
+
+    fn test_camera() -> Camera {
+        Camera::new(&CameraOptions {
+            pixel_width: 16,
+            pixel_height: 8,
+            vert_fov: 50.,
+            aperture: 0.,
+            origin: Vec3::default(),
+            look_at: Vec3::new(0., 0., -1.),
+            vup: Vec3::new(0., 1., 0.),
+            focus_target: None,
+            far: f64::INFINITY,
+        })
+        .unwrap()
+    }
+
+    fn test_options(samples_per_pixel: u32) -> RenderOptions {
+        RenderOptions {
+            samples_per_pixel,
+            max_depth: 4,
+            seed: Some(1),
+            sampling: PixelSampling::Independent,
+            indirect_clamp: None,
+            max_background_bounces: None,
+            path_regularization: None,
+            min_light_distance: None,
+            refracted_connections: false,
+        }
+    }
+
+    #[test]
+    fn renderer_renders_whole_image_and_regions() {
+        let renderer = Renderer::new(test_scene(), test_camera(), test_options(4));
+
+        let image = renderer.render();
+        assert_eq!((image.width(), image.height()), (16, 8));
+        assert!(image
+            .pixels()
+            .iter()
+            .all(|pixel| pixel.iter().all(|c| c.is_finite())));
+        assert!(image.pixels().iter().any(|pixel| *pixel != Vec3::default()));
+
+        // Pixels do not depend on the region they are rendered in, so quarters pasted back
+        // together match the whole image exactly.
+        let mut pasted = Image::new(16, 8, vec![Vec3::default(); 16 * 8]);
+        for &(x, y) in &[(0, 0), (8, 0), (0, 4), (8, 4)] {
+            let quarter = renderer.render_region(Region {
+                x,
+                y,
+                width: 8,
+                height: 4,
+            });
+            pasted.paste(&quarter, x, y);
+        }
+        assert_eq!(pasted.pixels(), image.pixels());
+    }
+
+    #[test]
+    fn progressive_render_stops_when_cancelled_from_another_thread() {
+        let scene = test_scene();
+        let camera = test_camera();
+        let opts = test_options(1000);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut last_snapshot = Vec::new();
+        let (pixels, passes) =
+            render_progressive(&scene, &camera, &opts, cancel.clone(), |pixels, passes| {
+                last_snapshot = pixels.to_vec();
+                if passes == 3 {
+                    let cancel = cancel.clone();
+                    thread::spawn(move || cancel.store(true, Ordering::Relaxed))
+                        .join()
+                        .unwrap();
+                }
+            });
+
+        assert_eq!(passes, 3);
+        assert_eq!(pixels, last_snapshot);
+        assert!(pixels
+            .iter()
+            .all(|pixel| pixel.iter().all(|v| v.is_finite())));
+    }
+}
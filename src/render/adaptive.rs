@@ -0,0 +1,333 @@
+use std::f64;
+
+use rand::RngCore;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::img;
+use crate::math::Vec3;
+use crate::scene::Scene;
+
+use super::tiles::tile_regions;
+use super::{trace_ray, with_rng, Camera, Region, RenderOptions};
+
+/// How [`render_adaptive`] decides which pixels still need samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorMetric {
+    /// Every pixel stops on its own, once the relative standard error of its luminance drops below
+    /// the threshold.
+    #[default]
+    PerPixel,
+    /// Pixels are judged together in blocks, starting from tiles of the image, as in Dammertz et
+    /// al.'s "A Hierarchical Automatic Stopping Condition for Monte Carlo Global Illumination".
+    ///
+    /// After every batch, each pixel's estimate from all of its samples is compared to that from
+    /// every other sample, and the differences pooled over the block estimate its relative error.
+    /// Blocks stop once the error drops below the threshold, and are split in two as they approach
+    /// it so that their halves can stop separately. A pixel whose samples happen to agree keeps
+    /// sampling as long as its neighbors are noisy, so that thin features are not missed.
+    Block,
+}
+
+/// Options controlling how [`render_adaptive`] distributes samples.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveOptions {
+    /// Number of samples every pixel receives before its error is first estimated.
+    pub min_samples: u32,
+    /// Number of samples taken between successive error estimates.
+    pub batch_size: u32,
+    /// Relative standard error of a pixel's luminance below which it is considered converged.
+    pub error_threshold: f64,
+    pub metric: ErrorMetric,
+}
+
+impl Default for AdaptiveOptions {
+    fn default() -> Self {
+        Self {
+            min_samples: 16,
+            batch_size: 16,
+            error_threshold: 0.02,
+            metric: ErrorMetric::default(),
+        }
+    }
+}
+
+/// Luminance below which pixels are considered dark, so that their relative error is measured
+/// against this instead of their true luminance.
+const DARK_LUMINANCE: f64 = 1e-3;
+
+/// Multiple of the running mean luminance (of the samples as they are) beyond which a sample is
+/// considered a firefly when estimating the error.
+const FIREFLY_FACTOR: f64 = 10.;
+
+/// Side length of the tiles from which blocks start out under [`ErrorMetric::Block`].
+const BLOCK_TILE_SIZE: u32 = 32;
+
+/// Blocks are never split into pieces narrower than this.
+const MIN_BLOCK_SIZE: u32 = 4;
+
+/// Multiple of the error threshold below which blocks are split in two.
+const SPLIT_FACTOR: f64 = 4.;
+
+/// Running mean and variance of a pixel's samples, accumulated with Welford's algorithm.
+///
+/// The color mean covers the samples as they are, but the luminance statistics used to judge
+/// convergence clamp fireflies first. A single huge sample would otherwise inflate the variance
+/// so much that the pixel could never be considered converged. Fireflies are judged against the
+/// mean of the unclamped samples, and errors are measured relative to it, so that a pixel whose
+/// brightness comes mostly from rare bright samples is not clamped toward black.
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelStats {
+    count: u32,
+    mean: Vec3,
+    luminance_mean: f64,
+    luminance_m2: f64,
+    /// Mean of the (clamped) luminance of every other sample, starting with the first.
+    half_luminance_mean: f64,
+}
+
+impl PixelStats {
+    fn add(&mut self, sample: Vec3) {
+        let y = img::luminance(&sample);
+        let y = if self.count > 0 {
+            y.min(FIREFLY_FACTOR * self.unclamped_luminance_mean().max(DARK_LUMINANCE))
+        } else {
+            y
+        };
+
+        if self.count.is_multiple_of(2) {
+            let half_count = (self.count / 2 + 1) as f64;
+            self.half_luminance_mean += (y - self.half_luminance_mean) / half_count;
+        }
+
+        self.count += 1;
+        let inv_count = 1. / self.count as f64;
+
+        self.mean += (sample - self.mean) * inv_count;
+
+        let delta = y - self.luminance_mean;
+        self.luminance_mean += delta * inv_count;
+        self.luminance_m2 += delta * (y - self.luminance_mean);
+    }
+
+    /// Returns the standard error of the mean luminance, relative to the mean itself.
+    fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+
+        let variance = self.luminance_m2 / (self.count - 1) as f64;
+        let std_error = (variance / self.count as f64).sqrt();
+
+        // Keep dark pixels from demanding huge numbers of samples to resolve noise no one will
+        // ever see.
+        std_error / self.unclamped_luminance_mean().max(DARK_LUMINANCE)
+    }
+
+    /// Returns the mean luminance of the samples as they are, fireflies included.
+    fn unclamped_luminance_mean(&self) -> f64 {
+        img::luminance(&self.mean)
+    }
+}
+
+/// Renders an image, taking more samples in pixels whose estimates are still noisy.
+///
+/// Every pixel receives at least `adaptive.min_samples` samples and at most
+/// `opts.samples_per_pixel`, stopping once its relative error (as measured by `adaptive.metric`)
+/// drops below `adaptive.error_threshold`. Either way, each pixel's value is the plain mean of its
+/// samples; the metric only decides how many it gets.
+///
+/// Returns the image along with the number of samples taken in each pixel.
+pub fn render_adaptive(
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+    adaptive: &AdaptiveOptions,
+) -> (Vec<Vec3>, Vec<u32>) {
+    let pixel_width = camera.pixel_width();
+    let pixel_count = (pixel_width * camera.pixel_height()) as usize;
+
+    let max_samples = opts.samples_per_pixel.max(1);
+    let min_samples = adaptive.min_samples.clamp(1, max_samples);
+    let batch_size = adaptive.batch_size.max(1);
+
+    let mut stats = vec![PixelStats::default(); pixel_count];
+
+    // Stratification would need to know the final sample count up front.
+    let sample_up_to = |stats: &mut PixelStats, idx: usize, target: u32, rng: &mut dyn RngCore| {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+
+        while stats.count < target {
+            let ray = camera.cast_ray(px, py, rng);
+            stats.add(trace_ray(scene, ray, camera.far(), rng, opts));
+        }
+    };
+
+    match adaptive.metric {
+        ErrorMetric::PerPixel => {
+            stats.par_iter_mut().enumerate().for_each(|(idx, stats)| {
+                with_rng(opts.seed, idx as u64, |rng| {
+                    let mut target = min_samples;
+
+                    loop {
+                        sample_up_to(stats, idx, target, rng);
+
+                        if target == max_samples
+                            || stats.relative_error() < adaptive.error_threshold
+                        {
+                            break;
+                        }
+
+                        target = (target + batch_size).min(max_samples);
+                    }
+                });
+            });
+        }
+        ErrorMetric::Block => {
+            let mut blocks = tile_regions(camera, BLOCK_TILE_SIZE);
+            let mut target = min_samples;
+
+            for batch in 0.. {
+                let mut active = vec![false; pixel_count];
+                for block in &blocks {
+                    for idx in block_pixels(block, pixel_width) {
+                        active[idx] = true;
+                    }
+                }
+
+                stats
+                    .par_iter_mut()
+                    .enumerate()
+                    .filter(|(idx, _)| active[*idx])
+                    .for_each(|(idx, stats)| {
+                        let stream = ((batch as u64) << 32) | idx as u64;
+                        with_rng(opts.seed, stream, |rng| {
+                            sample_up_to(stats, idx, target, rng)
+                        });
+                    });
+
+                if target == max_samples {
+                    break;
+                }
+
+                blocks = blocks
+                    .into_iter()
+                    .flat_map(|block| {
+                        let error = block_error(&stats, pixel_width, &block);
+                        if error < adaptive.error_threshold {
+                            vec![]
+                        } else if error < SPLIT_FACTOR * adaptive.error_threshold {
+                            split_block(block)
+                        } else {
+                            vec![block]
+                        }
+                    })
+                    .collect();
+
+                if blocks.is_empty() {
+                    break;
+                }
+
+                target = (target + batch_size).min(max_samples);
+            }
+        }
+    }
+
+    stats
+        .iter()
+        .enumerate()
+        .map(|(idx, stats)| {
+            let px = idx as u32 % pixel_width;
+            let py = idx as u32 / pixel_width;
+            (stats.mean * camera.vignetting(px, py), stats.count)
+        })
+        .unzip()
+}
+
+/// Returns the indices of the pixels of `block` in an image `pixel_width` pixels wide.
+fn block_pixels(block: &Region, pixel_width: u32) -> impl Iterator<Item = usize> + '_ {
+    (block.y..block.y + block.height).flat_map(move |y| {
+        (block.x..block.x + block.width).map(move |x| (y * pixel_width + x) as usize)
+    })
+}
+
+/// Estimates the relative standard error of the pixels of `block`, pooled over the block.
+fn block_error(stats: &[PixelStats], pixel_width: u32, block: &Region) -> f64 {
+    let mut diff = 0.;
+    let mut total = 0.;
+
+    for idx in block_pixels(block, pixel_width) {
+        let stats = &stats[idx];
+        if stats.count < 2 {
+            return f64::INFINITY;
+        }
+
+        diff += (stats.luminance_mean - stats.half_luminance_mean).abs();
+        total += stats.unclamped_luminance_mean().max(DARK_LUMINANCE);
+    }
+
+    // The mean of all samples differs from that of half of them by half the difference between
+    // two independent estimates, which is on average √(2/π) times the standard error of the mean.
+    (f64::consts::FRAC_PI_2).sqrt() * diff / total
+}
+
+/// Splits `block` in two across its longer side, unless that would make it too small.
+fn split_block(block: Region) -> Vec<Region> {
+    if block.width >= block.height && block.width >= 2 * MIN_BLOCK_SIZE {
+        let left = block.width / 2;
+        vec![
+            Region {
+                width: left,
+                ..block
+            },
+            Region {
+                x: block.x + left,
+                width: block.width - left,
+                ..block
+            },
+        ]
+    } else if block.height >= 2 * MIN_BLOCK_SIZE {
+        let top = block.height / 2;
+        vec![
+            Region {
+                height: top,
+                ..block
+            },
+            Region {
+                y: block.y + top,
+                height: block.height - top,
+                ..block
+            },
+        ]
+    } else {
+        vec![block]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rare_bright_samples_are_not_clamped_away() {
+        // Most of this pixel's brightness comes from one sample in ten, which is within
+        // `FIREFLY_FACTOR` of the true mean but far beyond that of the dark samples alone.
+        let samples: Vec<f64> = (0..1000)
+            .map(|i| if i % 10 == 9 { 1. } else { 0.01 })
+            .collect();
+
+        let mut stats = PixelStats::default();
+        for &y in &samples {
+            stats.add(Vec3::from_element(y));
+        }
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples.iter().map(|y| (y - mean).powi(2)).sum::<f64>() / (n - 1.);
+        let expected = (variance / n).sqrt() / mean;
+
+        // Only the first few bright samples, seen while the mean is still dark, are clamped.
+        assert!((stats.luminance_mean / mean - 1.).abs() < 0.03);
+        assert!((stats.relative_error() / expected - 1.).abs() < 0.05);
+    }
+}
@@ -0,0 +1,74 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::math::Vec3;
+use crate::scene::Scene;
+
+use super::{
+    cast_sample_ray, trace_path, with_rng, Camera, PathOutputs, PathSettings, RenderOptions,
+};
+
+/// Renders an image along with its alpha channel, for compositing over a photograph or another
+/// background.
+///
+/// Objects are opaque and everything behind them, lights and the background included, is
+/// transparent. [Shadow catchers](crate::material::ShadowCatcher) are black, with an alpha
+/// giving the fraction of their light the scene blocks, so that laying the image over a
+/// photograph darkens it where the scene casts shadows.
+///
+/// Returns the pixels with straight (not premultiplied) colors, as [`to_rgba8`] expects, along
+/// with their alpha values.
+///
+/// [`to_rgba8`]: crate::img::to_rgba8
+pub fn render_with_alpha(
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+) -> (Vec<Vec3>, Vec<f64>) {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let pixel_count = (pixel_width * camera.pixel_height()) as usize;
+
+    let mut pixels = vec![(Vec3::default(), 0.); pixel_count];
+
+    pixels
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(idx, (color, alpha))| {
+            let px = idx as u32 % pixel_width;
+            let py = idx as u32 / pixel_width;
+
+            with_rng(opts.seed, idx as u64, |rng| {
+                for index in 0..opts.samples_per_pixel {
+                    let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+                    let outputs = PathOutputs {
+                        alpha: Some(alpha),
+                        ..PathOutputs::default()
+                    };
+                    let (radiance, bounces) = trace_path(
+                        scene,
+                        ray,
+                        camera.far(),
+                        rng,
+                        PathSettings::for_sample(opts, px, py, index),
+                        outputs,
+                    );
+
+                    // Paths that hit no object (or only a shadow catcher) are transparent, and
+                    // leave no color behind.
+                    if bounces > 0 {
+                        *color += radiance;
+                    }
+                }
+            });
+
+            // The sums are premultiplied by the coverage, which dividing by it undoes.
+            if *alpha > 0. {
+                *color /= *alpha;
+            }
+            *color *= camera.vignetting(px, py);
+            *alpha /= opts.samples_per_pixel as f64;
+        });
+
+    pixels.into_iter().unzip()
+}
@@ -0,0 +1,117 @@
+use rand::RngCore;
+
+use super::{permute, stream_seed, PixelSample};
+
+/// Returns point `sample.index` of a correlated multi-jittered pattern of `sample.count` points
+/// over `[0, 1)²`, as described in Kensler's "Correlated Multi-Jittered Sampling".
+///
+/// The points are jittered within the cells of a grid of about `√count` by `√count` cells, and
+/// additionally fall into distinct strata along each axis, so that both the pattern and its
+/// projections are evenly covered for any number of points. Different values of `pattern` give
+/// independently shuffled patterns.
+pub(super) fn cmj(sample: PixelSample, pattern: u32) -> [f64; 2] {
+    let count = sample.count;
+    let m = sample.grid_size();
+    let n = count.div_ceil(m);
+
+    let s = permute(sample.index, count, pattern.wrapping_mul(0x5163_3e2d));
+    let sx = permute(s % m, m, pattern.wrapping_mul(0x68bc_21eb));
+    let sy = permute(s / m, n, pattern.wrapping_mul(0x02e5_be93));
+    let jx = hash_to_unit(s, pattern.wrapping_mul(0x967a_889b));
+    let jy = hash_to_unit(s, pattern.wrapping_mul(0x368c_c8b7));
+
+    [
+        ((s % m) as f64 + (sy as f64 + jx) / n as f64) / m as f64,
+        ((s / m) as f64 + (sx as f64 + jy) / m as f64) / n as f64,
+    ]
+}
+
+/// Hashes `value` (keyed by `key`) to a number in `[0, 1)`.
+fn hash_to_unit(mut value: u32, key: u32) -> f64 {
+    value ^= key;
+    value ^= value >> 17;
+    value ^= value >> 10;
+    value = value.wrapping_mul(0xb365_34e5);
+    value ^= value >> 12;
+    value ^= value >> 21;
+    value = value.wrapping_mul(0x93fc_4795);
+    value ^= 0xdf6e_307f;
+    value ^= value >> 17;
+    value = value.wrapping_mul(1 | key >> 18);
+
+    value as f64 / (1u64 << 32) as f64
+}
+
+/// Identifies one of a pixel's samples along with the patterns its multi-jittered dimensions are
+/// drawn from.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MultiJitteredSample {
+    pub sample: PixelSample,
+    /// Key from which the pixel's patterns are derived, differing between pixels.
+    pub key: u64,
+}
+
+impl MultiJitteredSample {
+    /// Returns point `dimension` of the sample, where each dimension is a pair of coordinates
+    /// drawn from its own pattern.
+    pub fn point(&self, dimension: u64) -> [f64; 2] {
+        cmj(self.sample, stream_seed(self.key, dimension) as u32)
+    }
+}
+
+/// A generator whose values are drawn in turn from the coordinates of a multi-jittered sample,
+/// starting at a given dimension, so that code drawing from a [`RngCore`] (such as light and BSDF
+/// sampling) can be stratified across a pixel's samples without knowing about it.
+///
+/// Every value is uniformly distributed on its own, and values drawn as the same pair are
+/// stratified in two dimensions together.
+pub(super) struct MultiJitteredRng {
+    sample: MultiJitteredSample,
+    dimension: u64,
+    /// The second coordinate of the current point, if it has not been drawn yet.
+    pending: Option<f64>,
+}
+
+impl MultiJitteredRng {
+    pub fn new(sample: MultiJitteredSample, first_dimension: u64) -> Self {
+        Self {
+            sample,
+            dimension: first_dimension,
+            pending: None,
+        }
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        if let Some(value) = self.pending.take() {
+            return value;
+        }
+
+        let [x, y] = self.sample.point(self.dimension);
+        self.dimension += 1;
+        self.pending = Some(y);
+        x
+    }
+}
+
+impl RngCore for MultiJitteredRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // Floats drawn from the generator keep the leading bits, recovering the value.
+        (self.next_f64() * (1u128 << 64) as f64) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use super::splitmix64;
+
+/// Bases of the dimensions of the Halton sequence, the first primes, each with Faure's
+/// permutation of its digits. The permutations break up the correlation between dimensions of
+/// similar bases, which otherwise shows as diagonal structure in projections onto them.
+const BASES: [(u32, &[u32]); 4] = [
+    (2, &[0, 1]),
+    (3, &[0, 1, 2]),
+    (5, &[0, 3, 2, 1, 4]),
+    (7, &[0, 2, 5, 3, 1, 4, 6]),
+];
+
+/// Returns coordinate `dimension` of point `index` of the Halton sequence, in `[0, 1)`, with its
+/// digits scrambled according to `scramble`, or `None` if the sequence has no such dimension.
+///
+/// Every digit is shifted (modulo the base) by an amount drawn from `scramble` and the digit's
+/// position before being mapped through Faure's permutation. Sequences scrambled differently are
+/// each as evenly distributed as the original, but do not correlate with one another.
+pub(super) fn scrambled_halton(dimension: usize, index: u32, scramble: u64) -> Option<f64> {
+    let &(base, permutation) = BASES.get(dimension)?;
+    let inv_base = 1. / base as f64;
+
+    let mut index = index;
+    let mut scale = inv_base;
+    let mut position = 0;
+    let mut value = 0.;
+
+    // Scrambling turns the zeros beyond the index's leading digit into other digits, so keep going
+    // until they no longer affect the result.
+    while scale > f64::EPSILON {
+        let shift = (splitmix64(scramble ^ position) % base as u64) as u32;
+        value += permutation[((index % base + shift) % base) as usize] as f64 * scale;
+
+        index /= base;
+        scale *= inv_base;
+        position += 1;
+    }
+
+    Some(value.min(1. - f64::EPSILON / 2.))
+}
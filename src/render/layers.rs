@@ -0,0 +1,189 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::img::Image;
+use crate::math::Vec3;
+use crate::scene::Scene;
+
+use super::{
+    cast_sample_ray, trace_path, with_rng, Camera, PathOutputs, PathSettings, RenderOptions,
+};
+
+/// A component of the rendered image, classified by how light reached the camera.
+///
+/// "Diffuse" and "specular" refer to the first scattering event along the camera path: specular
+/// scattering follows a single (delta) direction, as off a mirror or through glass, while diffuse
+/// scattering covers everything else, glossy reflection included.
+///
+/// Every contribution to the image falls into exactly one layer, so the layers sum to the full
+/// image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    /// Light sources seen directly by the camera.
+    Emission,
+    /// Light from the given light (by index in the scene) reaching the first surface hit and
+    /// scattered diffusely toward the camera.
+    DirectDiffuse(usize),
+    /// Light from the given light seen through a single specular scattering event.
+    DirectSpecular(usize),
+    /// Everything else, after a diffuse first scattering event.
+    IndirectDiffuse,
+    /// Everything else, after a specular first scattering event.
+    IndirectSpecular,
+}
+
+impl Layer {
+    /// Returns every layer of an image of a scene with `light_count` lights, in the order of
+    /// [`Layer::index`].
+    pub fn all(light_count: usize) -> Vec<Layer> {
+        let mut layers = vec![
+            Layer::Emission,
+            Layer::IndirectDiffuse,
+            Layer::IndirectSpecular,
+        ];
+        for light in 0..light_count {
+            layers.push(Layer::DirectDiffuse(light));
+            layers.push(Layer::DirectSpecular(light));
+        }
+        layers
+    }
+
+    /// Returns the position of the layer in [`Layer::all`].
+    pub fn index(&self) -> usize {
+        match *self {
+            Layer::Emission => 0,
+            Layer::IndirectDiffuse => 1,
+            Layer::IndirectSpecular => 2,
+            Layer::DirectDiffuse(light) => 3 + 2 * light,
+            Layer::DirectSpecular(light) => 4 + 2 * light,
+        }
+    }
+
+    /// Returns a name for the layer suitable for use in file names.
+    pub fn name(&self) -> String {
+        match self {
+            Layer::Emission => "emission".to_owned(),
+            Layer::IndirectDiffuse => "indirect_diffuse".to_owned(),
+            Layer::IndirectSpecular => "indirect_specular".to_owned(),
+            Layer::DirectDiffuse(light) => format!("light{}_direct_diffuse", light),
+            Layer::DirectSpecular(light) => format!("light{}_direct_specular", light),
+        }
+    }
+
+    /// Returns the layer for light emitted by light `light` and found by a path after `depth`
+    /// scattering events, the first of which was specular if `first_specular` is set.
+    pub(super) fn emitted(depth: u32, first_specular: bool, light: usize) -> Self {
+        match depth {
+            0 => Layer::Emission,
+            // Emission is only found after specular scattering events.
+            1 => Layer::DirectSpecular(light),
+            _ => Layer::indirect(first_specular),
+        }
+    }
+
+    /// Returns the layer for light from light `light` sampled at a vertex `depth` scattering
+    /// events into a path.
+    pub(super) fn sampled(depth: u32, first_specular: bool, light: usize) -> Self {
+        match depth {
+            0 => Layer::DirectDiffuse(light),
+            _ => Layer::indirect(first_specular),
+        }
+    }
+
+    pub(super) fn indirect(first_specular: bool) -> Self {
+        if first_specular {
+            Layer::IndirectSpecular
+        } else {
+            Layer::IndirectDiffuse
+        }
+    }
+}
+
+/// An image split into [`Layer`]s.
+pub struct MultiLayerImage {
+    width: u32,
+    height: u32,
+    layers: Vec<Vec<Vec3>>,
+}
+
+impl MultiLayerImage {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns every layer along with its pixels, in the order of [`Layer::all`].
+    pub fn layers(&self) -> impl Iterator<Item = (Layer, &[Vec3])> {
+        Layer::all((self.layers.len() - 3) / 2)
+            .into_iter()
+            .zip(self.layers.iter().map(Vec::as_slice))
+    }
+
+    pub fn layer(&self, layer: Layer) -> &[Vec3] {
+        &self.layers[layer.index()]
+    }
+
+    /// Returns the full image, the sum of all layers.
+    pub fn beauty(&self) -> Image {
+        let mut pixels = vec![Vec3::default(); (self.width * self.height) as usize];
+        for layer in &self.layers {
+            for (pixel, value) in pixels.iter_mut().zip(layer) {
+                *pixel += value;
+            }
+        }
+
+        Image::new(self.width, self.height, pixels)
+    }
+}
+
+/// Renders an image split into [`Layer`]s. The paths traced are the same as those of
+/// [`render_to`](super::render_to), so the layers sum to the same image.
+pub fn render_layers(scene: &Scene, camera: &Camera, opts: &RenderOptions) -> MultiLayerImage {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let pixel_count = (pixel_width * camera.pixel_height()) as usize;
+    let layer_count = Layer::all(scene.lights().len()).len();
+
+    let mut pixels = vec![vec![Vec3::default(); layer_count]; pixel_count];
+
+    pixels.par_iter_mut().enumerate().for_each(|(idx, layers)| {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+
+        with_rng(opts.seed, idx as u64, |rng| {
+            for index in 0..opts.samples_per_pixel {
+                let ray = cast_sample_ray(camera, px, py, index, rng, opts);
+                let outputs = PathOutputs {
+                    layers: Some(layers.as_mut_slice()),
+                    ..PathOutputs::default()
+                };
+                trace_path(
+                    scene,
+                    ray,
+                    camera.far(),
+                    rng,
+                    PathSettings::for_sample(opts, px, py, index),
+                    outputs,
+                );
+            }
+        });
+
+        let scale = camera.vignetting(px, py) / opts.samples_per_pixel as f64;
+        for value in layers.iter_mut() {
+            *value *= scale;
+        }
+    });
+
+    let layers = (0..layer_count)
+        .map(|layer| pixels.iter().map(|layers| layers[layer]).collect())
+        .collect();
+
+    MultiLayerImage {
+        width: pixel_width,
+        height: camera.pixel_height(),
+        layers,
+    }
+}
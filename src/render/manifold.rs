@@ -0,0 +1,214 @@
+use nalgebra::Matrix2;
+use rand::RngCore;
+
+use crate::geom::HitInfo;
+use crate::light::Light;
+use crate::math::{OrthoNormalBasis, RayKind, Unit3, Vec2, Vec3, EPSILON};
+use crate::scene::Scene;
+use crate::shading::{Pdf, SampledRadiance};
+
+/// Maximum number of refractions along a connection: enough to pass into and back out of a single
+/// object.
+const MAX_REFRACTIONS: usize = 2;
+
+/// Maximum number of Newton iterations spent looking for a connection.
+const MAX_ITERATIONS: usize = 16;
+
+/// Largest change of direction (in radians, roughly) taken by a single Newton step.
+const MAX_STEP: f64 = 0.1;
+
+/// Change of direction used to estimate derivatives by finite differences.
+const DIFF_STEP: f64 = 1e-6;
+
+/// How closely a connection must pass by the light to be accepted, relative to its length.
+const TOLERANCE: f64 = 1e-5;
+
+/// The end of a path bent through refractive surfaces, leaving the last of them.
+struct RefractedPath {
+    last: HitInfo,
+    /// The (world) direction in which the path leaves `last`.
+    dir: Unit3,
+    transmittance: Vec3,
+    refractions: usize,
+}
+
+/// Connects `hit` to the delta light at `light_point` through the refractive surfaces blocking
+/// the line between them (the "manifold next-event estimation" of Hanika et al., restricted to
+/// refraction alone and to a single object), returning the light arriving at `hit` along the
+/// connection.
+///
+/// The connection is found by Newton's method over the direction leaving `hit`, starting from the
+/// straight line to the light. This finds at most one of the possibly many connections, so that
+/// strongly focusing objects (such as glass balls) cast caustics that are too dark, and gives up on
+/// any connection that crosses an edge or escapes the object. `None` is returned when no
+/// connection is found, or the line to the light was not blocked by a refractive surface to begin
+/// with.
+pub(super) fn sample_refracted_light(
+    light: &dyn Light,
+    scene: &Scene,
+    hit: &HitInfo,
+    light_point: Vec3,
+    rng: &mut dyn RngCore,
+) -> Option<SampledRadiance> {
+    let (mut dir, dist) = Unit3::new_and_get(light_point - hit.point);
+    let tolerance = TOLERANCE * dist;
+
+    let mut path = trace_refracted(scene, hit, dir, light_point)?;
+    let mut prev_error = f64::INFINITY;
+
+    for _ in 0..MAX_ITERATIONS {
+        let basis = OrthoNormalBasis::from_w(path.dir);
+        let offset = light_offset(&path, light_point, &basis);
+
+        let error = offset.norm();
+        let converged = error < tolerance;
+
+        // Newton's method converges quickly or not at all; don't waste time on a lost cause.
+        if !converged && error >= prev_error {
+            return None;
+        }
+        prev_error = error;
+
+        let jacobian = offset_jacobian(scene, hit, dir, &path, light_point, &basis)?;
+        if converged {
+            return refracted_radiance(light, hit, dir, &path, &jacobian, rng);
+        }
+
+        let mut step = -(jacobian.try_inverse()? * offset);
+        if step.norm() > MAX_STEP {
+            step *= MAX_STEP / step.norm();
+        }
+
+        dir = perturb(dir, step);
+        path = trace_refracted(scene, hit, dir, light_point)?;
+    }
+
+    None
+}
+
+/// Computes the light reaching `hit` along `dir` through the converged `path`.
+///
+/// A pencil of directions of solid angle `dω` leaving `hit` sweeps out an area `dA` (perpendicular
+/// to the path) at the light; by conservation of étendue, the light's intensity `I` then arrives
+/// at `hit` as irradiance `I dω / dA` (times the transmittance), which reduces to the usual
+/// `I / r²` when nothing is in the way.
+fn refracted_radiance(
+    light: &dyn Light,
+    hit: &HitInfo,
+    dir: Unit3,
+    path: &RefractedPath,
+    jacobian: &Matrix2<f64>,
+    rng: &mut dyn RngCore,
+) -> Option<SampledRadiance> {
+    let area_per_solid_angle = jacobian.determinant().abs();
+    if area_per_solid_angle == 0. {
+        return None;
+    }
+
+    let sample = light.sample_incident_at(&path.last, rng)?;
+    let dist = match (sample.radiance.pdf, sample.dist) {
+        (Pdf::Delta, Some(dist)) => dist,
+        _ => return None,
+    };
+    let intensity = sample.radiance.color * dist.powi(2);
+
+    Some(SampledRadiance::new_delta(
+        hit.world_to_local(dir),
+        intensity.component_mul(&path.transmittance) / area_per_solid_angle,
+    ))
+}
+
+/// Traces a ray from `hit` along `dir`, refracting it through every surface it meets before
+/// passing by `light_point`. Returns `None` if the ray meets a surface that does not refract, or
+/// too many that do, or passes by the light without refracting at all.
+fn trace_refracted(
+    scene: &Scene,
+    hit: &HitInfo,
+    dir: Unit3,
+    light_point: Vec3,
+) -> Option<RefractedPath> {
+    let mut ray = hit.spawn_world_ray(dir).with_kind(RayKind::Shadow);
+    let mut last = None;
+    let mut transmittance = Vec3::from_element(1.);
+    let mut refractions = 0;
+
+    loop {
+        let t_light = (light_point - ray.origin).dot(&ray.dir);
+        let next = if t_light > 0. {
+            scene.hit(&ray, EPSILON, t_light)
+        } else {
+            None
+        };
+
+        let next = match next {
+            Some(next) => next,
+            None => {
+                return last.map(|last| RefractedPath {
+                    last,
+                    dir: ray.dir,
+                    transmittance,
+                    refractions,
+                })
+            }
+        };
+
+        if refractions == MAX_REFRACTIONS {
+            return None;
+        }
+
+        let refraction = next
+            .material
+            .specular_refraction(&next.shading_info(&ray))?;
+
+        transmittance.component_mul_assign(&refraction.attenuation);
+        refractions += 1;
+        ray = next
+            .geom_hit
+            .spawn_local_ray(refraction.dir)
+            .with_kind(RayKind::Shadow);
+        last = Some(next.geom_hit);
+    }
+}
+
+/// Returns the offset from the line along which `path` leaves to `light_point`, in the `u`/`v`
+/// coordinates of `basis`.
+fn light_offset(path: &RefractedPath, light_point: Vec3, basis: &OrthoNormalBasis) -> Vec2 {
+    let to_light = light_point - path.last.point;
+    let offset = to_light - to_light.dot(&path.dir) * path.dir.into_inner();
+    Vec2::new(offset.dot(&basis.u()), offset.dot(&basis.v()))
+}
+
+/// Estimates the derivatives of [`light_offset`] with respect to changes of the direction leaving
+/// `hit` (as in [`perturb`]), by forward differences. Fails if a perturbed path does not refract
+/// the same number of times as `path`.
+fn offset_jacobian(
+    scene: &Scene,
+    hit: &HitInfo,
+    dir: Unit3,
+    path: &RefractedPath,
+    light_point: Vec3,
+    basis: &OrthoNormalBasis,
+) -> Option<Matrix2<f64>> {
+    let offset = light_offset(path, light_point, basis);
+
+    let derivative = |step: Vec2| {
+        let perturbed = trace_refracted(scene, hit, perturb(dir, step), light_point)?;
+        if perturbed.refractions != path.refractions {
+            return None;
+        }
+        Some((light_offset(&perturbed, light_point, basis) - offset) / DIFF_STEP)
+    };
+
+    let du = derivative(Vec2::new(DIFF_STEP, 0.))?;
+    let dv = derivative(Vec2::new(0., DIFF_STEP))?;
+    Some(Matrix2::from_columns(&[du, dv]))
+}
+
+/// Tilts `dir` by `step` within the plane perpendicular to it. Small steps change the direction
+/// by an angle of `step.norm()`, sweeping out a solid angle equal to their area.
+fn perturb(dir: Unit3, step: Vec2) -> Unit3 {
+    let basis = OrthoNormalBasis::from_w(dir);
+    Unit3::new_normalize(
+        dir.into_inner() + step[0] * basis.u().into_inner() + step[1] * basis.v().into_inner(),
+    )
+}
@@ -0,0 +1,73 @@
+use crate::geom::HitSide;
+use crate::material::{Material, Medium};
+use crate::scene::PrimitiveHit;
+
+/// How a path meets the surface of a refractive medium.
+pub(super) enum Interface {
+    /// The surface separates the medium from the one outside it, whose refractive index is
+    /// `exterior_ior`.
+    Real { exterior_ior: f64 },
+    /// The surface lies within a medium of higher priority, and should be passed through.
+    False,
+}
+
+/// The refractive media a path is inside of, in the order in which it entered them.
+///
+/// Media are told apart by their material, so that objects made of several primitives (such as
+/// meshes) can be entered through one and left through another.
+#[derive(Default)]
+pub(super) struct MediumStack {
+    entries: Vec<(usize, Medium)>,
+}
+
+impl MediumStack {
+    /// Classifies the surface at `hit`, or returns `None` if its material does not enclose a
+    /// medium.
+    pub fn interface(&self, hit: &PrimitiveHit<'_>) -> Option<Interface> {
+        let medium = hit.material.interior_medium()?;
+        let key = material_key(hit.material);
+
+        // When leaving the medium, the surface separates it from whatever would be left.
+        let skipped = match hit.geom_hit.side {
+            HitSide::Outside => None,
+            HitSide::Inside => self.entries.iter().rposition(|&(k, _)| k == key),
+        };
+
+        let outside = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| Some(idx) != skipped)
+            .map(|(_, &(_, medium))| medium)
+            .max_by_key(|medium| medium.priority);
+
+        match outside {
+            Some(outside) if outside.priority > medium.priority => Some(Interface::False),
+            _ => Some(Interface::Real {
+                exterior_ior: outside.map_or(1., |outside| outside.refractive_index),
+            }),
+        }
+    }
+
+    /// Records the path passing through the surface at `hit`, into or out of its medium.
+    pub fn cross(&mut self, hit: &PrimitiveHit<'_>) {
+        let medium = match hit.material.interior_medium() {
+            Some(medium) => medium,
+            None => return,
+        };
+        let key = material_key(hit.material);
+
+        match hit.geom_hit.side {
+            HitSide::Outside => self.entries.push((key, medium)),
+            HitSide::Inside => {
+                if let Some(idx) = self.entries.iter().rposition(|&(k, _)| k == key) {
+                    self.entries.remove(idx);
+                }
+            }
+        }
+    }
+}
+
+fn material_key(material: &dyn Material) -> usize {
+    material as *const dyn Material as *const () as usize
+}
@@ -0,0 +1,435 @@
+use std::f64;
+
+use rand::prelude::SliceRandom;
+use rand::RngCore;
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+
+use crate::math::{Aabb, Unit3, Vec3, EPSILON};
+use crate::scene::{PrimitiveHit, Scene};
+use crate::shading::{same_hemisphere, Pdf, ShadingInfo};
+
+use super::media::{Interface, MediumStack};
+use super::{
+    cast_sample_ray, trace_path, with_rng, Camera, PathOutputs, PathSettings, RenderOptions,
+};
+
+/// Options controlling the photon passes of [`render_photon_mapped`].
+#[derive(Debug, Clone, Copy)]
+pub struct PhotonOptions {
+    /// Number of photons emitted from the lights in every pass.
+    pub photons_per_pass: u32,
+    /// Radius within which photons are gathered in the first pass.
+    pub initial_radius: f64,
+    /// Fraction of the photons gathered in one pass that is kept in the next, in `(0, 1)`.
+    /// Smaller values shrink the radius faster, trading noise for a quicker loss of bias.
+    pub alpha: f64,
+}
+
+impl Default for PhotonOptions {
+    fn default() -> Self {
+        Self {
+            photons_per_pass: 100_000,
+            initial_radius: 0.02,
+            alpha: 2. / 3.,
+        }
+    }
+}
+
+/// A photon stored on a non-specular surface after reaching it by specular bounces alone.
+struct Photon {
+    point: Vec3,
+    /// Direction from which the photon arrived.
+    dir: Unit3,
+    /// Geometric normal of the surface, facing the side the photon arrived from.
+    normal: Unit3,
+    power: Vec3,
+}
+
+/// A balanced kd-tree of caustic photons, stored implicitly: the photon splitting a range of the
+/// array sits at its midpoint, with the photons on either side of the split to its left and right.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    radius: f64,
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>, radius: f64) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build_kd_tree(&mut photons, &mut axes);
+
+        Self {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    /// Estimates the radiance leaving `hit` toward the viewer due to light that reached it by
+    /// specular bounces alone, from the photons within the gather radius.
+    pub fn caustic_radiance(&self, hit: &PrimitiveHit<'_>, shading_info: &ShadingInfo) -> Vec3 {
+        let geom_hit = &hit.geom_hit;
+        let mut flux = Vec3::default();
+
+        self.for_each_near(geom_hit.point, 0, self.photons.len(), &mut |photon| {
+            // Skip photons on the far side of thin geometry or on surfaces facing elsewhere.
+            if photon.normal.dot(&geom_hit.geometric_normal) <= 0. {
+                return;
+            }
+
+            let dir = geom_hit.world_to_local(photon.dir);
+            if !geom_hit.is_consistent(dir) {
+                return;
+            }
+
+            flux += hit
+                .material
+                .bsdf(shading_info, dir)
+                .component_mul(&photon.power);
+        });
+
+        flux / (f64::consts::PI * self.radius.powi(2))
+    }
+
+    fn for_each_near(&self, point: Vec3, start: usize, end: usize, f: &mut dyn FnMut(&Photon)) {
+        if start >= end {
+            return;
+        }
+
+        let mid = start + (end - start) / 2;
+        let photon = &self.photons[mid];
+        let offset = point[self.axes[mid] as usize] - photon.point[self.axes[mid] as usize];
+
+        let (near, far) = if offset < 0. {
+            ((start, mid), (mid + 1, end))
+        } else {
+            ((mid + 1, end), (start, mid))
+        };
+
+        let radius_squared = self.radius.powi(2);
+
+        self.for_each_near(point, near.0, near.1, f);
+
+        if (point - photon.point).norm_squared() <= radius_squared {
+            f(photon);
+        }
+
+        if offset.powi(2) <= radius_squared {
+            self.for_each_near(point, far.0, far.1, f);
+        }
+    }
+}
+
+fn build_kd_tree(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let bounds = photons[1..]
+        .iter()
+        .fold(Aabb::at_point(photons[0].point), |aabb, photon| {
+            aabb.extend(photon.point)
+        });
+    let axis = bounds.max_extent_axis();
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |p1, p2| p1.point[axis].total_cmp(&p2.point[axis]));
+    axes[mid] = axis as u8;
+
+    let (left, rest) = photons.split_at_mut(mid);
+    let (left_axes, rest_axes) = axes.split_at_mut(mid);
+
+    build_kd_tree(left, left_axes);
+    build_kd_tree(&mut rest[1..], &mut rest_axes[1..]);
+}
+
+/// Renders an image by path tracing, with caustics (light reaching non-specular surfaces by
+/// specular bounces alone) gathered from photon maps instead.
+///
+/// Each of the `opts.samples_per_pixel` passes emits a fresh set of photons and traces one path
+/// per pixel. The gather radius shrinks from pass to pass as in probabilistic progressive photon
+/// mapping, so that the average of the passes converges even though each of them is biased.
+///
+/// Photons are emitted only by lights that support [`Light::sample_photon`]; light from other
+/// lights (such as the sky) casts no caustics.
+///
+/// [`Light::sample_photon`]: crate::light::Light::sample_photon
+pub fn render_photon_mapped(
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+    photon_opts: &PhotonOptions,
+) -> Vec<Vec3> {
+    opts.assert_valid();
+
+    let pixel_width = camera.pixel_width();
+    let pixel_count = (pixel_width * camera.pixel_height()) as usize;
+
+    let mut accum = vec![Vec3::default(); pixel_count];
+    let mut radius_squared = photon_opts.initial_radius.powi(2);
+
+    for pass in 1..=opts.samples_per_pixel {
+        let photon_map = PhotonMap::new(
+            trace_photons(scene, camera, opts, photon_opts, pass),
+            radius_squared.sqrt(),
+        );
+
+        accum
+            .par_chunks_mut(pixel_width as usize)
+            .enumerate()
+            .for_each(|(py, row)| {
+                let stream = ((pass as u64) << 32) | py as u64;
+
+                with_rng(opts.seed, stream, |rng| {
+                    for (px, pixel) in row.iter_mut().enumerate() {
+                        let ray =
+                            cast_sample_ray(camera, px as u32, py as u32, pass - 1, rng, opts);
+                        *pixel += trace_path(
+                            scene,
+                            ray,
+                            camera.far(),
+                            rng,
+                            PathSettings {
+                                caustics: Some(&photon_map),
+                                // The photons already carry the caustics regularization would
+                                // find.
+                                path_regularization: None,
+                                ..PathSettings::for_sample(opts, px as u32, py as u32, pass - 1)
+                            },
+                            PathOutputs::default(),
+                        )
+                        .0;
+                    }
+                });
+            });
+
+        radius_squared *= (pass as f64 + photon_opts.alpha) / (pass as f64 + 1.);
+    }
+
+    let inv_passes = 1. / opts.samples_per_pixel as f64;
+    for (idx, pixel) in accum.iter_mut().enumerate() {
+        let px = idx as u32 % pixel_width;
+        let py = idx as u32 / pixel_width;
+        *pixel *= inv_passes * camera.vignetting(px, py);
+    }
+
+    accum
+}
+
+/// Emits `photon_opts.photons_per_pass` photons for pass `pass`, returning those that landed on
+/// non-specular surfaces after one or more specular bounces.
+fn trace_photons(
+    scene: &Scene,
+    camera: &Camera,
+    opts: &RenderOptions,
+    photon_opts: &PhotonOptions,
+    pass: u32,
+) -> Vec<Photon> {
+    const CHUNK_SIZE: u32 = 4096;
+    const PHOTON_STREAMS: u64 = 1 << 63;
+
+    let count = photon_opts.photons_per_pass;
+    if count == 0 || scene.lights().is_empty() {
+        return Vec::new();
+    }
+
+    let power_scale = scene.lights().len() as f64 / count as f64;
+
+    (0..count.div_ceil(CHUNK_SIZE))
+        .into_par_iter()
+        .map(|chunk| {
+            let stream = PHOTON_STREAMS | ((pass as u64) << 32) | chunk as u64;
+            let chunk_count = CHUNK_SIZE.min(count - chunk * CHUNK_SIZE);
+
+            with_rng(opts.seed, stream, |rng| {
+                let mut photons = Vec::new();
+                for _ in 0..chunk_count {
+                    trace_photon(
+                        scene,
+                        camera,
+                        rng,
+                        opts.max_depth,
+                        power_scale,
+                        &mut photons,
+                    );
+                }
+                photons
+            })
+        })
+        .flatten()
+        .collect()
+}
+
+fn trace_photon(
+    scene: &Scene,
+    camera: &Camera,
+    rng: &mut dyn RngCore,
+    max_depth: u32,
+    power_scale: f64,
+    photons: &mut Vec<Photon>,
+) {
+    let emitted = match scene
+        .lights()
+        .choose(rng)
+        .and_then(|light| light.sample_photon(rng))
+    {
+        Some(emitted) => emitted,
+        None => return,
+    };
+
+    // Photons are emitted throughout the shutter interval, so that they land on moving geometry
+    // wherever the camera sees it.
+    let mut ray = emitted.ray.with_time(camera.sample_time(rng));
+    let mut power = emitted.power * power_scale;
+    let mut specular_bounce = false;
+    let mut media = MediumStack::default();
+
+    for _ in 0..max_depth {
+        let hit = match scene.hit(&ray, EPSILON, f64::INFINITY) {
+            Some(hit) => hit,
+            None => return,
+        };
+
+        let shading_info = match media.interface(&hit) {
+            Some(Interface::Real { exterior_ior }) => {
+                hit.shading_info(&ray).with_exterior_ior(exterior_ior)
+            }
+            Some(Interface::False) => {
+                media.cross(&hit);
+                ray = hit.geom_hit.spawn_world_ray(ray.dir);
+                continue;
+            }
+            None => hit.shading_info(&ray),
+        };
+
+        // Light reaching a surface directly is handled by sampling the lights, so only photons
+        // that have been focused by specular surfaces are worth storing.
+        if specular_bounce && !hit.material.is_always_specular() {
+            photons.push(Photon {
+                point: hit.geom_hit.point,
+                dir: -ray.dir,
+                normal: hit.geom_hit.geometric_normal,
+                power,
+            });
+        }
+
+        let sample = match hit
+            .material
+            .sample_bsdf(&shading_info, rng)
+            .filter(|sample| hit.geom_hit.is_consistent(sample.dir))
+        {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        if !matches!(sample.pdf, Pdf::Delta) {
+            return;
+        }
+
+        if !same_hemisphere(*sample.dir, *shading_info.outgoing) {
+            media.cross(&hit);
+        }
+
+        power.component_mul_assign(&sample.scaled_color());
+        if power == Vec3::default() {
+            return;
+        }
+
+        specular_bounce = true;
+        ray = hit.geom_hit.spawn_local_ray(sample.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    use crate::geom::{Plane, Sphere};
+    use crate::light::PointLight;
+    use crate::material::{Dielectric, Lambertian};
+    use crate::math::Ray;
+    use crate::render::{CameraOptions, Layer, PixelSampling};
+    use crate::scene::SceneBuilder;
+
+    use super::*;
+
+    #[test]
+    fn caustics_on_directly_seen_surfaces_are_indirect_diffuse() {
+        // A glass ball focusing a light onto the floor below it.
+        let mut builder = SceneBuilder::new();
+        builder.add_primitive(
+            Plane::new(Vec3::default(), Vec3::y_axis()),
+            Arc::new(Lambertian::new(Vec3::from_element(0.5))),
+        );
+        builder.add_primitive(
+            Sphere::new(Vec3::new(0., 1., 0.), 0.5),
+            Arc::new(Dielectric::new(1.5)),
+        );
+        builder.add_light(PointLight::new(
+            Vec3::new(0., 3., 0.),
+            Vec3::from_element(10.),
+        ));
+        let scene = builder.build();
+
+        let camera = Camera::new(&CameraOptions {
+            pixel_width: 1,
+            pixel_height: 1,
+            vert_fov: 50.,
+            aperture: 0.,
+            origin: Vec3::new(2., 0.2, 0.),
+            look_at: Vec3::default(),
+            vup: Vec3::new(0., 1., 0.),
+            focus_target: None,
+            far: f64::INFINITY,
+        })
+        .unwrap();
+
+        let opts = RenderOptions {
+            samples_per_pixel: 1,
+            max_depth: 4,
+            seed: Some(1),
+            sampling: PixelSampling::Independent,
+            indirect_clamp: None,
+            max_background_bounces: None,
+            path_regularization: None,
+            min_light_distance: None,
+            refracted_connections: false,
+        };
+        let photon_opts = PhotonOptions {
+            photons_per_pass: 10_000,
+            ..PhotonOptions::default()
+        };
+        let photon_map =
+            PhotonMap::new(trace_photons(&scene, &camera, &opts, &photon_opts, 1), 0.1);
+
+        // Look at the floor under the ball from the side, passing below it. Ending the path at
+        // the floor leaves the caustic as the only indirect light.
+        let mut layers = vec![Vec3::default(); Layer::all(1).len()];
+        let ray = Ray::new(
+            Vec3::new(2., 0.2, 0.),
+            -Unit3::new_normalize(Vec3::new(2., 0.2, 0.)),
+        );
+        trace_path(
+            &scene,
+            ray,
+            f64::INFINITY,
+            &mut Pcg64::seed_from_u64(1),
+            PathSettings {
+                max_depth: 1,
+                caustics: Some(&photon_map),
+                ..PathSettings::default()
+            },
+            PathOutputs {
+                layers: Some(&mut layers),
+                ..PathOutputs::default()
+            },
+        );
+
+        assert!(layers[Layer::IndirectDiffuse.index()][1] > 0.);
+        assert_eq!(layers[Layer::IndirectSpecular.index()], Vec3::default());
+    }
+}
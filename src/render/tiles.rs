@@ -0,0 +1,75 @@
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::img;
+use crate::math::Vec3;
+use crate::scene::Scene;
+
+use super::{render_region_to, Camera, Region, RenderOptions};
+
+/// A finished rectangle of the image, as produced by [`render_tiles_iter`].
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub region: Region,
+    /// The tile's linear RGB pixels, in row-major order within `region`.
+    pub pixels: Vec<Vec3>,
+}
+
+impl Tile {
+    /// Converts the tile to 8-bit sRGB, mapping luminance `max_luminance` to white.
+    ///
+    /// Unlike [`img::pixels_to_srgb`], this does not expose each tile for its own brightest
+    /// pixel, so that tiles converted with the same `max_luminance` fit together seamlessly.
+    pub fn to_srgb(&self, max_luminance: f64) -> Vec<u8> {
+        img::pixels_to_srgb_with_max_luminance(&self.pixels, max_luminance)
+    }
+}
+
+/// Renders the image in `tile_size` × `tile_size` tiles (smaller at the right and bottom edges)
+/// on a background thread, returning an iterator yielding the tiles in the order in which they
+/// are finished.
+///
+/// Every pixel comes out exactly as it would from [`render_to`](super::render_to), so
+/// reassembling the tiles reproduces the full render. Dropping the iterator stops the render once
+/// the tiles in flight are done.
+pub fn render_tiles_iter(
+    scene: Arc<Scene>,
+    camera: Arc<Camera>,
+    opts: RenderOptions,
+    tile_size: u32,
+) -> impl Iterator<Item = Tile> {
+    let (sender, receiver) = mpsc::channel();
+    let regions = tile_regions(&camera, tile_size.max(1));
+
+    thread::spawn(move || {
+        // Stop handing out tiles as soon as nobody is listening.
+        let _ = regions
+            .into_par_iter()
+            .try_for_each_with(sender, |sender, region| {
+                let mut pixels = vec![Vec3::default(); region.pixel_count()];
+                render_region_to(&mut pixels, &scene, &camera, &opts, &region);
+                sender.send(Tile { region, pixels })
+            });
+    });
+
+    receiver.into_iter()
+}
+
+pub(super) fn tile_regions(camera: &Camera, tile_size: u32) -> Vec<Region> {
+    let (width, height) = (camera.pixel_width(), camera.pixel_height());
+
+    (0..height)
+        .step_by(tile_size as usize)
+        .flat_map(|y| {
+            (0..width).step_by(tile_size as usize).map(move |x| Region {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            })
+        })
+        .collect()
+}
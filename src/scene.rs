@@ -1,16 +1,22 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use crate::geom::{Geom, HitInfo};
-use crate::light::Light;
+use crate::geom::{Geom, HitInfo, HitSide, RawHitInfo};
+use crate::light::{AmbientLight, Light};
 use crate::material::Material;
-use crate::math::Ray;
+use crate::math::{Aabb, Ray, RayKind, Unit3, Vec3, EPSILON};
 use crate::shading::ShadingInfo;
 
-use self::bvh::BvhNode;
 use self::prim::Primitive;
 
+pub use self::bvh::{Bvh, BvhItem};
+pub use self::validate::SceneWarning;
+
+pub mod generators;
+
 mod bvh;
 mod prim;
+mod validate;
 
 pub struct PrimitiveHit<'a> {
     pub geom_hit: HitInfo,
@@ -28,56 +34,400 @@ impl<'a> PrimitiveHit<'a> {
         ShadingInfo {
             side: self.geom_hit.side,
             outgoing,
+            point: self.geom_hit.point,
+            normal: self.geom_hit.world_normal(),
+            exterior_ior: 1.,
+            min_roughness: 0.,
+        }
+    }
+}
+
+/// The geometry of a hit found by [`Scene::intersect`], without any of the state used for
+/// shading it.
+#[derive(Debug, Clone, Copy)]
+pub struct Intersection {
+    /// Distance along the ray at which the hit occurred.
+    pub t: f64,
+    pub point: Vec3,
+    /// The normal of the surface, pointing out of the object whichever side it was hit from.
+    pub normal: Unit3,
+    pub side: HitSide,
+    pub material: MaterialId,
+    /// Position of the primitive hit among all those added to the scene builder.
+    pub primitive: usize,
+}
+
+/// Identifies a material in a scene's material table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialId(usize);
+
+impl MaterialId {
+    /// Returns the position of the material in [`Scene::materials`].
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The kinds of rays that can hit a primitive, for effects such as objects that cast shadows
+/// without being seen themselves. Primitives are visible to every kind of ray by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Visibility {
+    /// Whether the camera sees the primitive directly.
+    pub camera: bool,
+    /// Whether the primitive casts shadows.
+    pub shadow: bool,
+    /// Whether the primitive shows up in reflections and refractions, and in the light bouncing
+    /// off it onto other surfaces.
+    pub reflection: bool,
+}
+
+impl Visibility {
+    pub fn visible_to(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Secondary => self.reflection,
+        }
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            reflection: true,
         }
     }
 }
 
 pub struct SceneBuilder {
+    materials: Vec<Arc<dyn Material + Send + Sync>>,
+    /// Maps the address of every material in `materials` to its id.
+    material_ids: HashMap<usize, MaterialId>,
     primitives: Vec<Primitive>,
+    unbounded_primitives: Vec<Primitive>,
     lights: Vec<Arc<dyn Light + Send + Sync>>,
+    ambient: Option<AmbientLight>,
+    max_leaf_size: usize,
 }
 
 impl SceneBuilder {
     pub fn new() -> Self {
         Self {
+            materials: Vec::new(),
+            material_ids: HashMap::new(),
             primitives: Vec::new(),
+            unbounded_primitives: Vec::new(),
             lights: Vec::new(),
+            ambient: None,
+            max_leaf_size: 4,
         }
     }
 
+    /// Sets the maximum number of primitives stored in a single BVH leaf.
+    pub fn set_max_leaf_size(&mut self, max_leaf_size: usize) {
+        self.max_leaf_size = max_leaf_size;
+    }
+
+    /// Adds `material` to the scene's material table, returning its id. Adding the same `Arc`
+    /// again returns the id it was first given, so that it is stored only once.
+    pub fn add_material(&mut self, material: Arc<dyn Material + Send + Sync>) -> MaterialId {
+        let materials = &mut self.materials;
+
+        *self
+            .material_ids
+            .entry(material_address(&*material))
+            .or_insert_with(|| {
+                materials.push(material);
+                MaterialId(materials.len() - 1)
+            })
+    }
+
+    /// Adds a primitive to the scene, adding its material to the material table as with
+    /// [`SceneBuilder::add_material`].
     pub fn add_primitive(
         &mut self,
-        geom: impl Geom + Sync + 'static,
+        geom: impl Geom + Send + Sync + 'static,
         material: Arc<dyn Material + Send + Sync>,
     ) {
-        self.primitives.push(Primitive::new(geom, material))
+        let material = self.add_material(material);
+        self.add_primitive_with_material(geom, material);
+    }
+
+    /// Adds a primitive made of the material `material` from the table. Unbounded primitives
+    /// (such as infinite planes) are kept out of the BVH and tested against every ray.
+    ///
+    /// Panics if `material` was not returned by this builder.
+    pub fn add_primitive_with_material(
+        &mut self,
+        geom: impl Geom + Send + Sync + 'static,
+        material: MaterialId,
+    ) {
+        self.add_primitive_with_visibility(geom, material, Visibility::default());
+    }
+
+    /// Like [`SceneBuilder::add_primitive_with_material`], but hides the primitive from the kinds
+    /// of rays `visibility` excludes.
+    ///
+    /// Panics if `material` was not returned by this builder.
+    pub fn add_primitive_with_visibility(
+        &mut self,
+        geom: impl Geom + Send + Sync + 'static,
+        material: MaterialId,
+        visibility: Visibility,
+    ) {
+        assert!(material.0 < self.materials.len(), "unknown material id");
+
+        let index = self.primitives.len() + self.unbounded_primitives.len();
+        let prim = Primitive::new(geom, material, visibility, index);
+
+        if prim.geom.bounds().is_some() {
+            self.primitives.push(prim);
+        } else {
+            self.unbounded_primitives.push(prim);
+        }
     }
 
     pub fn add_light(&mut self, light: impl Light + Send + Sync + 'static) {
         self.lights.push(Arc::new(light))
     }
 
+    /// Adds a constant ambient term to the lighting, for previews where shadows should not be
+    /// pitch black. Scenes have no ambient light by default.
+    pub fn set_ambient(&mut self, ambient: AmbientLight) {
+        self.ambient = Some(ambient);
+    }
+
     pub fn build(self) -> Scene {
         Scene {
-            primitives: bvh::build(self.primitives),
+            materials: self.materials,
+            primitives: Bvh::build(self.primitives, self.max_leaf_size),
+            unbounded_primitives: self.unbounded_primitives,
             lights: self.lights,
+            ambient: self.ambient,
         }
     }
 }
 
+impl Default for SceneBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Scene {
-    primitives: Option<Box<BvhNode>>,
+    materials: Vec<Arc<dyn Material + Send + Sync>>,
+    primitives: Bvh<Primitive>,
+    unbounded_primitives: Vec<Primitive>,
     lights: Vec<Arc<dyn Light + Send + Sync>>,
+    ambient: Option<AmbientLight>,
 }
 
 impl Scene {
-    pub fn hit(&self, ray: &Ray, t_max: f64) -> Option<PrimitiveHit<'_>> {
-        let (prim, raw) = self.primitives.as_ref()?.hit(ray, t_max)?;
-        let geom_hit = HitInfo::from_raw(ray, &raw);
-        Some(PrimitiveHit::new(geom_hit, &*prim.material))
+    /// Finds the closest hit along `ray` between `t_min` and `t_max`, ignoring primitives whose
+    /// [`Visibility`] hides them from rays of its kind, and prepares it for shading.
+    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<PrimitiveHit<'_>> {
+        let intersection = self.intersect(ray, t_min, t_max)?;
+
+        let raw = RawHitInfo {
+            t: intersection.t,
+            outward_normal: intersection.normal,
+        };
+        Some(PrimitiveHit::new(
+            HitInfo::from_raw(ray, &raw),
+            &*self.materials[intersection.material.0],
+        ))
+    }
+
+    /// Like [`Scene::hit`], but returns only the geometry of the hit, for uses of the scene other
+    /// than rendering (such as baking ambient occlusion).
+    pub fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<Intersection> {
+        let mut closest = self.primitives.hit(ray, t_min, t_max);
+        let mut t_max = closest.as_ref().map_or(t_max, |(_, raw)| raw.t);
+
+        for prim in &self.unbounded_primitives {
+            if let Some(raw) = BvhItem::hit(prim, ray, t_min, t_max) {
+                t_max = raw.t;
+                closest = Some((prim, raw));
+            }
+        }
+
+        let (prim, raw) = closest?;
+        let side = if ray.dir.dot(&raw.outward_normal) > 0. {
+            HitSide::Inside
+        } else {
+            HitSide::Outside
+        };
+
+        Some(Intersection {
+            t: raw.t,
+            point: ray.at(raw.t),
+            normal: raw.outward_normal,
+            side,
+            material: prim.material,
+            primitive: prim.index,
+        })
+    }
+
+    /// Returns the number of BVH nodes tested when finding the closest hit along `ray`.
+    pub fn count_bvh_node_tests(&self, ray: &Ray, t_min: f64, t_max: f64) -> u32 {
+        self.primitives.count_node_tests(ray, t_min, t_max)
+    }
+
+    /// Returns the fraction of light surviving along `ray` up to `t_max`, attenuated by every
+    /// transparent surface it passes through. Any opaque surface blocks the light completely.
+    pub fn transmittance(&self, ray: &Ray, t_max: f64) -> Vec3 {
+        const MAX_TRANSPARENT_HITS: u32 = 16;
+
+        let mut ray = *ray;
+        let mut t_max = t_max;
+        let mut transmittance = Vec3::from_element(1.);
+
+        for _ in 0..MAX_TRANSPARENT_HITS {
+            let hit = match self.hit(&ray, EPSILON, t_max) {
+                Some(hit) => hit,
+                None => return transmittance,
+            };
+
+            match hit.material.shadow_transmittance(&hit.shading_info(&ray)) {
+                Some(factor) => transmittance.component_mul_assign(&factor),
+                None => return Vec3::default(),
+            }
+
+            t_max -= hit.geom_hit.t;
+            ray = hit.geom_hit.spawn_world_ray(ray.dir).with_kind(ray.kind);
+        }
+
+        Vec3::default()
+    }
+
+    /// Returns the bounds of all bounded primitives in the scene, or `None` if there are none.
+    /// Unbounded primitives and lights are not included.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.primitives.bounds()
+    }
+
+    /// Returns the number of primitives in the scene, bounded or not. This is the number of
+    /// primitives added to the builder, however they are grouped into BVH leaves.
+    pub fn primitive_count(&self) -> usize {
+        self.primitives.items().len() + self.unbounded_primitives.len()
+    }
+
+    /// Returns the material table, indexed by [`MaterialId::index`].
+    pub fn materials(&self) -> &[Arc<dyn Material + Send + Sync>] {
+        &self.materials
+    }
+
+    pub fn material(&self, id: MaterialId) -> &Arc<dyn Material + Send + Sync> {
+        &self.materials[id.0]
+    }
+
+    /// Replaces the material `id`, changing the look of every primitive made of it.
+    pub fn set_material(&mut self, id: MaterialId, material: Arc<dyn Material + Send + Sync>) {
+        self.materials[id.0] = material;
     }
 
     pub fn lights(&self) -> &[Arc<dyn Light + Send + Sync>] {
         &self.lights
     }
+
+    pub fn light_count(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn ambient(&self) -> Option<&AmbientLight> {
+        self.ambient.as_ref()
+    }
+}
+
+fn material_address(material: &(dyn Material + Send + Sync)) -> usize {
+    material as *const (dyn Material + Send + Sync) as *const () as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::geom::Sphere;
+    use crate::material::Lambertian;
+
+    use super::*;
+
+    fn lambertian() -> Arc<dyn Material + Send + Sync> {
+        Arc::new(Lambertian::new(Vec3::from_element(0.5)))
+    }
+
+    #[test]
+    fn intersect_matches_analytic_sphere() {
+        let mut builder = SceneBuilder::new();
+        let other = builder.add_material(lambertian());
+        let material = builder.add_material(lambertian());
+        builder.add_primitive_with_material(Sphere::new(Vec3::new(5., 0., 0.), 1.), other);
+        builder.add_primitive_with_material(Sphere::new(Vec3::new(0., 0., -3.), 1.), material);
+        let scene = builder.build();
+
+        // A ray offset from the center by `d` hits a unit sphere `√(1 - d²)` before its center.
+        let d: f64 = 0.6;
+        let ray = Ray::new(
+            Vec3::new(d, 0., 0.),
+            Unit3::new_normalize(Vec3::new(0., 0., -1.)),
+        );
+        let hit = scene.intersect(&ray, EPSILON, f64::INFINITY).unwrap();
+
+        let depth = (1. - d.powi(2)).sqrt();
+        let expected_point = Vec3::new(d, 0., -3. + depth);
+        assert!((hit.t - (3. - depth)).abs() < 1e-12);
+        assert!((hit.point - expected_point).norm() < 1e-12);
+        assert!((*hit.normal - Vec3::new(d, 0., depth)).norm() < 1e-12);
+        assert_eq!(hit.side, HitSide::Outside);
+        assert_eq!(hit.material, material);
+        assert_eq!(hit.primitive, 1);
+
+        // From inside, the far side is hit, with the normal still pointing outward.
+        let inner = Ray::new(
+            Vec3::new(0., 0., -3.),
+            Unit3::new_normalize(Vec3::new(0., 1., 0.)),
+        );
+        let hit = scene.intersect(&inner, EPSILON, f64::INFINITY).unwrap();
+        assert!((hit.t - 1.).abs() < 1e-12);
+        assert!((*hit.normal - Vec3::new(0., 1., 0.)).norm() < 1e-12);
+        assert_eq!(hit.side, HitSide::Inside);
+
+        let miss = Ray::new(
+            Vec3::new(2., 0., 0.),
+            Unit3::new_normalize(Vec3::new(0., 0., -1.)),
+        );
+        assert!(scene.intersect(&miss, EPSILON, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn t_min_skips_nearby_hits() {
+        let mut builder = SceneBuilder::new();
+        let material = builder.add_material(lambertian());
+        builder.add_primitive_with_material(Sphere::new(Vec3::new(0., 0., -0.3), 0.1), material);
+        builder.add_primitive_with_material(Sphere::new(Vec3::new(0., 0., -2.), 0.5), material);
+        let scene = builder.build();
+
+        let ray = Ray::new(
+            Vec3::default(),
+            Unit3::new_normalize(Vec3::new(0., 0., -1.)),
+        );
+
+        let near = scene.intersect(&ray, EPSILON, f64::INFINITY).unwrap();
+        assert!((near.t - 0.2).abs() < 1e-12);
+
+        // Both hits on the near sphere (at 0.2 and 0.4) lie before `t_min`.
+        let far = scene.intersect(&ray, 0.5, f64::INFINITY).unwrap();
+        assert!((far.t - 1.5).abs() < 1e-12);
+        assert_eq!(
+            scene.hit(&ray, 0.5, f64::INFINITY).unwrap().geom_hit.t,
+            far.t
+        );
+
+        // A `t_min` between the near sphere's two hits finds its far side.
+        let inside = scene.intersect(&ray, 0.3, f64::INFINITY).unwrap();
+        assert!((inside.t - 0.4).abs() < 1e-12);
+        assert_eq!(inside.side, HitSide::Inside);
+
+        assert!(scene.intersect(&ray, 0.5, 1.).is_none());
+    }
 }
@@ -1,115 +1,428 @@
-use crate::geom::RawHitInfo;
-use crate::math::{Aabb, Ray, Vec3, EPSILON};
+use std::ops::Range;
+
+use crate::geom::{Geom, RawHitInfo};
+use crate::math::{Aabb, Ray, Vec3};
 
 use super::Primitive;
 
-enum BvhNodeData {
+/// Something that can be stored in a [`Bvh`]: an object with bounds, which rays may hit. Every
+/// [`Geom`] is one.
+pub trait BvhItem {
+    /// Returns the bounds of the item, or `None` if it extends infinitely, which keeps it out of
+    /// any BVH.
+    fn bounds(&self) -> Option<Aabb>;
+    /// Returns the closest hit along `ray` between `t_min` and `t_max`, if any.
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo>;
+}
+
+impl<G: Geom + ?Sized> BvhItem for G {
+    fn bounds(&self) -> Option<Aabb> {
+        Geom::bounds(self)
+    }
+
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
+        Geom::hit(self, ray, t_min, t_max)
+    }
+}
+
+impl BvhItem for Primitive {
+    fn bounds(&self) -> Option<Aabb> {
+        self.geom.bounds()
+    }
+
+    /// Primitives are invisible to the kinds of rays their [`Visibility`](super::Visibility)
+    /// excludes.
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<RawHitInfo> {
+        if !self.visibility.visible_to(ray.kind) {
+            return None;
+        }
+
+        self.geom.hit(ray, t_min, t_max)
+    }
+}
+
+enum BvhNodeData<T> {
     Leaf {
-        prim: Primitive,
+        items: Vec<T>,
     },
     Interior {
-        left: Box<BvhNode>,
-        right: Box<BvhNode>,
+        left: Box<BvhNode<T>>,
+        right: Box<BvhNode<T>>,
     },
 }
 
-pub struct BvhNode {
+struct BvhNode<T> {
     bounds: Aabb,
-    data: BvhNodeData,
+    data: BvhNodeData<T>,
 }
 
-impl BvhNode {
-    pub fn hit(&self, ray: &Ray, t_max: f64) -> Option<(&Primitive, RawHitInfo)> {
-        if !self.bounds.hit(ray, EPSILON, t_max) {
-            return None;
+struct FlatBvhNode {
+    bounds: Aabb,
+    /// Index of the node following this node's subtree in depth-first order, where traversal
+    /// resumes if the ray misses `bounds`.
+    skip: usize,
+    /// Items stored in this node; empty for interior nodes.
+    items: Range<usize>,
+}
+
+/// A bounding volume hierarchy over items of type `T`, accelerating the search for the closest
+/// item along a ray.
+///
+/// The hierarchy is flattened into depth-first order, so that traversal walks a contiguous array
+/// instead of chasing pointers.
+pub struct Bvh<T> {
+    nodes: Vec<FlatBvhNode>,
+    items: Vec<T>,
+}
+
+impl<T: BvhItem> Bvh<T> {
+    /// Builds a hierarchy over `items`, with at most `max_leaf_size` (at least 1) items in each
+    /// leaf. The hierarchy depends only on the bounds of the items and their order.
+    ///
+    /// Panics if any of the items is unbounded.
+    pub fn build(items: impl IntoIterator<Item = T>, max_leaf_size: usize) -> Self {
+        let root = do_build(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(order, item)| {
+                    let bounds = item
+                        .bounds()
+                        .expect("unbounded items cannot be stored in a BVH");
+
+                    TaggedItem {
+                        item,
+                        order,
+                        bounds,
+                        centroid: bounds.centroid(),
+                    }
+                })
+                .collect(),
+            max_leaf_size.max(1),
+        );
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            items: Vec::new(),
+        };
+
+        if let Some(root) = root {
+            bvh.flatten(*root);
         }
 
-        match &self.data {
-            BvhNodeData::Leaf { prim } => prim.geom.hit(ray, t_max).map(|info| (prim, info)),
-            BvhNodeData::Interior { left, right } => {
-                let left_hit = left.hit(ray, t_max);
-                let right_hit = right.hit(ray, left_hit.map_or(t_max, |(_prim, info)| info.t));
+        bvh
+    }
+
+    /// Returns the items in the hierarchy, in no particular order.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Returns the bounds of everything in the hierarchy, or `None` if it is empty.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|root| root.bounds)
+    }
+
+    /// Finds the closest item hit along `ray` between `t_min` and `t_max`.
+    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(&T, RawHitInfo)> {
+        self.traverse(ray, t_min, t_max, || {})
+    }
+
+    /// Returns the number of nodes whose bounds are tested when finding the closest hit along
+    /// `ray`.
+    pub fn count_node_tests(&self, ray: &Ray, t_min: f64, t_max: f64) -> u32 {
+        let mut count = 0;
+        self.traverse(ray, t_min, t_max, || count += 1);
+        count
+    }
+
+    /// Finds the closest hit along `ray`, invoking `on_node_test` for each node whose bounds are
+    /// tested along the way.
+    fn traverse(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        mut on_node_test: impl FnMut(),
+    ) -> Option<(&T, RawHitInfo)> {
+        let mut closest = None;
+        let mut t_max = t_max;
+
+        let mut idx = 0;
+        while let Some(node) = self.nodes.get(idx) {
+            on_node_test();
+
+            if !node.bounds.hit(ray, t_min, t_max) {
+                idx = node.skip;
+                continue;
+            }
 
-                match (left_hit, right_hit) {
-                    (None, Some(hit)) => Some(hit),
-                    (Some((_priml, il)), Some((primr, ir))) if ir.t < il.t => Some((primr, ir)),
-                    _ => left_hit,
+            for item in &self.items[node.items.clone()] {
+                if let Some(info) = item.hit(ray, t_min, t_max) {
+                    t_max = info.t;
+                    closest = Some((item, info));
                 }
             }
+
+            idx += 1;
         }
+
+        closest
     }
-}
 
-pub fn build(primitives: impl IntoIterator<Item = Primitive>) -> Option<Box<BvhNode>> {
-    do_build(
-        primitives
-            .into_iter()
-            .map(|prim| {
-                let bounds = prim.geom.bounds();
-
-                TaggedPrimitive {
-                    prim,
-                    bounds,
-                    centroid: bounds.centroid(),
-                }
-            })
-            .collect(),
-    )
+    fn flatten(&mut self, node: BvhNode<T>) {
+        let idx = self.nodes.len();
+        let items_start = self.items.len();
+
+        self.nodes.push(FlatBvhNode {
+            bounds: node.bounds,
+            skip: 0,
+            items: items_start..items_start,
+        });
+
+        match node.data {
+            BvhNodeData::Leaf { items } => {
+                self.items.extend(items);
+                self.nodes[idx].items = items_start..self.items.len();
+            }
+            BvhNodeData::Interior { left, right } => {
+                self.flatten(*left);
+                self.flatten(*right);
+            }
+        }
+
+        self.nodes[idx].skip = self.nodes.len();
+    }
 }
 
-struct TaggedPrimitive {
-    prim: Primitive,
+struct TaggedItem<T> {
+    item: T,
+    /// Position of the item in the input, which breaks ties between items.
+    order: usize,
     bounds: Aabb,
     centroid: Vec3,
 }
 
-fn do_build(mut tagged_primitives: Vec<TaggedPrimitive>) -> Option<Box<BvhNode>> {
-    if tagged_primitives.is_empty() {
+fn do_build<T>(
+    mut tagged_items: Vec<TaggedItem<T>>,
+    max_leaf_size: usize,
+) -> Option<Box<BvhNode<T>>> {
+    if tagged_items.is_empty() {
         return None;
     }
 
-    if tagged_primitives.len() == 1 {
-        let first = tagged_primitives.pop().unwrap();
+    let bounds = tagged_items[1..]
+        .iter()
+        .fold(tagged_items[0].bounds, |aabb, next| {
+            aabb.union(&next.bounds)
+        });
+
+    if tagged_items.len() <= max_leaf_size {
+        // The partitioning below leaves each half in no particular order.
+        tagged_items.sort_unstable_by_key(|ti| ti.order);
 
         return Some(Box::new(BvhNode {
-            bounds: first.bounds,
-            data: BvhNodeData::Leaf { prim: first.prim },
+            bounds,
+            data: BvhNodeData::Leaf {
+                items: tagged_items.into_iter().map(|ti| ti.item).collect(),
+            },
         }));
     }
 
-    let bounds = tagged_primitives[1..]
-        .iter()
-        .fold(tagged_primitives[0].bounds, |aabb, next| {
-            aabb.union(&next.bounds)
-        });
-
     // Partition the boxes by centroid values, using the axis along which the extent spanned by the
     // centroids is the longest.
 
-    let centroid_bounds = tagged_primitives[1..].iter().fold(
-        Aabb::at_point(tagged_primitives[0].centroid),
-        |aabb, next| aabb.extend(next.centroid),
-    );
+    let centroid_bounds = tagged_items[1..]
+        .iter()
+        .fold(Aabb::at_point(tagged_items[0].centroid), |aabb, next| {
+            aabb.extend(next.centroid)
+        });
 
-    let longest_axis = (centroid_bounds.max_point - centroid_bounds.min_point).imax();
-    let mid = tagged_primitives.len() / 2;
+    let longest_axis = centroid_bounds.max_extent_axis();
+    let mid = tagged_items.len() / 2;
 
-    tagged_primitives.select_nth_unstable_by(mid, |tp1, tp2| {
-        tp1.centroid[longest_axis]
-            .partial_cmp(&tp2.centroid[longest_axis])
-            .unwrap()
+    // Ties (including the case where all centroids coincide) are broken by input order, which
+    // keeps the split at the midpoint and the tree balanced, and makes the build deterministic.
+    // A total order keeps items with NaN bounds from aborting the build; scenes report them from
+    // `Scene::validate` instead.
+    tagged_items.select_nth_unstable_by(mid, |ti1, ti2| {
+        ti1.centroid[longest_axis]
+            .total_cmp(&ti2.centroid[longest_axis])
+            .then(ti1.order.cmp(&ti2.order))
     });
 
     let (left, right) = {
-        let right = tagged_primitives.split_off(mid);
-        (tagged_primitives, right)
+        let right = tagged_items.split_off(mid);
+        (tagged_items, right)
     };
 
     Some(Box::new(BvhNode {
         bounds,
         data: BvhNodeData::Interior {
-            left: do_build(left).unwrap(),
-            right: do_build(right).unwrap(),
+            left: do_build(left, max_leaf_size).unwrap(),
+            right: do_build(right, max_leaf_size).unwrap(),
         },
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    use crate::geom::Sphere;
+    use crate::math::{Unit3, EPSILON};
+
+    use super::*;
+
+    /// An item identified by `id`, which is never hit.
+    struct Marker {
+        id: usize,
+        bounds: Aabb,
+    }
+
+    impl BvhItem for Marker {
+        fn bounds(&self) -> Option<Aabb> {
+            Some(self.bounds)
+        }
+
+        fn hit(&self, _ray: &Ray, _t_min: f64, _t_max: f64) -> Option<RawHitInfo> {
+            None
+        }
+    }
+
+    /// Returns the ids stored in each leaf of `bvh`, in depth-first order.
+    fn leaf_ids(bvh: &Bvh<Marker>) -> Vec<Vec<usize>> {
+        bvh.nodes
+            .iter()
+            .filter(|node| !node.items.is_empty())
+            .map(|node| bvh.items[node.items.clone()].iter().map(|m| m.id).collect())
+            .collect()
+    }
+
+    /// Builds the leaves [`Bvh::build`] should produce for `items` by fully sorting each node
+    /// along its split axis, breaking ties by input order.
+    fn reference_leaf_ids(items: &[(usize, Aabb)], max_leaf_size: usize) -> Vec<Vec<usize>> {
+        // Items are tagged with their position in the original input.
+        fn build(mut items: Vec<(usize, usize, Aabb)>, max_leaf_size: usize) -> Vec<Vec<usize>> {
+            if items.len() <= max_leaf_size {
+                items.sort_by_key(|&(order, _, _)| order);
+                return vec![items.iter().map(|&(_, id, _)| id).collect()];
+            }
+
+            let centroids = items[1..]
+                .iter()
+                .fold(Aabb::at_point(items[0].2.centroid()), |aabb, item| {
+                    aabb.extend(item.2.centroid())
+                });
+            let axis = centroids.max_extent_axis();
+
+            items.sort_by(|(o1, _, b1), (o2, _, b2)| {
+                b1.centroid()[axis]
+                    .total_cmp(&b2.centroid()[axis])
+                    .then(o1.cmp(o2))
+            });
+
+            let right = items.split_off(items.len() / 2);
+            let mut leaves = build(items, max_leaf_size);
+            leaves.extend(build(right, max_leaf_size));
+            leaves
+        }
+
+        build(
+            items
+                .iter()
+                .enumerate()
+                .map(|(order, &(id, bounds))| (order, id, bounds))
+                .collect(),
+            max_leaf_size,
+        )
+    }
+
+    #[test]
+    fn build_breaks_ties_deterministically() {
+        let mut rng = Pcg64::seed_from_u64(132);
+
+        // Few distinct positions, so that many centroids coincide along every axis.
+        let mut items: Vec<_> = (0..200)
+            .map(|id| {
+                let center = Vec3::new(
+                    rng.gen_range(0..4) as f64,
+                    rng.gen_range(0..3) as f64,
+                    rng.gen_range(0..2) as f64,
+                );
+                let half = Vec3::from_element(rng.gen_range(1..3) as f64 / 4.);
+                (id, Aabb::new(center - half, center + half))
+            })
+            .collect();
+        items.shuffle(&mut rng);
+
+        let build = || Bvh::build(items.iter().map(|&(id, bounds)| Marker { id, bounds }), 4);
+
+        let first = build();
+        let second = build();
+
+        assert_eq!(leaf_ids(&first), leaf_ids(&second));
+        assert_eq!(
+            first.nodes.iter().map(|node| node.skip).collect::<Vec<_>>(),
+            second
+                .nodes
+                .iter()
+                .map(|node| node.skip)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(leaf_ids(&first), reference_leaf_ids(&items, 4));
+    }
+
+    #[test]
+    fn sphere_bvh_finds_closest_hits() {
+        let mut rng = Pcg64::seed_from_u64(194);
+
+        let spheres: Vec<_> = (0..100)
+            .map(|_| {
+                let center = Vec3::from_fn(|_, _| rng.gen_range(-5.0..5.0));
+                Sphere::new(center, rng.gen_range(0.1..0.6))
+            })
+            .collect();
+        let bvh = Bvh::build(
+            spheres
+                .iter()
+                .map(|sphere| Sphere::new(sphere.center, sphere.radius)),
+            2,
+        );
+
+        assert_eq!(bvh.items().len(), spheres.len());
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let origin = Vec3::from_fn(|_, _| rng.gen_range(-8.0..8.0));
+            let dir = Unit3::new_normalize(Vec3::from_fn(|_, _| rng.gen_range(-1.0..1.0)));
+            let ray = Ray::new(origin, dir);
+
+            let expected = spheres
+                .iter()
+                .filter_map(|sphere| Geom::hit(sphere, &ray, EPSILON, f64::INFINITY))
+                .map(|raw| raw.t)
+                .min_by(f64::total_cmp);
+
+            match (bvh.hit(&ray, EPSILON, f64::INFINITY), expected) {
+                (Some((sphere, raw)), Some(t)) => {
+                    hits += 1;
+                    assert_eq!(raw.t, t);
+                    let dist = (ray.at(raw.t) - sphere.center).norm();
+                    assert!((dist - sphere.radius).abs() < 1e-9);
+                }
+                (None, None) => {}
+                (found, expected) => panic!(
+                    "BVH found {:?}, expected {:?}",
+                    found.map(|(_, raw)| raw.t),
+                    expected
+                ),
+            }
+        }
+
+        // Make sure the rays exercise both outcomes.
+        assert!(hits > 50 && hits < 950, "{} hits", hits);
+    }
+}
@@ -0,0 +1,143 @@
+//! Procedural scene generators.
+
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::geom::Sphere;
+use crate::light::GradientEnvironment;
+use crate::material::{Dielectric, Lambertian, Material, Mirror};
+use crate::math::Vec3;
+
+use super::{Scene, SceneBuilder};
+
+pub struct RandomSphereParams {
+    /// Small spheres are placed on the integer grid spanning `-grid_range..grid_range` along
+    /// both the x and z axes.
+    pub grid_range: i32,
+    /// Probability that a small sphere is diffuse.
+    pub diffuse_probability: f64,
+    /// Probability that a small sphere is metallic. Spheres that are neither diffuse nor
+    /// metallic are made of glass.
+    pub metal_probability: f64,
+    pub ground_material: Arc<dyn Material + Send + Sync>,
+}
+
+impl Default for RandomSphereParams {
+    fn default() -> Self {
+        Self {
+            grid_range: 11,
+            diffuse_probability: 0.8,
+            metal_probability: 0.15,
+            ground_material: Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        }
+    }
+}
+
+/// Generates the scene from the cover of "Ray Tracing in One Weekend": a field of small random
+/// spheres around three large ones, lit by a sky gradient.
+///
+/// The layout depends only on the values drawn from `rng`, so a seeded generator always produces
+/// the same scene.
+pub fn random_spheres(rng: &mut dyn RngCore, params: RandomSphereParams) -> Scene {
+    let mut builder = SceneBuilder::new();
+
+    builder.add_primitive(
+        Sphere::new(Vec3::new(0., -1000., 0.), 1000.),
+        params.ground_material,
+    );
+
+    let glass_material: Arc<dyn Material + Send + Sync> = Arc::new(Dielectric::new(1.5));
+    let clearing = Vec3::new(4., 0.2, 0.);
+
+    for a in -params.grid_range..params.grid_range {
+        for b in -params.grid_range..params.grid_range {
+            let center = Vec3::new(
+                a as f64 + 0.9 * rng.gen::<f64>(),
+                0.2,
+                b as f64 + 0.9 * rng.gen::<f64>(),
+            );
+
+            if (center - clearing).norm() <= 0.9 {
+                continue;
+            }
+
+            let choice: f64 = rng.gen();
+            let material: Arc<dyn Material + Send + Sync> = if choice < params.diffuse_probability {
+                let albedo = random_color(rng, 0., 1.).component_mul(&random_color(rng, 0., 1.));
+                Arc::new(Lambertian::new(albedo))
+            } else if choice < params.diffuse_probability + params.metal_probability {
+                Arc::new(Mirror::new(random_color(rng, 0.5, 1.)))
+            } else {
+                glass_material.clone()
+            };
+
+            builder.add_primitive(Sphere::new(center, 0.2), material);
+        }
+    }
+
+    builder.add_primitive(Sphere::new(Vec3::new(0., 1., 0.), 1.), glass_material);
+    builder.add_primitive(
+        Sphere::new(Vec3::new(-4., 1., 0.), 1.),
+        Arc::new(Lambertian::new(Vec3::new(0.4, 0.2, 0.1))),
+    );
+    builder.add_primitive(
+        Sphere::new(Vec3::new(4., 1., 0.), 1.),
+        Arc::new(Mirror::new(Vec3::new(0.7, 0.6, 0.5))),
+    );
+
+    builder.add_light(GradientEnvironment::default());
+
+    builder.build()
+}
+
+fn random_color(rng: &mut dyn RngCore, min: f64, max: f64) -> Vec3 {
+    Vec3::from_fn(|_, _| rng.gen_range(min..max))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    use crate::math::{Ray, Unit3, EPSILON};
+    use crate::scene::SceneWarning;
+
+    use super::*;
+
+    #[test]
+    fn random_spheres_are_deterministic() {
+        let params = || RandomSphereParams {
+            grid_range: 4,
+            ..RandomSphereParams::default()
+        };
+
+        for seed in 0..4 {
+            let scene = random_spheres(&mut Pcg64::seed_from_u64(seed), params());
+            let again = random_spheres(&mut Pcg64::seed_from_u64(seed), params());
+
+            assert_eq!(scene.primitive_count(), again.primitive_count());
+            for x in -4..4 {
+                let ray = Ray::new(
+                    Vec3::new(13., 2., 3.),
+                    Unit3::new_normalize(Vec3::new(x as f64 - 13., -1.8, -3.)),
+                );
+                let t = |scene: &Scene| {
+                    scene
+                        .hit(&ray, EPSILON, f64::INFINITY)
+                        .map(|hit| hit.geom_hit.t)
+                };
+                assert_eq!(t(&scene), t(&again));
+            }
+
+            // Every grid cell holds at most one small sphere, along with the ground and the three
+            // large spheres.
+            assert!(scene.primitive_count() <= 8 * 8 + 4);
+
+            assert!(!scene
+                .validate()
+                .iter()
+                .any(|warning| matches!(warning, SceneWarning::CoincidentPrimitives(..))));
+        }
+    }
+}
@@ -1,21 +1,27 @@
-use std::sync::Arc;
-
 use crate::geom::Geom;
-use crate::material::Material;
+
+use super::{MaterialId, Visibility};
 
 pub struct Primitive {
-    pub geom: Box<dyn Geom + Sync>,
-    pub material: Arc<dyn Material + Send + Sync>,
+    pub geom: Box<dyn Geom + Send + Sync>,
+    pub material: MaterialId,
+    pub visibility: Visibility,
+    /// Position of the primitive among all those added to the scene builder.
+    pub index: usize,
 }
 
 impl Primitive {
     pub fn new(
-        geom: impl Geom + Sync + 'static,
-        material: Arc<dyn Material + Send + Sync>,
+        geom: impl Geom + Send + Sync + 'static,
+        material: MaterialId,
+        visibility: Visibility,
+        index: usize,
     ) -> Self {
         Self {
             geom: Box::new(geom),
             material,
+            visibility,
+            index,
         }
     }
 }
@@ -0,0 +1,105 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::math::Aabb;
+
+use super::Scene;
+
+/// A likely mistake in a scene's setup, reported by [`Scene::validate`].
+///
+/// Primitives are identified by the order in which they were added to the scene builder, and
+/// lights by their index in [`Scene::lights`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SceneWarning {
+    /// The two primitives have identical bounds, and most likely coincide.
+    CoincidentPrimitives(usize, usize),
+    /// The primitive's bounds contain NaN coordinates.
+    NanBounds(usize),
+    /// The primitive's bounds collapse to a point or a line segment, so it has no area.
+    DegeneratePrimitive(usize),
+    /// The light emits nothing.
+    BlackLight(usize),
+    /// Nothing in the scene emits light, so the image will be black.
+    Unlit,
+}
+
+impl fmt::Display for SceneWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SceneWarning::CoincidentPrimitives(first, second) => {
+                write!(f, "primitives {} and {} coincide", first, second)
+            }
+            SceneWarning::NanBounds(prim) => write!(f, "primitive {} has NaN bounds", prim),
+            SceneWarning::DegeneratePrimitive(prim) => {
+                write!(f, "primitive {} is degenerate", prim)
+            }
+            SceneWarning::BlackLight(light) => write!(f, "light {} emits nothing", light),
+            SceneWarning::Unlit => write!(f, "scene has no lighting and will render black"),
+        }
+    }
+}
+
+impl Scene {
+    /// Checks the scene for setups that are valid but probably unintended. The scene renders
+    /// regardless of what is reported.
+    pub fn validate(&self) -> Vec<SceneWarning> {
+        let mut warnings = Vec::new();
+
+        let mut bounded = Vec::new();
+        for prim in self.primitives.items() {
+            let bounds = match prim.geom.bounds() {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+
+            if has_nan(&bounds) {
+                warnings.push(SceneWarning::NanBounds(prim.index));
+            } else {
+                if bounds.surface_area() == 0. {
+                    warnings.push(SceneWarning::DegeneratePrimitive(prim.index));
+                }
+                bounded.push((bounds, prim.index));
+            }
+        }
+
+        // Sorting brings primitives with identical bounds next to each other, avoiding a
+        // quadratic number of comparisons.
+        bounded.sort_unstable_by(|(b1, i1), (b2, i2)| cmp_bounds(b1, b2).then(i1.cmp(i2)));
+        for pair in bounded.windows(2) {
+            let ((b1, i1), (b2, i2)) = (pair[0], pair[1]);
+            if cmp_bounds(&b1, &b2) == Ordering::Equal {
+                warnings.push(SceneWarning::CoincidentPrimitives(i1, i2));
+            }
+        }
+
+        for (idx, light) in self.lights.iter().enumerate() {
+            if light.is_black() {
+                warnings.push(SceneWarning::BlackLight(idx));
+            }
+        }
+
+        if self.lights.iter().all(|light| light.is_black()) && self.ambient.is_none() {
+            warnings.push(SceneWarning::Unlit);
+        }
+
+        warnings
+    }
+}
+
+fn has_nan(bounds: &Aabb) -> bool {
+    bounds
+        .min_point
+        .iter()
+        .chain(bounds.max_point.iter())
+        .any(|v| v.is_nan())
+}
+
+fn cmp_bounds(b1: &Aabb, b2: &Aabb) -> Ordering {
+    b1.min_point
+        .iter()
+        .chain(b1.max_point.iter())
+        .zip(b2.min_point.iter().chain(b2.max_point.iter()))
+        .map(|(v1, v2)| v1.total_cmp(v2))
+        .find(|&ordering| ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
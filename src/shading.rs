@@ -13,13 +13,80 @@ pub fn same_hemisphere(incoming: Vec3, outgoing: Vec3) -> bool {
     incoming[2] * outgoing[2] > 0.
 }
 
+/// What becomes of light meeting a smooth interface between two refractive media.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefractionEvent {
+    /// The light passes into the other medium, continuing in the given direction.
+    Refract(Vec3),
+    /// The light meets the interface beyond the critical angle and is reflected entirely in the
+    /// given direction.
+    TotalInternalReflection(Vec3),
+}
+
+/// Refracts `outgoing` through the interface with normal `normal`, where `eta` is the ratio of the
+/// refractive index on the side of `outgoing` to that on the other side.
+///
+/// The normal may point to either side of the interface. Rough materials should pass the
+/// microfacet normal, as it determines the angle of incidence and hence whether the light is
+/// totally internally reflected.
+pub fn refract_or_reflect(outgoing: Vec3, normal: Vec3, eta: f64) -> RefractionEvent {
+    let cos_theta = outgoing.dot(&normal);
+    let normal = if cos_theta < 0. { -normal } else { normal };
+    let cos_theta = cos_theta.abs();
+
+    if eta * (1. - cos_theta.powi(2)).max(0.).sqrt() > 1. {
+        return RefractionEvent::TotalInternalReflection(2. * cos_theta * normal - outgoing);
+    }
+
+    let refracted_perp = eta * (cos_theta * normal - outgoing);
+    let refracted_par = -(1. - refracted_perp.norm_squared()).max(0.).sqrt() * normal;
+    RefractionEvent::Refract(refracted_perp + refracted_par)
+}
+
+/// Combines the pdfs of the individual lobes of a multi-lobe BSDF, given as `(weight, pdf)` pairs,
+/// where `weight` is the probability of choosing the lobe when sampling.
+///
+/// A material made of several lobes should sample a direction by choosing a single lobe according
+/// to its weight and sampling it, but must then report the value of this combined pdf for the
+/// direction (and the sum of all lobes' BSDFs), not just the pdf of the chosen lobe. Each
+/// direction could have been produced by any of the lobes, and both the throughput computation
+/// and MIS rely on its overall density.
+pub fn combine_pdfs(lobes: &[(f64, f64)]) -> f64 {
+    lobes.iter().map(|(weight, pdf)| weight * pdf).sum()
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ShadingInfo {
     pub side: HitSide,
     pub outgoing: Unit3,
+    /// The world-space position of the hit, for materials that vary across space (such as
+    /// procedural textures).
+    pub point: Vec3,
+    /// The shading normal in world space, facing the side from which the surface was hit (see
+    /// [`HitInfo::world_normal`](crate::geom::HitInfo::world_normal)). Directions such as
+    /// `outgoing` are instead expressed in the local shading frame around it.
+    pub normal: Unit3,
+    /// Refractive index of the medium surrounding the object at the hit: 1 (air), unless the
+    /// integrator found the object nested inside another refractive one.
+    pub exterior_ior: f64,
+    /// Roughness below which glossy and specular lobes are widened, for path regularization (see
+    /// [`RenderOptions::path_regularization`]). Zero leaves materials as they are.
+    ///
+    /// [`RenderOptions::path_regularization`]: crate::render::RenderOptions::path_regularization
+    pub min_roughness: f64,
 }
 
 impl ShadingInfo {
+    pub fn with_exterior_ior(mut self, exterior_ior: f64) -> Self {
+        self.exterior_ior = exterior_ior;
+        self
+    }
+
+    pub fn with_min_roughness(mut self, min_roughness: f64) -> Self {
+        self.min_roughness = min_roughness;
+        self
+    }
+
     pub fn cos_theta(&self) -> f64 {
         cos_theta(self.outgoing)
     }
@@ -47,8 +114,13 @@ impl Pdf {
 #[derive(Debug, Clone, Copy)]
 pub struct SampledRadiance {
     pub dir: Unit3,
+    /// The radiance (or BSDF value) along `dir`. For samples created by
+    /// [`SampledRadiance::new_specular`], this is instead the fraction of light scattered, which
+    /// already accounts for the cosine factor.
     pub color: Vec3,
     pub pdf: Pdf,
+    /// Whether `color` already accounts for the cosine factor.
+    projected: bool,
 }
 
 impl SampledRadiance {
@@ -57,6 +129,7 @@ impl SampledRadiance {
             dir,
             color,
             pdf: Pdf::Real(pdf),
+            projected: false,
         }
     }
 
@@ -65,10 +138,102 @@ impl SampledRadiance {
             dir,
             color,
             pdf: Pdf::Delta,
+            projected: false,
+        }
+    }
+
+    /// Creates a sample of specular scattering into `dir`, where `attenuation` is the fraction of
+    /// light scattered.
+    ///
+    /// A specular BSDF is a delta function divided by the cosine factor that
+    /// [`SampledRadiance::scaled_color`] multiplies back in; keeping the two out of the
+    /// computation altogether avoids blowing up at grazing angles, where both approach zero.
+    pub fn new_specular(dir: Unit3, attenuation: Vec3) -> Self {
+        Self {
+            dir,
+            color: attenuation,
+            pdf: Pdf::Delta,
+            projected: true,
         }
     }
 
+    /// Returns the color multiplied by the cosine factor and divided by the pdf, ready to be
+    /// multiplied into a path's throughput. The cosine is taken in absolute value, so light
+    /// transmitted through the surface counts just like reflected light.
     pub fn scaled_color(&self) -> Vec3 {
-        cos_theta(self.dir) * self.pdf.factor() * self.color
+        if self.projected {
+            return self.pdf.factor() * self.color;
+        }
+
+        cos_theta(self.dir).abs() * self.pdf.factor() * self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ratio of the refractive index of air to that of glass.
+    const AIR_TO_GLASS: f64 = 1. / 1.5;
+
+    /// Returns the unit direction at angle `theta` from the normal pointing into glass that fills
+    /// `z < 0`, for light meeting the surface from inside.
+    fn inside_glass(theta: f64) -> Vec3 {
+        Vec3::new(theta.sin(), 0., -theta.cos())
+    }
+
+    #[test]
+    fn refraction_up_to_glass_critical_angle() {
+        let critical = (1. / 1.5f64).asin();
+        assert!((critical.to_degrees() - 41.81).abs() < 0.01);
+
+        let normal = Vec3::z();
+
+        let below = critical - 1e-3;
+        match refract_or_reflect(inside_glass(below), normal, 1.5) {
+            RefractionEvent::Refract(refracted) => {
+                assert!((refracted.norm() - 1.).abs() < 1e-12);
+                // Snell's law: n₁ sin θ₁ = n₂ sin θ₂.
+                let sin_refracted = refracted.xy().norm();
+                assert!((1.5 * below.sin() - sin_refracted).abs() < 1e-12);
+                assert!(refracted[2] > 0.);
+            }
+            event => panic!(
+                "expected refraction below the critical angle, got {:?}",
+                event
+            ),
+        }
+
+        let above = critical + 1e-3;
+        match refract_or_reflect(inside_glass(above), normal, 1.5) {
+            RefractionEvent::TotalInternalReflection(reflected) => {
+                assert!((reflected.norm() - 1.).abs() < 1e-12);
+                let expected = Vec3::new(-above.sin(), 0., -above.cos());
+                assert!((reflected - expected).norm() < 1e-12);
+            }
+            event => panic!("expected total internal reflection, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn refraction_into_glass_obeys_snells_law() {
+        for i in 0..=8 {
+            let theta = i as f64 * 10f64.to_radians();
+            let outgoing = Vec3::new(theta.sin(), 0., theta.cos());
+
+            // The normal may point to either side.
+            for &normal in &[Vec3::z(), -Vec3::z()] {
+                match refract_or_reflect(outgoing, normal, AIR_TO_GLASS) {
+                    RefractionEvent::Refract(refracted) => {
+                        assert!((refracted.norm() - 1.).abs() < 1e-12);
+                        let sin_refracted = refracted.xy().norm();
+                        assert!((theta.sin() - 1.5 * sin_refracted).abs() < 1e-12);
+                        assert!(refracted[2] < 0.);
+                        assert!(refracted[0] <= 0.);
+                    }
+                    event => panic!("light entering glass cannot reflect totally: {:?}", event),
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,373 @@
+use std::f64;
+
+use nalgebra::Matrix3;
+
+use crate::math::Vec3;
+
+/// Shortest wavelength (in nanometers) considered when sampling the visible spectrum.
+pub const LAMBDA_MIN: f64 = 360.;
+/// Longest wavelength (in nanometers) considered when sampling the visible spectrum.
+pub const LAMBDA_MAX: f64 = 830.;
+
+/// A piecewise Gaussian with different widths on either side of its mean.
+struct Lobe {
+    weight: f64,
+    mean: f64,
+    sigma_below: f64,
+    sigma_above: f64,
+}
+
+impl Lobe {
+    const fn new(weight: f64, mean: f64, sigma_below: f64, sigma_above: f64) -> Self {
+        Self {
+            weight,
+            mean,
+            sigma_below,
+            sigma_above,
+        }
+    }
+
+    fn eval(&self, lambda: f64) -> f64 {
+        let sigma = if lambda < self.mean {
+            self.sigma_below
+        } else {
+            self.sigma_above
+        };
+
+        self.weight * (-0.5 * ((lambda - self.mean) / sigma).powi(2)).exp()
+    }
+
+    fn integral(&self) -> f64 {
+        self.weight * (f64::consts::PI / 2.).sqrt() * (self.sigma_below + self.sigma_above)
+    }
+}
+
+// Multi-lobe fit to the CIE 1931 2° standard observer, from Wyman, Sloan and Shirley, "Simple
+// Analytic Approximations to the CIE XYZ Color Matching Functions" (2013).
+
+const X_LOBES: [Lobe; 3] = [
+    Lobe::new(1.056, 599.8, 37.9, 31.0),
+    Lobe::new(0.362, 442.0, 16.0, 26.7),
+    Lobe::new(-0.065, 501.1, 20.4, 26.2),
+];
+
+const Y_LOBES: [Lobe; 2] = [
+    Lobe::new(0.821, 568.8, 46.9, 40.5),
+    Lobe::new(0.286, 530.9, 16.3, 31.1),
+];
+
+const Z_LOBES: [Lobe; 2] = [
+    Lobe::new(1.217, 437.0, 11.8, 36.0),
+    Lobe::new(0.681, 459.0, 26.0, 13.8),
+];
+
+fn eval_lobes(lobes: &[Lobe], lambda: f64) -> f64 {
+    lobes.iter().map(|lobe| lobe.eval(lambda)).sum()
+}
+
+/// Returns the values of the CIE color matching functions at wavelength `lambda` (in nanometers).
+pub fn wavelength_to_xyz(lambda: f64) -> Vec3 {
+    Vec3::new(
+        eval_lobes(&X_LOBES, lambda),
+        eval_lobes(&Y_LOBES, lambda),
+        eval_lobes(&Z_LOBES, lambda),
+    )
+}
+
+/// Converts CIE XYZ to linear sRGB (D65 white point).
+pub fn xyz_to_srgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.2404542 * xyz[0] - 1.5371385 * xyz[1] - 0.4985314 * xyz[2],
+        -0.9692660 * xyz[0] + 1.8760108 * xyz[1] + 0.0415560 * xyz[2],
+        0.0556434 * xyz[0] - 0.2040259 * xyz[1] + 1.0572252 * xyz[2],
+    )
+}
+
+/// Adapts XYZ colors from the equal-energy illuminant (E) to D65 using the Bradford transform, so
+/// that a flat spectrum ends up neutral in sRGB.
+pub fn adapt_e_to_d65(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        0.9531874 * xyz[0] - 0.0265906 * xyz[1] + 0.0238731 * xyz[2],
+        -0.0382467 * xyz[0] + 1.0288406 * xyz[1] + 0.0094060 * xyz[2],
+        0.0026068 * xyz[0] - 0.0030332 * xyz[1] + 1.0892565 * xyz[2],
+    )
+}
+
+/// Maps `u` in `[0, 1)` to a wavelength uniformly distributed over the visible range, returning
+/// the wavelength and its pdf.
+pub fn sample_wavelength(u: f64) -> (f64, f64) {
+    (
+        LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN),
+        1. / (LAMBDA_MAX - LAMBDA_MIN),
+    )
+}
+
+/// Accumulates radiance samples taken at individual wavelengths into a single color.
+///
+/// The result is normalized so that a constant spectrum of value 1 has a luminance (`Y`) of 1, and
+/// maps to white in sRGB.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpectralAccumulator {
+    xyz: Vec3,
+    sample_count: u32,
+}
+
+impl SpectralAccumulator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_sample(&mut self, lambda: f64, radiance: f64, pdf: f64) {
+        self.xyz += wavelength_to_xyz(lambda) * (radiance / pdf);
+        self.sample_count += 1;
+    }
+
+    pub fn xyz(&self) -> Vec3 {
+        if self.sample_count == 0 {
+            return Vec3::default();
+        }
+
+        let y_integral: f64 = Y_LOBES.iter().map(Lobe::integral).sum();
+        self.xyz / (self.sample_count as f64 * y_integral)
+    }
+
+    pub fn srgb(&self) -> Vec3 {
+        xyz_to_srgb(adapt_e_to_d65(self.xyz()))
+    }
+}
+
+/// Number of wavelengths at which spectra are evaluated when integrating them back to RGB.
+const RGB_INTEGRATION_STEPS: usize = 94;
+
+/// Number of intermediate colors visited on the way from gray to the target when fitting a
+/// spectrum to an RGB color.
+const FIT_CONTINUATION_STEPS: usize = 16;
+
+/// Maximum number of Gauss-Newton iterations per continuation step.
+const FIT_MAX_ITERATIONS: usize = 32;
+
+/// A smooth reflectance spectrum, represented as a sigmoid of a quadratic polynomial in the
+/// wavelength as described by Jakob and Hanika, "A Low-Dimensional Function Space for Efficient
+/// Spectral Upsampling" (2019). Its values always lie in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spectrum {
+    /// Polynomial coefficients, from the quadratic term down, in the wavelength remapped from the
+    /// visible range to `[0, 1]`.
+    coeffs: [f64; 3],
+}
+
+impl Spectrum {
+    /// Creates a spectrum with the same `value` at every wavelength. `value` is clamped to
+    /// `[0, 1]`.
+    pub fn constant(value: f64) -> Self {
+        Self {
+            coeffs: [0., 0., sigmoid_inverse(value.clamp(0., 1.))],
+        }
+    }
+
+    /// Evaluates the spectrum at wavelength `lambda` (in nanometers).
+    pub fn eval(&self, lambda: f64) -> f64 {
+        sigmoid(self.polynomial(normalize_wavelength(lambda)))
+    }
+
+    /// Returns the linear sRGB color of the spectrum when lit by the equal-energy illuminant,
+    /// with the same normalization as [`SpectralAccumulator`].
+    pub fn to_srgb(&self) -> Vec3 {
+        rgb_integration_points()
+            .map(|(t, weight)| weight * sigmoid(self.polynomial(t)))
+            .sum()
+    }
+
+    fn polynomial(&self, t: f64) -> f64 {
+        let [c0, c1, c2] = self.coeffs;
+        // Written out so that infinite constant terms don't produce `0 * inf`.
+        if c0 == 0. && c1 == 0. {
+            return c2;
+        }
+        (c0 * t + c1) * t + c2
+    }
+
+    /// Returns the sRGB color of the spectrum along with its derivatives with respect to each of
+    /// the coefficients, one per column.
+    fn srgb_with_jacobian(&self) -> (Vec3, Matrix3<f64>) {
+        let mut rgb = Vec3::default();
+        let mut jacobian = Matrix3::zeros();
+
+        for (t, weight) in rgb_integration_points() {
+            let x = self.polynomial(t);
+            rgb += weight * sigmoid(x);
+
+            let dsigmoid = 0.5 * (1. + x * x).powf(-1.5);
+            let dx = Vec3::new(t * t, t, 1.);
+            jacobian += weight * dsigmoid * dx.transpose();
+        }
+
+        (rgb, jacobian)
+    }
+}
+
+/// Upsamples a linear sRGB reflectance to a smooth spectrum whose color under the equal-energy
+/// illuminant matches it, so that RGB-authored albedos can be used in spectral transport.
+///
+/// Components are clamped to `[0, 1]`. Grays map to constant spectra exactly; other colors are
+/// fit numerically, and saturated colors at the edge of the gamut only approximately, as no
+/// reflectance spectrum reproduces them exactly. The fit is too expensive to run per sample, so
+/// spectra should be computed once per color.
+pub fn rgb_to_spectrum(rgb: Vec3) -> Spectrum {
+    let target = rgb.map(|c| c.clamp(0., 1.));
+    if target[0] == target[1] && target[1] == target[2] {
+        return Spectrum::constant(target[0]);
+    }
+
+    // Fitting a saturated color directly from a flat initial guess tends to diverge, so walk the
+    // target over from the (neutral) color of the initial guess, refining the previous fit at
+    // every step.
+    let mut spectrum = Spectrum::constant(0.5);
+    let start = spectrum.to_srgb();
+
+    for step in 1..=FIT_CONTINUATION_STEPS {
+        let step_target = start.lerp(&target, step as f64 / FIT_CONTINUATION_STEPS as f64);
+        refine_fit(&mut spectrum, &step_target);
+    }
+
+    spectrum
+}
+
+/// Runs damped Gauss-Newton iterations on `spectrum`'s coefficients to bring its color closer to
+/// `target`.
+fn refine_fit(spectrum: &mut Spectrum, target: &Vec3) {
+    let mut damping = 1e-6;
+    let (mut rgb, mut jacobian) = spectrum.srgb_with_jacobian();
+
+    for _ in 0..FIT_MAX_ITERATIONS {
+        let residual = rgb - target;
+        let error = residual.norm_squared();
+        if error < 1e-14 {
+            return;
+        }
+
+        let normal = jacobian.transpose() * jacobian + Matrix3::identity() * damping;
+        let delta = match normal.try_inverse() {
+            Some(inverse) => inverse * (jacobian.transpose() * residual),
+            None => return,
+        };
+
+        let candidate = Spectrum {
+            coeffs: [
+                spectrum.coeffs[0] - delta[0],
+                spectrum.coeffs[1] - delta[1],
+                spectrum.coeffs[2] - delta[2],
+            ],
+        };
+        let (candidate_rgb, candidate_jacobian) = candidate.srgb_with_jacobian();
+
+        if (candidate_rgb - target).norm_squared() < error {
+            *spectrum = candidate;
+            rgb = candidate_rgb;
+            jacobian = candidate_jacobian;
+            damping = (damping * 0.1).max(1e-12);
+        } else {
+            damping *= 10.;
+        }
+    }
+}
+
+/// Yields midpoint-rule sample positions over the visible range (remapped to `[0, 1]`), along
+/// with the sRGB color contributed by each under the equal-energy illuminant.
+fn rgb_integration_points() -> impl Iterator<Item = (f64, Vec3)> {
+    let y_integral: f64 = Y_LOBES.iter().map(Lobe::integral).sum();
+    let scale = (LAMBDA_MAX - LAMBDA_MIN) / (RGB_INTEGRATION_STEPS as f64 * y_integral);
+
+    (0..RGB_INTEGRATION_STEPS).map(move |i| {
+        let t = (i as f64 + 0.5) / RGB_INTEGRATION_STEPS as f64;
+        let lambda = LAMBDA_MIN + t * (LAMBDA_MAX - LAMBDA_MIN);
+        let weight = xyz_to_srgb(adapt_e_to_d65(wavelength_to_xyz(lambda))) * scale;
+        (t, weight)
+    })
+}
+
+fn normalize_wavelength(lambda: f64) -> f64 {
+    (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+fn sigmoid(x: f64) -> f64 {
+    if x.is_infinite() {
+        return if x > 0. { 1. } else { 0. };
+    }
+    0.5 + x / (2. * (1. + x * x).sqrt())
+}
+
+fn sigmoid_inverse(y: f64) -> f64 {
+    if y <= 0. {
+        return f64::NEG_INFINITY;
+    }
+    if y >= 1. {
+        return f64::INFINITY;
+    }
+    (y - 0.5) / (y * (1. - y)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accumulates `radiance` over wavelengths spread evenly across the visible range.
+    fn accumulate(radiance: impl Fn(f64) -> f64) -> SpectralAccumulator {
+        const SAMPLES: u32 = 10_000;
+
+        let mut accum = SpectralAccumulator::new();
+        for i in 0..SAMPLES {
+            let (lambda, pdf) = sample_wavelength((i as f64 + 0.5) / SAMPLES as f64);
+            accum.add_sample(lambda, radiance(lambda), pdf);
+        }
+        accum
+    }
+
+    #[test]
+    fn flat_spectrum_is_white() {
+        let accum = accumulate(|_| 1.);
+        assert!((accum.xyz()[1] - 1.).abs() < 1e-3, "{}", accum.xyz());
+        assert!(
+            (accum.srgb() - Vec3::from_element(1.)).norm() < 1e-2,
+            "{}",
+            accum.srgb()
+        );
+    }
+
+    #[test]
+    fn green_wavelength_is_green() {
+        let mut accum = SpectralAccumulator::new();
+        accum.add_sample(550., 1., 1.);
+        let rgb = accum.srgb();
+        assert!(rgb[1] > rgb[0] && rgb[1] > rgb[2], "{}", rgb);
+    }
+
+    #[test]
+    fn rgb_to_spectrum_round_trips() {
+        assert_eq!(
+            rgb_to_spectrum(Vec3::from_element(0.3)),
+            Spectrum::constant(0.3)
+        );
+
+        for &rgb in &[
+            Vec3::new(0.8, 0.2, 0.2),
+            Vec3::new(0.2, 0.8, 0.2),
+            Vec3::new(0.2, 0.2, 0.8),
+            Vec3::new(0.6, 0.5, 0.1),
+        ] {
+            let spectrum = rgb_to_spectrum(rgb);
+            assert!(
+                (spectrum.to_srgb() - rgb).norm() < 1e-3,
+                "{} fit as {}",
+                rgb,
+                spectrum.to_srgb()
+            );
+
+            for i in 0..=100 {
+                let lambda = LAMBDA_MIN + i as f64 / 100. * (LAMBDA_MAX - LAMBDA_MIN);
+                let value = spectrum.eval(lambda);
+                assert!((0. ..=1.).contains(&value), "{} at {}nm", value, lambda);
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::img;
+use crate::math::{Vec2, Vec3};
+
+/// A function of texture coordinates.
+///
+/// Textures return linear values. Textures holding colors authored in sRGB (as those of most image
+/// files are) should be wrapped in [`SrgbTexture`] to decode them, while those holding other data,
+/// such as normals or roughness, are used as they are.
+pub trait Texture {
+    fn value(&self, uv: Vec2) -> Vec3;
+}
+
+impl<F: Fn(Vec2) -> Vec3> Texture for F {
+    fn value(&self, uv: Vec2) -> Vec3 {
+        self(uv)
+    }
+}
+
+/// A color texture whose values are encoded with the sRGB transfer function, decoded to linear
+/// colors on lookup.
+pub struct SrgbTexture<T> {
+    inner: T,
+}
+
+impl<T: Texture> SrgbTexture<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: Texture> Texture for SrgbTexture<T> {
+    fn value(&self, uv: Vec2) -> Vec3 {
+        img::srgb_to_linear(self.inner.value(uv))
+    }
+}